@@ -20,13 +20,15 @@ fn cmd(cmd: &str, args: &[&str]) {
 fn main() -> Result<(), Box<dyn Error>> {
     let opt = libslirp::Opt::from_args();
     let iface = Rc::new(Iface::without_packet_info("testtap%d", Mode::Tap)?);
-    let mut addr = opt.ipv4.net.to_string();
-    addr.push_str("/24");
+    let addr = opt.ipv4.cidr().to_string();
     cmd("ip", &["addr", "add", "dev", iface.name(), addr.as_str()]);
     cmd("ip", &["link", "set", "up", "dev", iface.name()]);
 
     let poll = Poll::new()?;
-    let mut slirp = libslirp::MioHandler::new(&opt, &poll, iface.as_raw_fd());
+    let mut slirp = libslirp::MioHandler::new(&opt, &poll, iface.as_raw_fd())?;
+    for e in slirp.setup_errors() {
+        eprintln!("{}", e);
+    }
 
     let mut events = Events::with_capacity(1024);
     let mut duration = None;
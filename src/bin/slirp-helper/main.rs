@@ -0,0 +1,223 @@
+use std::error::Error;
+use std::io;
+use std::mem;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+use libc;
+use libslirp;
+use libslirp::transport::{self, stream, Transport};
+use mio::{Events, Poll};
+use structopt::StructOpt;
+
+mod netns;
+mod systemd;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "slirp",
+    about = "slirp helper process",
+    after_help = "The slirp options can also be set with SLIRP_* environment variables, named \
+                  after their long flag (such as SLIRP_DHCP_START), lists being comma-separated. \
+                  The command line takes precedence over the environment, which takes \
+                  precedence over the --config file."
+)]
+struct Opt {
+    /// Activate debug mode
+    #[structopt(long)]
+    debug: bool,
+    /// Load the slirp options from a TOML file, overridden by the command line (needs the
+    /// "config" feature)
+    #[structopt(name = "config-path", parse(from_os_str), long = "config")]
+    config: Option<PathBuf>,
+    /// Exit with parent process
+    #[structopt(long = "exit-with-parent")]
+    exit_with_parent: bool,
+    /// Unix datagram socket path
+    #[structopt(name = "path", parse(from_os_str), long = "socket-path")]
+    socket_path: Option<PathBuf>,
+    /// Unix datagram socket file descriptor
+    #[structopt(long)]
+    fd: Option<i32>,
+    /// Connect to a stream socket (unix:PATH or tcp:HOST:PORT), as QEMU -netdev stream server
+    #[structopt(long = "stream-connect")]
+    stream_connect: Option<stream::Addr>,
+    /// Listen on a stream socket (unix:PATH or tcp:HOST:PORT), for QEMU -netdev stream client
+    #[structopt(long = "stream-listen")]
+    stream_listen: Option<stream::Addr>,
+    /// Act as a vhost-user-net backend, listening on the given socket path
+    #[structopt(name = "vhost-user-path", parse(from_os_str), long = "vhost-user")]
+    vhost_user: Option<PathBuf>,
+    /// Plug into the VDE switch with the given control directory
+    #[structopt(name = "vde-switch", parse(from_os_str), long = "vde")]
+    vde: Option<PathBuf>,
+    /// Create a TAP interface, configured with the guest DHCP address
+    #[structopt(long)]
+    tap: Option<String>,
+    /// Exchange frames with the TAP interface with a virtio-net header
+    #[structopt(long = "tap-vnet-hdr")]
+    tap_vnet_hdr: bool,
+    /// Confine the process with seccomp, and Landlock when TFTP is enabled
+    #[structopt(long)]
+    sandbox: bool,
+    /// Create a TAP interface in the network namespace of the given PID or path
+    #[structopt(long)]
+    netns: Option<String>,
+    /// Join the user namespace of the given PID or path before the network namespace
+    /// (by default, the user namespace of the --netns PID)
+    #[structopt(long)]
+    userns: Option<String>,
+    /// Name of the TAP interface created in the network namespace
+    #[structopt(long = "netns-tap", default_value = "tap0")]
+    netns_tap: String,
+    /// Write "1" to the given file descriptor when the network is ready
+    #[structopt(long = "ready-fd")]
+    ready_fd: Option<i32>,
+
+    #[structopt(flatten)]
+    slirp: libslirp::Opt,
+}
+
+fn set_exit_with_parent() {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM, 0, 0, 0);
+    }
+}
+
+#[cfg(feature = "config")]
+fn load_config(path: &Path) -> io::Result<libslirp::Opt> {
+    libslirp::Opt::from_toml_file(path)
+}
+
+#[cfg(not(feature = "config"))]
+fn load_config(_path: &Path) -> io::Result<libslirp::Opt> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "built without configuration file support, enable the \"config\" feature",
+    ))
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let matches = Opt::clap().get_matches();
+    let mut opt = Opt::from_clap(&matches);
+    let mut slirp = match &opt.config {
+        Some(path) => load_config(path)?,
+        None => libslirp::Opt::default(),
+    };
+    let cli = mem::replace(&mut opt.slirp, libslirp::Opt::default());
+    let res = slirp.apply_env().and_then(|_| {
+        slirp.override_with(cli, &matches);
+        slirp.validate()
+    });
+    opt.slirp = slirp;
+    if let Err(errors) = res {
+        for e in errors {
+            eprintln!("Invalid option: {}", e);
+        }
+        std::process::exit(1);
+    }
+    if opt.debug {
+        dbg!(&opt);
+    }
+    let transport: Box<dyn Transport> = match &opt {
+        Opt { fd: Some(fd), .. } => Box::new(unsafe { transport::Datagram::from_fd(*fd)? }),
+        Opt {
+            socket_path: Some(path),
+            ..
+        } => Box::new(transport::Datagram::new(UnixDatagram::bind(path)?)?),
+        Opt {
+            stream_connect: Some(addr),
+            ..
+        } => stream::connect(addr)?,
+        Opt {
+            stream_listen: Some(addr),
+            ..
+        } => stream::accept(addr)?,
+        Opt {
+            vhost_user: Some(path),
+            ..
+        } => Box::new(transport::VhostUser::accept(path)?),
+        Opt { vde: Some(dir), .. } => Box::new(transport::Vde::connect(dir)?),
+        Opt {
+            tap: Some(name), ..
+        } => {
+            let tap = transport::Tap::new(name, opt.tap_vnet_hdr)?;
+            tap.set_ipv4(opt.slirp.ipv4.dhcp_start(), opt.slirp.ipv4.mask())?;
+            tap.set_up()?;
+            Box::new(tap)
+        }
+        Opt {
+            netns: Some(netns), ..
+        } => {
+            let tap = netns::tap(&netns::Config {
+                netns,
+                userns: opt.userns.as_ref().map(String::as_str),
+                tap: &opt.netns_tap,
+                addr: opt.slirp.ipv4.dhcp_start(),
+                mask: opt.slirp.ipv4.mask(),
+                gateway: opt.slirp.ipv4.host(),
+                mtu: opt.slirp.mtu(),
+            })?;
+            Box::new(transport::Datagram::new(tap)?)
+        }
+        _ => {
+            // socket activation: prefer the socket named "slirp"
+            let fds = systemd::listen_fds()?;
+            match fds.iter().find(|(_, name)| name == "slirp").or(fds.first()) {
+                Some((fd, name)) => systemd::transport(*fd, name)?,
+                None => panic!("Missing a socket argument"),
+            }
+        }
+    };
+
+    if opt.exit_with_parent {
+        set_exit_with_parent();
+    }
+
+    // Landlock only confines the threads started after it
+    if opt.sandbox {
+        if let Err(e) = libslirp::sandbox::restrict_fs(&opt.slirp) {
+            eprintln!("Failed to enter the sandbox: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let poll = Poll::new()?;
+    let mut slirp = libslirp::MioHandler::with_transport(&opt.slirp, &poll, transport)?;
+    if !slirp.setup_errors().is_empty() {
+        for e in slirp.setup_errors() {
+            eprintln!("{}", e);
+        }
+        std::process::exit(1);
+    }
+    if opt.sandbox {
+        if let Err(e) = libslirp::sandbox::seccomp() {
+            eprintln!("Failed to enter the sandbox: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(fd) = opt.ready_fd {
+        netns::notify_ready(fd)?;
+    }
+    let mut notifier = systemd::Notifier::from_env()?;
+    if let Some(notifier) = &notifier {
+        notifier.ready("Running")?;
+    }
+
+    let mut events = Events::with_capacity(1024);
+    let mut duration = None;
+
+    loop {
+        if opt.debug {
+            dbg!(duration);
+        }
+
+        poll.poll(&mut events, duration)?;
+        duration = slirp.dispatch(&events)?;
+        if let Some(notifier) = &mut notifier {
+            if let Some(watchdog) = notifier.watchdog()? {
+                duration = Some(duration.map_or(watchdog, |d| d.min(watchdog)));
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::ptr;
+
+use libslirp::transport::Tap;
+
+/// A namespace, given as a PID or a path (such as /proc/PID/ns/net).
+fn ns_path(ns: &str, kind: &str) -> PathBuf {
+    match ns.parse::<u32>() {
+        Ok(pid) => PathBuf::from(format!("/proc/{}/ns/{}", pid, kind)),
+        Err(_) => PathBuf::from(ns),
+    }
+}
+
+fn setns(path: &PathBuf, nstype: libc::c_int) -> io::Result<()> {
+    let ns = File::open(path)?;
+    if unsafe { libc::setns(ns.as_raw_fd(), nstype) } < 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(
+            err.kind(),
+            format!("Failed to join {}: {}", path.display(), err),
+        ));
+    }
+    Ok(())
+}
+
+fn same_ns(a: &PathBuf, b: &str) -> io::Result<bool> {
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+/// The TAP interface to create in a network namespace.
+pub struct Config<'a> {
+    pub netns: &'a str,
+    pub userns: Option<&'a str>,
+    pub tap: &'a str,
+    pub addr: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub mtu: u32,
+}
+
+fn setup_tap(cfg: &Config) -> io::Result<Tap> {
+    let userns = match cfg.userns {
+        Some(userns) => Some(ns_path(userns, "user")),
+        None => {
+            // when given a PID, join its user namespace, if it isn't ours
+            let userns = ns_path(cfg.netns, "user");
+            if cfg.netns.parse::<u32>().is_ok() && !same_ns(&userns, "/proc/self/ns/user")? {
+                Some(userns)
+            } else {
+                None
+            }
+        }
+    };
+    if let Some(userns) = userns {
+        setns(&userns, libc::CLONE_NEWUSER)?;
+    }
+    setns(&ns_path(cfg.netns, "net"), libc::CLONE_NEWNET)?;
+
+    let tap = Tap::new(cfg.tap, false)?;
+    tap.set_mtu(cfg.mtu)?;
+    tap.set_ipv4(cfg.addr, cfg.mask)?;
+    tap.set_up()?;
+    tap.add_route(Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED, cfg.gateway)?;
+
+    Ok(tap)
+}
+
+fn send_fd(sock: &UnixStream, fd: RawFd) -> io::Result<()> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut cmsg = [0u64; 4];
+    let size = mem::size_of::<RawFd>() as u32;
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(size) } as _;
+    unsafe {
+        let c = libc::CMSG_FIRSTHDR(&msg);
+        (*c).cmsg_level = libc::SOL_SOCKET;
+        (*c).cmsg_type = libc::SCM_RIGHTS;
+        (*c).cmsg_len = libc::CMSG_LEN(size) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(c) as *mut RawFd, fd);
+    }
+
+    if unsafe { libc::sendmsg(sock.as_raw_fd(), &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn recv_fd(sock: &UnixStream) -> io::Result<File> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut cmsg = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&cmsg) as _;
+
+    if unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        let c = libc::CMSG_FIRSTHDR(&msg);
+        if c.is_null() || (*c).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Failed to create the TAP interface in the namespace",
+            ));
+        }
+        Ok(File::from_raw_fd(ptr::read_unaligned(
+            libc::CMSG_DATA(c) as *const RawFd
+        )))
+    }
+}
+
+/// Create and configure a TAP interface in the network namespace.
+///
+/// Joining a user namespace is irreversible, so this is done by a child
+/// process, which sends back the TAP file descriptor: the caller stays in
+/// the host namespaces.
+pub fn tap(cfg: &Config) -> io::Result<File> {
+    let (parent, child) = UnixStream::pair()?;
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(parent);
+            let res = setup_tap(cfg).and_then(|tap| send_fd(&child, tap.as_raw_fd()));
+            if let Err(e) = &res {
+                eprintln!("netns: {}", e);
+            }
+            unsafe { libc::_exit(res.is_err() as libc::c_int) }
+        }
+        pid => {
+            drop(child);
+            let tap = recv_fd(&parent);
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+            tap
+        }
+    }
+}
+
+/// Tell the container runtime that the network is up.
+pub fn notify_ready(fd: RawFd) -> io::Result<()> {
+    use std::io::Write;
+
+    // closed when dropped
+    let mut ready = unsafe { File::from_raw_fd(fd) };
+    ready.write_all(b"1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn paths() {
+        assert_eq!(ns_path("42", "net"), PathBuf::from("/proc/42/ns/net"));
+        assert_eq!(
+            ns_path("/run/netns/test", "net"),
+            PathBuf::from("/run/netns/test")
+        );
+        let pid = std::process::id().to_string();
+        assert!(same_ns(&ns_path(&pid, "net"), "/proc/self/ns/net").unwrap());
+    }
+
+    #[test]
+    fn pass_fd() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (mut tap, peer) = UnixStream::pair().unwrap();
+
+        send_fd(&a, peer.as_raw_fd()).unwrap();
+        drop(peer);
+        let mut received = recv_fd(&b).unwrap();
+        received.write_all(b"tap").unwrap();
+        let mut buf = [0; 3];
+        tap.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"tap");
+
+        // no file descriptor
+        (&a).write_all(b"x").unwrap();
+        assert!(recv_fd(&b).is_err());
+    }
+
+    #[test]
+    fn missing_ns() {
+        let cfg = Config {
+            netns: "/nonexistent/ns/net",
+            userns: None,
+            tap: "tap0",
+            addr: Ipv4Addr::new(10, 0, 2, 100),
+            mask: Ipv4Addr::new(255, 255, 255, 0),
+            gateway: Ipv4Addr::new(10, 0, 2, 2),
+            mtu: 1500,
+        };
+        // fails before joining any namespace, so no need to fork
+        let err = setup_tap(&cfg).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
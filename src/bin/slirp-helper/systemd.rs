@@ -0,0 +1,255 @@
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::process;
+use std::time::{Duration, Instant};
+
+use libslirp::transport::{self, stream::Stream, Transport};
+
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The sockets passed with socket activation, with their name.
+fn parse_listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+) -> io::Result<Vec<(RawFd, String)>> {
+    let pid = match pid {
+        Some(pid) => pid,
+        None => return Ok(Vec::new()),
+    };
+    if pid.parse::<u32>().ok() != Some(process::id()) {
+        // meant for another process
+        return Ok(Vec::new());
+    }
+
+    let n = fds
+        .unwrap_or("0")
+        .parse::<RawFd>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid LISTEN_FDS"))?;
+    let mut names = names.map(|n| n.split(':')).into_iter().flatten();
+
+    Ok((LISTEN_FDS_START..LISTEN_FDS_START + n)
+        .map(|fd| (fd, names.next().unwrap_or("unknown").to_string()))
+        .collect())
+}
+
+/// Take the sockets passed by systemd, as sd_listen_fds_with_names().
+pub fn listen_fds() -> io::Result<Vec<(RawFd, String)>> {
+    let var = |name| env::var(name).ok();
+    let fds = parse_listen_fds(
+        var("LISTEN_PID").as_ref().map(String::as_str),
+        var("LISTEN_FDS").as_ref().map(String::as_str),
+        var("LISTEN_FDNAMES").as_ref().map(String::as_str),
+    )?;
+
+    // don't pass them to children
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    for (fd, _) in &fds {
+        if unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(fds)
+}
+
+fn sockopt(fd: RawFd, opt: libc::c_int) -> io::Result<libc::c_int> {
+    let mut val: libc::c_int = 0;
+    let mut len = mem::size_of_val(&val) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            opt,
+            &mut val as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(val)
+}
+
+/// Make a transport of a passed socket.
+///
+/// A datagram socket is used as is. A stream socket carries QEMU stream
+/// frames, or vhost-user messages if it is named "vhost-user": when it is
+/// listening, the first connection is accepted.
+pub fn transport(fd: RawFd, name: &str) -> io::Result<Box<dyn Transport>> {
+    if sockopt(fd, libc::SO_TYPE)? == libc::SOCK_DGRAM {
+        return Ok(Box::new(unsafe { transport::Datagram::from_fd(fd)? }));
+    }
+
+    let listening = sockopt(fd, libc::SO_ACCEPTCONN)? != 0;
+    if sockopt(fd, libc::SO_DOMAIN)? != libc::AF_UNIX {
+        let sock = if listening {
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            listener.accept()?.0
+        } else {
+            unsafe { TcpStream::from_raw_fd(fd) }
+        };
+        return Ok(Box::new(Stream::new(sock)?));
+    }
+
+    let sock = if listening {
+        let listener = unsafe { UnixListener::from_raw_fd(fd) };
+        listener.accept()?.0
+    } else {
+        unsafe { UnixStream::from_raw_fd(fd) }
+    };
+    if name == "vhost-user" {
+        Ok(Box::new(transport::VhostUser::new(sock)?))
+    } else {
+        Ok(Box::new(Stream::new(sock)?))
+    }
+}
+
+/// Notifications to the service manager, as sd_notify().
+pub struct Notifier {
+    sock: UnixDatagram,
+    addr: libc::sockaddr_un,
+    addr_len: libc::socklen_t,
+    watchdog: Option<Duration>,
+    next_watchdog: Instant,
+}
+
+impl Notifier {
+    /// Notify `path` (a leading '@' stands for an abstract socket), and
+    /// ping the watchdog every half `watchdog`.
+    pub fn new(path: &OsStr, watchdog: Option<Duration>) -> io::Result<Self> {
+        let path = path.as_bytes();
+        let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+        if path.is_empty() || path.len() >= addr.sun_path.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid NOTIFY_SOCKET",
+            ));
+        }
+
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+            *dst = *src as libc::c_char;
+        }
+        if path[0] == b'@' {
+            addr.sun_path[0] = 0;
+        }
+        let addr_len = (mem::size_of::<libc::sa_family_t>() + path.len()) as libc::socklen_t;
+
+        Ok(Self {
+            sock: UnixDatagram::unbound()?,
+            addr,
+            addr_len,
+            watchdog,
+            next_watchdog: Instant::now(),
+        })
+    }
+
+    /// The notifier of the service manager that started us, if any.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let path = match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let pid = env::var("WATCHDOG_PID").ok();
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| pid.map_or(true, |pid| pid.parse().ok() == Some(process::id())))
+            .map(Duration::from_micros);
+
+        Self::new(&path, watchdog).map(Some)
+    }
+
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        let ret = unsafe {
+            libc::sendto(
+                self.sock.as_raw_fd(),
+                state.as_ptr() as *const libc::c_void,
+                state.len(),
+                libc::MSG_NOSIGNAL,
+                &self.addr as *const _ as *const libc::sockaddr,
+                self.addr_len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn ready(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("READY=1\nSTATUS={}", status))
+    }
+
+    /// Ping the watchdog when it is due, and return the time until the
+    /// next ping.
+    pub fn watchdog(&mut self) -> io::Result<Option<Duration>> {
+        let interval = match self.watchdog {
+            Some(watchdog) => watchdog / 2,
+            None => return Ok(None),
+        };
+
+        let now = Instant::now();
+        if now >= self.next_watchdog {
+            self.notify("WATCHDOG=1")?;
+            self.next_watchdog = now + interval;
+        }
+        Ok(Some(self.next_watchdog - now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn listen_fds() {
+        let pid = process::id().to_string();
+        let pid = Some(pid.as_str());
+
+        assert!(parse_listen_fds(None, Some("1"), None).unwrap().is_empty());
+        assert!(parse_listen_fds(Some("1"), Some("1"), None)
+            .unwrap()
+            .is_empty());
+        assert!(parse_listen_fds(pid, Some("x"), None).is_err());
+        assert_eq!(
+            parse_listen_fds(pid, Some("2"), Some("slirp")).unwrap(),
+            vec![(3, "slirp".to_string()), (4, "unknown".to_string())]
+        );
+    }
+
+    #[test]
+    fn notify() {
+        let path = env::temp_dir().join(format!("slirp-notify-test-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+
+        let mut notifier = Notifier::new(path.as_os_str(), Some(Duration::from_secs(10))).unwrap();
+        notifier.ready("Running").unwrap();
+        let mut buf = [0; 64];
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=Running");
+
+        let next = notifier.watchdog().unwrap().unwrap();
+        assert!(next <= Duration::from_secs(5));
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+        // not due yet
+        notifier.watchdog().unwrap();
+        manager.set_nonblocking(true).unwrap();
+        assert!(manager.recv(&mut buf).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,425 @@
+//! Events of the TCP connections and UDP flows of the guest through slirp.
+//!
+//! They are derived from the frames exchanged with the guest: the SYN, FIN
+//! and RST segments of TCP, and the first and last datagrams of UDP.
+
+use crate::filter::{Filter, Verdict};
+use crate::packet::{Tcp, Udp, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN};
+use crate::Protocol;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+// the expiration of the UDP sockets of libslirp
+const UDP_TIMEOUT: i64 = 240 * NANOS_PER_SEC;
+// the connection timeout of libslirp
+const SYN_TIMEOUT: i64 = 75 * NANOS_PER_SEC;
+// the idle time of an established TCP connection, after which it is
+// considered gone: libslirp sends no keepalives by default, and the guest or
+// the peer may have vanished without a FIN or RST
+const TCP_TIMEOUT: i64 = 2 * 3600 * NANOS_PER_SEC;
+
+/// A TCP connection or UDP flow opened by the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Flow {
+    pub protocol: Protocol,
+    /// The address of the guest.
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+impl fmt::Display for Flow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} -> {}", self.protocol, self.src, self.dst)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// A TCP connection was established, or a UDP flow started.
+    Opened(Flow),
+    /// A TCP connection was reset, or timed out, before it was established.
+    Refused(Flow),
+    /// A TCP connection was closed, reset or idle for two hours, or a UDP
+    /// flow expired.
+    Closed {
+        flow: Flow,
+        /// The bytes of payload from the guest.
+        sent: u64,
+        /// The bytes of payload to the guest.
+        received: u64,
+        duration: Duration,
+    },
+}
+
+struct Conn {
+    established: bool,
+    start: i64,
+    last: i64,
+    sent: u64,
+    received: u64,
+    fin_sent: bool,
+    fin_received: bool,
+}
+
+impl Conn {
+    fn new(established: bool, now: i64) -> Self {
+        Self {
+            established,
+            start: now,
+            last: now,
+            sent: 0,
+            received: 0,
+            fin_sent: false,
+            fin_received: false,
+        }
+    }
+
+    /// The time the connection is considered gone, without another frame.
+    fn expires(&self, protocol: Protocol) -> i64 {
+        match protocol {
+            Protocol::Udp => self.last + UDP_TIMEOUT,
+            Protocol::Tcp if self.established => self.last + TCP_TIMEOUT,
+            Protocol::Tcp => self.start + SYN_TIMEOUT,
+        }
+    }
+}
+
+/// A filter sending the connection events of the guest to a channel.
+pub struct Tracker {
+    conns: HashMap<Flow, Conn>,
+    events: Sender<ConnectionEvent>,
+    next_expire: i64,
+}
+
+impl Tracker {
+    pub fn new(events: Sender<ConnectionEvent>) -> Self {
+        Self {
+            conns: HashMap::new(),
+            events,
+            next_expire: 0,
+        }
+    }
+
+    /// A tracker, and the receiver of its events.
+    pub fn channel() -> (Self, Receiver<ConnectionEvent>) {
+        let (tx, rx) = mpsc::channel();
+        (Self::new(tx), rx)
+    }
+
+    fn emit(&self, event: ConnectionEvent) {
+        // nobody may be listening anymore
+        let _ = self.events.send(event);
+    }
+
+    fn close(&mut self, flow: Flow, now: i64) {
+        let conn = match self.conns.remove(&flow) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if conn.established {
+            self.emit(ConnectionEvent::Closed {
+                flow,
+                sent: conn.sent,
+                received: conn.received,
+                duration: Duration::from_nanos((now - conn.start).max(0) as u64),
+            });
+        } else {
+            self.emit(ConnectionEvent::Refused(flow));
+        }
+    }
+
+    /// Close the UDP flows that libslirp expired, the TCP connections that
+    /// weren't established in time, and the idle ones.
+    fn expire(&mut self, now: i64) {
+        let expired: Vec<_> = self
+            .conns
+            .iter()
+            .filter(|(flow, conn)| now >= conn.expires(flow.protocol))
+            .map(|(flow, _)| *flow)
+            .collect();
+        for flow in expired {
+            // an established flow ended with its last frame
+            let conn = &self.conns[&flow];
+            let end = if conn.established { conn.last } else { now };
+            self.close(flow, end);
+        }
+    }
+
+    /// Expire the flows on the frames too, at most once a second, for the
+    /// event loops without the filter timeouts.
+    fn expire_on_frame(&mut self, now: i64) {
+        if now >= self.next_expire {
+            self.next_expire = now + NANOS_PER_SEC;
+            self.expire(now);
+        }
+    }
+
+    fn guest_tcp(&mut self, tcp: &Tcp, now: i64) {
+        let flow = Flow {
+            protocol: Protocol::Tcp,
+            src: tcp.src,
+            dst: tcp.dst,
+        };
+        if tcp.has(TCP_SYN) && !tcp.has(TCP_ACK) {
+            self.conns
+                .entry(flow)
+                .or_insert_with(|| Conn::new(false, now));
+            return;
+        }
+
+        let conn = match self.conns.get_mut(&flow) {
+            Some(conn) => conn,
+            None => return,
+        };
+        conn.sent += tcp.payload.len() as u64;
+        conn.last = now;
+        if tcp.has(TCP_RST) {
+            self.close(flow, now);
+        } else if tcp.has(TCP_FIN) {
+            conn.fin_sent = true;
+            if conn.fin_received {
+                self.close(flow, now);
+            }
+        }
+    }
+
+    fn slirp_tcp(&mut self, tcp: &Tcp, now: i64) {
+        let flow = Flow {
+            protocol: Protocol::Tcp,
+            src: tcp.dst,
+            dst: tcp.src,
+        };
+        let conn = match self.conns.get_mut(&flow) {
+            Some(conn) => conn,
+            None => return,
+        };
+        conn.received += tcp.payload.len() as u64;
+        conn.last = now;
+
+        if tcp.has(TCP_RST) {
+            self.close(flow, now);
+        } else if tcp.has(TCP_SYN | TCP_ACK) && !conn.established {
+            conn.established = true;
+            conn.start = now;
+            self.emit(ConnectionEvent::Opened(flow));
+        } else if tcp.has(TCP_FIN) {
+            conn.fin_received = true;
+            if conn.fin_sent {
+                self.close(flow, now);
+            }
+        }
+    }
+
+    fn udp(&mut self, udp: &Udp, guest: bool, now: i64) {
+        let (src, dst) = if guest {
+            (udp.src, udp.dst)
+        } else {
+            (udp.dst, udp.src)
+        };
+        let flow = Flow {
+            protocol: Protocol::Udp,
+            src,
+            dst,
+        };
+        if guest && !self.conns.contains_key(&flow) {
+            self.conns.insert(flow, Conn::new(true, now));
+            self.emit(ConnectionEvent::Opened(flow));
+        }
+
+        if let Some(conn) = self.conns.get_mut(&flow) {
+            let len = udp.payload.len() as u64;
+            if guest {
+                conn.sent += len;
+            } else {
+                conn.received += len;
+            }
+            conn.last = now;
+        }
+    }
+}
+
+impl Filter for Tracker {
+    fn input(&mut self, frame: &[u8], now: i64, _reply: &mut Vec<Vec<u8>>) -> Verdict {
+        self.expire_on_frame(now);
+        if let Some(tcp) = Tcp::parse(frame) {
+            self.guest_tcp(&tcp, now);
+        } else if let Some(udp) = Udp::parse(frame) {
+            self.udp(&udp, true, now);
+        }
+        Verdict::Pass
+    }
+
+    fn output(&mut self, frame: &mut Vec<u8>, now: i64) -> Verdict {
+        self.expire_on_frame(now);
+        if let Some(tcp) = Tcp::parse(frame) {
+            self.slirp_tcp(&tcp, now);
+        } else if let Some(udp) = Udp::parse(frame) {
+            self.udp(&udp, false, now);
+        }
+        Verdict::Pass
+    }
+
+    fn deadline(&self) -> Option<i64> {
+        self.conns
+            .iter()
+            .map(|(flow, conn)| conn.expires(flow.protocol))
+            .min()
+    }
+
+    fn timeout(&mut self, now: i64, _reply: &mut Vec<Vec<u8>>) {
+        self.expire(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::udp_frame;
+
+    const GUEST: &str = "10.0.2.15:40000";
+    const REMOTE: &str = "192.0.2.1:80";
+
+    fn tcp(src: &str, dst: &str, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let (src, dst): (SocketAddr, SocketAddr) = (src.parse().unwrap(), dst.parse().unwrap());
+        let ip = |addr: SocketAddr| match addr.ip() {
+            std::net::IpAddr::V4(ip) => ip.octets(),
+            _ => unreachable!(),
+        };
+
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        let len = (20 + 20 + payload.len()) as u16;
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        frame.extend_from_slice(&ip(src));
+        frame.extend_from_slice(&ip(dst));
+        frame.extend_from_slice(&src.port().to_be_bytes());
+        frame.extend_from_slice(&dst.port().to_be_bytes());
+        frame.extend_from_slice(&[0; 8]);
+        frame.extend_from_slice(&[0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn flow(protocol: Protocol) -> Flow {
+        Flow {
+            protocol,
+            src: GUEST.parse().unwrap(),
+            dst: REMOTE.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn tcp_connection() {
+        let (mut tracker, events) = Tracker::channel();
+        let mut reply = Vec::new();
+        let mut input = |t: &mut Tracker, frame: Vec<u8>, now| t.input(&frame, now, &mut reply);
+
+        input(&mut tracker, tcp(GUEST, REMOTE, TCP_SYN, b""), 0);
+        input(&mut tracker, tcp(GUEST, REMOTE, TCP_SYN, b""), 1);
+        assert!(events.try_recv().is_err());
+        tracker.output(&mut tcp(REMOTE, GUEST, TCP_SYN | TCP_ACK, b""), 10);
+        assert_eq!(
+            events.try_recv(),
+            Ok(ConnectionEvent::Opened(flow(Protocol::Tcp)))
+        );
+
+        input(&mut tracker, tcp(GUEST, REMOTE, TCP_ACK, b"GET /"), 20);
+        tracker.output(&mut tcp(REMOTE, GUEST, TCP_ACK, b"200 OK\r\n"), 30);
+        tracker.output(&mut tcp(REMOTE, GUEST, TCP_ACK | TCP_FIN, b""), 40);
+        assert!(events.try_recv().is_err());
+        input(&mut tracker, tcp(GUEST, REMOTE, TCP_ACK | TCP_FIN, b""), 50);
+        assert_eq!(
+            events.try_recv(),
+            Ok(ConnectionEvent::Closed {
+                flow: flow(Protocol::Tcp),
+                sent: 5,
+                received: 8,
+                duration: Duration::from_nanos(40),
+            })
+        );
+        assert!(tracker.conns.is_empty());
+    }
+
+    #[test]
+    fn tcp_refused() {
+        let (mut tracker, events) = Tracker::channel();
+        tracker.input(&tcp(GUEST, REMOTE, TCP_SYN, b""), 0, &mut Vec::new());
+        tracker.output(&mut tcp(REMOTE, GUEST, TCP_RST | TCP_ACK, b""), 1);
+        assert_eq!(
+            events.try_recv(),
+            Ok(ConnectionEvent::Refused(flow(Protocol::Tcp)))
+        );
+
+        // not from the guest
+        tracker.output(&mut tcp(REMOTE, GUEST, TCP_SYN, b""), 2);
+        tracker.input(
+            &tcp(GUEST, REMOTE, TCP_SYN | TCP_ACK, b""),
+            3,
+            &mut Vec::new(),
+        );
+        assert!(events.try_recv().is_err());
+        assert!(tracker.conns.is_empty());
+    }
+
+    #[test]
+    fn tcp_idle() {
+        let (mut tracker, events) = Tracker::channel();
+        tracker.input(&tcp(GUEST, REMOTE, TCP_SYN, b""), 0, &mut Vec::new());
+        assert_eq!(tracker.deadline(), Some(SYN_TIMEOUT));
+        tracker.output(&mut tcp(REMOTE, GUEST, TCP_SYN | TCP_ACK, b""), 10);
+        events.try_recv().unwrap();
+        tracker.input(&tcp(GUEST, REMOTE, TCP_ACK, b"ping"), 20, &mut Vec::new());
+
+        let deadline = tracker.deadline().unwrap();
+        assert_eq!(deadline, 20 + TCP_TIMEOUT);
+        tracker.timeout(deadline - 1, &mut Vec::new());
+        assert!(events.try_recv().is_err());
+        tracker.timeout(deadline, &mut Vec::new());
+        assert_eq!(
+            events.try_recv(),
+            Ok(ConnectionEvent::Closed {
+                flow: flow(Protocol::Tcp),
+                sent: 4,
+                received: 0,
+                duration: Duration::from_nanos(10),
+            })
+        );
+        assert_eq!(tracker.deadline(), None);
+    }
+
+    #[test]
+    fn udp_flow() {
+        let (mut tracker, events) = Tracker::channel();
+        let (guest, remote) = (GUEST.parse().unwrap(), REMOTE.parse().unwrap());
+        let query = udp_frame([2; 6], [4; 6], guest, remote, b"query");
+        let mut answer = udp_frame([4; 6], [2; 6], remote, guest, b"answer");
+
+        tracker.input(&query, 0, &mut Vec::new());
+        assert_eq!(
+            events.try_recv(),
+            Ok(ConnectionEvent::Opened(flow(Protocol::Udp)))
+        );
+        tracker.output(&mut answer, NANOS_PER_SEC);
+        tracker.input(&query, 2 * NANOS_PER_SEC, &mut Vec::new());
+        assert!(events.try_recv().is_err());
+
+        // another frame, once the flow expired
+        tracker.output(&mut answer, 3 * NANOS_PER_SEC + UDP_TIMEOUT);
+        assert_eq!(
+            events.try_recv(),
+            Ok(ConnectionEvent::Closed {
+                flow: flow(Protocol::Udp),
+                sent: 10,
+                received: 6,
+                duration: Duration::from_secs(2),
+            })
+        );
+    }
+}
@@ -1,30 +1,198 @@
 use libslirp_sys::*;
 
-use crate::Opt;
+use crate::conntrack::{ConnectionEvent, Tracker};
+use crate::dhcp;
+use crate::dhcpv6;
+use crate::dns;
+use crate::filter::{Filter, Verdict};
+use crate::packet::{set_tcp_addrs, Tcp, ETH_HLEN, TCP_ACK, TCP_SYN};
+use crate::ra;
+use crate::tftp;
+use crate::{HostFwd, Opt, OptError, Protocol};
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::error::Error;
 use std::ffi::{CStr, CString};
 use std::io;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use std::os::raw::{c_char, c_int, c_void};
 use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
 use std::{fmt, mem, ops, slice, str};
 
+/// The parameters of a [`Context`], given to libslirp.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Isolate the guest from the host.
+    pub restricted: bool,
+    pub ipv4_enabled: bool,
+    pub vnetwork: Ipv4Addr,
+    pub vnetmask: Ipv4Addr,
+    pub vhost: Ipv4Addr,
+    pub ipv6_enabled: bool,
+    pub vprefix_addr6: Ipv6Addr,
+    pub vprefix_len: u8,
+    pub vhost6: Ipv6Addr,
+    pub vhostname: Option<String>,
+    pub tftp_server_name: Option<String>,
+    pub tftp_path: Option<PathBuf>,
+    pub tftp_bootfile: Option<String>,
+    pub vdhcp_start: Ipv4Addr,
+    pub vnameserver: Ipv4Addr,
+    pub vnameserver6: Ipv6Addr,
+    pub vdnssearch: Vec<String>,
+    pub vdomainname: Option<String>,
+    /// 0 for the default of libslirp.
+    pub if_mtu: usize,
+    /// 0 for the default of libslirp.
+    pub if_mru: usize,
+    /// The source address of the outbound sockets, from libslirp 4.2.
+    pub outbound_addr: Option<Ipv4Addr>,
+    /// The source address of the outbound IPv6 sockets, from libslirp 4.2.
+    pub outbound_addr6: Option<Ipv6Addr>,
+}
+
+impl Config {
+    /// The parameters of `opt`, or the error of its DNS suffixes.
+    pub fn from_opt(opt: &Opt) -> Result<Self, OptError> {
+        Ok(Self {
+            restricted: opt.restrict,
+            ipv4_enabled: !opt.ipv4.disable,
+            vnetwork: opt.ipv4.net(),
+            vnetmask: opt.ipv4.mask(),
+            vhost: opt.ipv4.host(),
+            ipv6_enabled: !opt.ipv6.disable,
+            vprefix_addr6: opt.ipv6.prefix(),
+            vprefix_len: opt.ipv6.prefix_len(),
+            vhost6: opt.ipv6.host(),
+            vhostname: opt.hostname.clone(),
+            tftp_server_name: opt.tftp.name.clone(),
+            tftp_path: opt.tftp.root.clone(),
+            tftp_bootfile: opt.tftp.bootfile.clone(),
+            vdhcp_start: opt.ipv4.dhcp_start(),
+            vnameserver: opt.ipv4.dns(),
+            vnameserver6: opt.ipv6.dns(),
+            vdnssearch: opt.dns_search()?,
+            vdomainname: opt.domainname.clone(),
+            if_mtu: opt.mtu() as usize,
+            if_mru: opt.mru() as usize,
+            outbound_addr: opt.outbound_addr,
+            outbound_addr6: opt.outbound_addr6,
+        })
+    }
+}
+
+impl Default for Config {
+    /// The parameters of the default options, the network of QEMU.
+    fn default() -> Self {
+        Self::from_opt(&Opt::default()).expect("invalid default options")
+    }
+}
+
 pub struct Context<H> {
     inner: Box<Inner<H>>,
+    dhcp_leases: Option<dhcp::Leases>,
+    mru: usize,
+    // the frames over the MRU since the last report, and its time
+    oversized: u64,
+    reported: Option<i64>,
+    setup_errors: Vec<SetupError>,
+    // the addresses of libslirp that its guest forwards may not take, and
+    // the one they take instead
+    reserved: [Ipv4Addr; 2],
+    alias: Ipv4Addr,
+}
+
+/// A part of the [`Opt`] that [`Context::new_with_opt`] failed to set up.
+#[derive(Debug)]
+pub enum SetupError {
+    HostFwd(HostFwd, io::Error),
+    OutboundInterface(String, io::Error),
+    DnsLog(io::Error),
+    DnsHosts(io::Error),
+    DnsProxy(io::Error),
+    Tftp(io::Error),
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // the error names the rule
+            SetupError::HostFwd(_, e) => write!(f, "{}", e),
+            SetupError::OutboundInterface(interface, e) => {
+                write!(f, "Failed to use the interface {}: {}", interface, e)
+            }
+            SetupError::DnsLog(e) => write!(f, "Failed to open the DNS log: {}", e),
+            SetupError::DnsHosts(e) => write!(f, "Failed to load the DNS hosts: {}", e),
+            SetupError::DnsProxy(e) => write!(f, "Failed to start the DNS proxy: {}", e),
+            SetupError::Tftp(e) => write!(f, "Failed to start the TFTP server: {}", e),
+        }
+    }
 }
 
+impl Error for SetupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SetupError::HostFwd(_, e) | SetupError::OutboundInterface(_, e) => Some(e),
+            SetupError::DnsLog(e)
+            | SetupError::DnsHosts(e)
+            | SetupError::DnsProxy(e)
+            | SetupError::Tftp(e) => Some(e),
+        }
+    }
+}
+
+// the default MRU of libslirp
+const DEFAULT_MRU: usize = 1500;
+// the frames over the MRU are reported at most once in that time
+const REPORT_INTERVAL: i64 = 10 * 1_000_000_000;
+
 struct Inner<H> {
     context: *mut Slirp,
     callbacks: SlirpCb,
     handler: H,
+    filters: Vec<Box<dyn Filter>>,
+    // boxed, as libslirp keeps their address
+    #[allow(clippy::vec_box)]
+    streams: Vec<Box<Stream<H>>>,
+    // the interface of the outbound sockets
+    device: Option<CString>,
+}
+
+/// A guest forward of libslirp to a filter.
+struct Stream<H> {
+    inner: *mut Inner<H>,
+    filter: usize,
+    addr: SocketAddrV4,
+    // the address of the guest forward in libslirp, if `addr` is reserved
+    alias: Ipv4Addr,
+}
+
+extern "C" fn stream_write_handler<H: Handler>(
+    buf: *const c_void,
+    len: usize,
+    opaque: *mut c_void,
+) -> isize {
+    let (inner, filter, addr) = unsafe {
+        let stream = &*(opaque as *const Stream<H>);
+        (&mut *stream.inner, stream.filter, stream.addr)
+    };
+    let data = unsafe { slice::from_raw_parts(buf as *const u8, len) };
+    let now = inner.handler.clock_get_ns();
+    inner.filters[filter].stream_input(addr, data, now);
+    len as isize
 }
 
 impl<H> Drop for Context<H> {
     fn drop(&mut self) {
-        unsafe {
-            slirp_cleanup(self.inner.context);
+        // unless libslirp failed to create it
+        if !self.inner.context.is_null() {
+            unsafe {
+                slirp_cleanup(self.inner.context);
+            }
         }
     }
 }
@@ -223,7 +391,7 @@ extern "C" fn send_packet_handler<H: Handler>(
     opaque: *mut c_void,
 ) -> isize {
     let slice = unsafe { slice::from_raw_parts(buf as *const u8, len) };
-    let res = unsafe { (*(opaque as *mut Inner<H>)).handler.send_packet(slice) };
+    let res = unsafe { (*(opaque as *mut Inner<H>)).send_packet(slice) };
     if res.is_ok() {
         res.unwrap() as isize
     } else {
@@ -232,6 +400,88 @@ extern "C" fn send_packet_handler<H: Handler>(
     }
 }
 
+impl<H: Handler> Inner<H> {
+    /// Send a frame to the guest, through the filters.
+    fn send_packet(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.filters.is_empty() {
+            return self.handler.send_packet(buf);
+        }
+
+        let now = self.handler.clock_get_ns();
+        let mut frame = buf.to_vec();
+        self.unalias(&mut frame);
+        for filter in &mut self.filters {
+            if filter.output(&mut frame, now) == Verdict::Drop {
+                return Ok(buf.len());
+            }
+        }
+        self.handler.send_packet(&frame)?;
+        Ok(buf.len())
+    }
+
+    /// The stream of a frame from the guest to the TCP address `dst`.
+    fn stream(&self, dst: SocketAddr) -> Option<&Stream<H>> {
+        self.streams
+            .iter()
+            .map(|s| &**s)
+            .find(|s| SocketAddr::V4(s.addr) == dst)
+    }
+
+    /// A frame from the guest, to pass to libslirp, with the address of the
+    /// guest forward of its stream. `None` drops it.
+    fn stream_input<'a>(&mut self, buf: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        let (src, dst, open) = match Tcp::parse(buf) {
+            Some(tcp) => (tcp.src, tcp.dst, tcp.has(TCP_SYN) && !tcp.has(TCP_ACK)),
+            None => return Some(Cow::Borrowed(buf)),
+        };
+        // the guest forwards at an alias are reached through their stream
+        let aliased = self
+            .streams
+            .iter()
+            .any(|s| s.alias != *s.addr.ip() && SocketAddr::from((s.alias, s.addr.port())) == dst);
+        if aliased {
+            return None;
+        }
+        let (filter, addr, alias) = match self.stream(dst) {
+            Some(stream) => (stream.filter, stream.addr, stream.alias),
+            None => return Some(Cow::Borrowed(buf)),
+        };
+
+        if open {
+            let now = self.handler.clock_get_ns();
+            self.filters[filter].stream_open(addr, src, now);
+        }
+        match src {
+            SocketAddr::V4(src) if alias != *addr.ip() => {
+                let mut frame = buf.to_vec();
+                set_tcp_addrs(&mut frame, *src.ip(), alias);
+                Some(Cow::Owned(frame))
+            }
+            _ => Some(Cow::Borrowed(buf)),
+        }
+    }
+
+    /// Give their stream address back to the segments of a guest forward
+    /// at an alias.
+    fn unalias(&self, frame: &mut [u8]) {
+        let (src, dst) = match Tcp::parse(frame) {
+            Some(Tcp {
+                src: SocketAddr::V4(src),
+                dst: SocketAddr::V4(dst),
+                ..
+            }) => (src, dst),
+            _ => return,
+        };
+        let stream = self
+            .streams
+            .iter()
+            .find(|s| s.alias != *s.addr.ip() && SocketAddrV4::new(s.alias, s.addr.port()) == src);
+        if let Some(stream) = stream {
+            set_tcp_addrs(frame, *stream.addr.ip(), *dst.ip());
+        }
+    }
+}
+
 extern "C" fn guest_error_handler<H: Handler>(msg: *const c_char, opaque: *mut c_void) {
     let msg = str::from_utf8(unsafe { CStr::from_ptr(msg) }.to_bytes()).unwrap_or("");
     unsafe { (*(opaque as *mut Inner<H>)).handler.guest_error(msg) }
@@ -278,8 +528,98 @@ extern "C" fn timer_mod_handler<H: Handler>(
     }
 }
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_to_device(fd: RawFd, device: &CStr) -> io::Result<()> {
+    let name = device.to_bytes_with_nul();
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_to_device(_fd: RawFd, _device: &CStr) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "binding to an interface is not supported",
+    ))
+}
+
+/// Replace `fd` with a socket that can't reach the network, on which
+/// libslirp fails its connection or datagrams.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn disable_socket(fd: RawFd) -> io::Result<()> {
+    let flags = libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+    let dead = unsafe { libc::socket(libc::AF_UNIX, flags, 0) };
+    if dead < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe { libc::dup3(dead, fd, libc::O_CLOEXEC) };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(dead) };
+    if ret < 0 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn disable_socket(_fd: RawFd) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "disabling a socket is not supported",
+    ))
+}
+
+/// Reset errno, to tell whether a failing C function set it.
+#[cfg(target_os = "linux")]
+fn clear_errno() -> bool {
+    unsafe { *libc::__errno_location() = 0 };
+    true
+}
+
+#[cfg(target_os = "android")]
+fn clear_errno() -> bool {
+    unsafe { *libc::__errno() = 0 };
+    true
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn clear_errno() -> bool {
+    false
+}
+
+/// Whether `fd` is a connected socket, such as one accepted from a host.
+fn connected(fd: RawFd) -> bool {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    unsafe { libc::getpeername(fd, &mut addr as *mut _ as *mut _, &mut len) == 0 }
+}
+
 extern "C" fn register_poll_fd_handler<H: Handler>(fd: c_int, opaque: *mut c_void) {
-    unsafe { (*(opaque as *mut Inner<H>)).handler.register_poll_fd(fd) }
+    let inner = unsafe { &mut *(opaque as *mut Inner<H>) };
+    // the new sockets to the outside, not the connections of the forwarded
+    // ports
+    if let Some(device) = inner.device.as_ref().filter(|_| !connected(fd)) {
+        if let Err(e) = bind_to_device(fd, device) {
+            eprintln!("Failed to bind to {}: {}", device.to_string_lossy(), e);
+            // fail closed, rather than leave by another interface
+            if let Err(e) = disable_socket(fd) {
+                eprintln!("Failed to disable the socket: {}", e);
+                unsafe { libc::shutdown(fd, libc::SHUT_RDWR) };
+            }
+        }
+    }
+    inner.handler.register_poll_fd(fd)
 }
 
 extern "C" fn unregister_poll_fd_handler<H: Handler>(fd: c_int, opaque: *mut c_void) {
@@ -291,51 +631,82 @@ extern "C" fn notify_handler<H: Handler>(opaque: *mut c_void) {
 }
 
 impl<H: Handler> Context<H> {
-    pub fn new_with_opt(opt: &Opt, handler: H) -> Self {
-        Self::new(
-            opt.restrict,
-            !opt.ipv4.disable,
-            opt.ipv4.net,
-            opt.ipv4.mask,
-            opt.ipv4.host,
-            !opt.ipv6.disable,
-            opt.ipv6.prefix,
-            opt.ipv6.prefix_len,
-            opt.ipv6.host,
-            opt.hostname.clone(),
-            opt.tftp.name.clone(),
-            opt.tftp.root.clone(),
-            opt.tftp.bootfile.clone(),
-            opt.ipv4.dhcp_start,
-            opt.ipv4.dns,
-            opt.ipv6.dns,
-            opt.dns_suffixes.clone(),
-            opt.domainname.clone(),
-            handler,
-        )
+    /// A context set up according to `opt`. The parts that fail to be set
+    /// up, such as a forward of a port in use, are left out and reported by
+    /// [`setup_errors`](Self::setup_errors).
+    ///
+    /// Fails with `InvalidInput` if a DNS suffix is invalid.
+    pub fn new_with_opt(opt: &Opt, handler: H) -> io::Result<Self> {
+        let invalid = |e: OptError| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let mut ctxt = Self::new(&Config::from_opt(opt).map_err(invalid)?, handler)?;
+
+        for fwd in &opt.hostfwd {
+            if let Err(e) = ctxt.add_hostfwd(fwd) {
+                ctxt.setup_errors.push(SetupError::HostFwd(fwd.clone(), e));
+            }
+        }
+        if let Some(interface) = &opt.outbound_interface {
+            if let Err(e) = ctxt.set_outbound_interface(interface) {
+                let error = SetupError::OutboundInterface(interface.clone(), e);
+                ctxt.setup_errors.push(error);
+            }
+        }
+
+        // first, to see the queries answered by the other filters
+        if let Some(path) = &opt.dns_log {
+            match dns::log::JsonLines::append(path) {
+                Ok(sink) => ctxt.add_filter(Box::new(dns::Observer::new(Box::new(sink))))?,
+                Err(e) => ctxt.setup_errors.push(SetupError::DnsLog(e)),
+            }
+        }
+        if !opt.dns_hosts.is_empty() || opt.hosts_file.is_some() {
+            match dns::Hosts::from_opt(opt) {
+                Ok(hosts) => ctxt.add_filter(Box::new(hosts))?,
+                Err(e) => ctxt.setup_errors.push(SetupError::DnsHosts(e)),
+            }
+        }
+        if !opt.dns_upstreams.is_empty() {
+            let proxy = dns::Proxy::from_opt(opt);
+            if let Err(e) = proxy.and_then(|proxy| ctxt.add_filter(Box::new(proxy))) {
+                ctxt.setup_errors.push(SetupError::DnsProxy(e));
+            }
+        }
+        let extra = dhcp::ExtraOptions::from_opt(opt);
+        if !extra.is_empty() {
+            ctxt.add_filter(Box::new(extra))?;
+        }
+        let profile = dhcp::BootProfile::from_opt(opt);
+        if !profile.is_empty() {
+            ctxt.add_filter(Box::new(dhcp::Pxe::new(profile)))?;
+        }
+        // after the filters of the replies, to let them see the requests
+        if !opt.ipv4.disable && opt.ipv4.rust_dhcp() {
+            let server = dhcp::Server::from_opt(opt).map_err(invalid)?;
+            ctxt.dhcp_leases = Some(server.leases());
+            ctxt.add_filter(Box::new(server))?;
+        }
+        let ra = ra::RouterAdvert::from_opt(opt).map_err(invalid)?;
+        if !opt.ipv6.disable && ra.changes() {
+            ctxt.add_filter(Box::new(ra))?;
+        }
+        if !opt.ipv6.disable && opt.ipv6.dhcpv6 {
+            let server = dhcpv6::Server::from_opt(opt).map_err(invalid)?;
+            ctxt.add_filter(Box::new(server))?;
+        }
+        if opt.tftp.server || opt.tftp.archive.is_some() {
+            match tftp::Tftp::from_opt(opt) {
+                Ok(tftp) => ctxt.add_filter(Box::new(tftp))?,
+                Err(e) => ctxt.setup_errors.push(SetupError::Tftp(e)),
+            }
+        }
+
+        Ok(ctxt)
     }
 
-    pub fn new(
-        restricted: bool,
-        ipv4_enabled: bool,
-        vnetwork: Ipv4Addr,
-        vnetmask: Ipv4Addr,
-        vhost: Ipv4Addr,
-        ipv6_enabled: bool,
-        vprefix_addr6: Ipv6Addr,
-        vprefix_len: u8,
-        vhost6: Ipv6Addr,
-        vhostname: Option<String>,
-        tftp_server_name: Option<String>,
-        tftp_path: Option<PathBuf>,
-        tftp_bootfile: Option<String>,
-        vdhcp_start: Ipv4Addr,
-        vnameserver: Ipv4Addr,
-        vnameserver6: Ipv6Addr,
-        vdnssearch: Vec<String>,
-        vdomainname: Option<String>,
-        handler: H,
-    ) -> Self {
+    /// A context of libslirp, failing if libslirp rejects the
+    /// configuration, such as a libslirp older than 4.2 with an outbound
+    /// address.
+    pub fn new(config: &Config, handler: H) -> io::Result<Self> {
         let mut ret = Context {
             inner: Box::new(Inner {
                 context: std::ptr::null_mut(),
@@ -351,10 +722,25 @@ impl<H: Handler> Context<H> {
                     notify: Some(notify_handler::<H>),
                 },
                 handler,
+                filters: Vec::new(),
+                streams: Vec::new(),
+                device: None,
             }),
+            dhcp_leases: None,
+            oversized: 0,
+            reported: None,
+            setup_errors: Vec::new(),
+            reserved: [config.vhost, config.vnameserver],
+            alias: Ipv4Addr::from(u32::from(config.vnetwork) & u32::from(config.vnetmask)),
+            mru: if config.if_mru == 0 {
+                DEFAULT_MRU
+            } else {
+                config.if_mru
+            },
         };
 
-        let cstr_vdns: Vec<_> = vdnssearch
+        let cstr_vdns: Vec<_> = config
+            .vdnssearch
             .iter()
             .map(|arg| CString::new(arg.clone().into_bytes()).unwrap())
             .collect();
@@ -363,48 +749,347 @@ impl<H: Handler> Context<H> {
 
         let as_ptr = |p: &Option<CString>| p.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
 
-        let tftp_path = tftp_path.and_then(|s| CString::new(s.to_string_lossy().into_owned()).ok());
-        let vhostname = vhostname.and_then(|s| CString::new(s).ok());
-        let tftp_server_name = tftp_server_name.and_then(|s| CString::new(s).ok());
-        let tftp_bootfile = tftp_bootfile.and_then(|s| CString::new(s).ok());
-        let vdomainname = vdomainname.and_then(|s| CString::new(s).ok());
+        let cstring = |s: &Option<String>| s.as_ref().and_then(|s| CString::new(s.as_str()).ok());
+        let tftp_path = config
+            .tftp_path
+            .as_ref()
+            .and_then(|s| CString::new(s.to_string_lossy().into_owned()).ok());
+        let vhostname = cstring(&config.vhostname);
+        let tftp_server_name = cstring(&config.tftp_server_name);
+        let tftp_bootfile = cstring(&config.tftp_bootfile);
+        let vdomainname = cstring(&config.vdomainname);
+
+        let mut cfg: SlirpConfig = unsafe { mem::zeroed() };
+        cfg.version = 1;
+        cfg.restricted = config.restricted as i32;
+        cfg.in_enabled = config.ipv4_enabled;
+        cfg.vnetwork = config.vnetwork.into();
+        cfg.vnetmask = config.vnetmask.into();
+        cfg.vhost = config.vhost.into();
+        cfg.in6_enabled = config.ipv6_enabled;
+        cfg.vprefix_addr6 = config.vprefix_addr6.into();
+        cfg.vprefix_len = config.vprefix_len;
+        cfg.vhost6 = config.vhost6.into();
+        cfg.vhostname = as_ptr(&vhostname);
+        cfg.tftp_server_name = as_ptr(&tftp_server_name);
+        cfg.tftp_path = as_ptr(&tftp_path);
+        cfg.bootfile = as_ptr(&tftp_bootfile);
+        cfg.vdhcp_start = config.vdhcp_start.into();
+        cfg.vnameserver = config.vnameserver.into();
+        cfg.vnameserver6 = config.vnameserver6.into();
+        cfg.vdnssearch = p_vdns.as_ptr() as *mut *const _;
+        cfg.vdomainname = as_ptr(&vdomainname);
+        cfg.if_mtu = config.if_mtu;
+        cfg.if_mru = config.if_mru;
+
+        // the source addresses of the outbound sockets, from version 2
+        let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+        if let Some(addr) = config.outbound_addr {
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_addr.s_addr = u32::from(addr).to_be();
+            cfg.version = 2;
+            cfg.outbound_addr = &mut sin as *mut _ as *mut _;
+        }
+        let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+        if let Some(addr) = config.outbound_addr6 {
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_addr.s6_addr = addr.octets();
+            cfg.version = 2;
+            cfg.outbound_addr6 = &mut sin6 as *mut _ as *mut _;
+        }
 
         let ptr = &*ret.inner as *const _ as *mut _;
-        ret.inner.context = unsafe {
-            slirp_init(
-                restricted as i32,
-                ipv4_enabled,
-                vnetwork.into(),
-                vnetmask.into(),
-                vhost.into(),
-                ipv6_enabled,
-                vprefix_addr6.into(),
-                vprefix_len,
-                vhost6.into(),
-                as_ptr(&vhostname),
-                as_ptr(&tftp_server_name),
-                as_ptr(&tftp_path),
-                as_ptr(&tftp_bootfile),
-                vdhcp_start.into(),
-                vnameserver.into(),
-                vnameserver6.into(),
-                p_vdns.as_ptr() as *mut *const _,
-                as_ptr(&vdomainname),
-                &ret.inner.callbacks,
-                ptr,
-            )
-        };
+        ret.inner.context = unsafe { slirp_new(&cfg, &ret.inner.callbacks, ptr) };
 
-        assert!(!ret.inner.context.is_null());
-        ret
+        if ret.inner.context.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "libslirp failed to create the context",
+            ));
+        }
+        Ok(ret)
     }
 
     // FIXME: all methods take &mut self, but could they be immutable instead?
     // This would simplify a lot of code, allowing immutable aliases
     pub fn input(&mut self, buf: &[u8]) {
-        unsafe {
-            slirp_input(self.inner.context, buf.as_ptr(), buf.len() as i32);
+        // the MRU, with the ethernet header
+        if buf.len() > self.mru + ETH_HLEN {
+            self.oversized(buf.len());
+            return;
+        }
+
+        let mut verdict = Verdict::Pass;
+        let mut replies = Vec::new();
+        if !self.inner.filters.is_empty() {
+            let now = self.inner.handler.clock_get_ns();
+            for filter in &mut self.inner.filters {
+                verdict = filter.input(buf, now, &mut replies);
+                if verdict == Verdict::Drop {
+                    break;
+                }
+            }
+        }
+        self.send_replies(replies);
+
+        if verdict == Verdict::Pass {
+            if let Some(frame) = self.inner.stream_input(buf) {
+                unsafe {
+                    slirp_input(self.inner.context, frame.as_ptr(), frame.len() as i32);
+                }
+            }
+        }
+        self.flush_streams();
+    }
+
+    /// Count a frame over the MRU, and report the count to the handler, at
+    /// most once per `REPORT_INTERVAL` as the guest may send many.
+    fn oversized(&mut self, len: usize) {
+        self.oversized += 1;
+        let now = self.inner.handler.clock_get_ns();
+        if self.reported.map_or(false, |t| now - t < REPORT_INTERVAL) {
+            return;
+        }
+        let msg = match self.oversized {
+            1 => format!("frame of {} bytes over the MRU of {}", len, self.mru),
+            n => format!(
+                "{} frames over the MRU of {}, the last of {} bytes",
+                n, self.mru, len
+            ),
+        };
+        self.inner.handler.guest_error(&msg);
+        self.oversized = 0;
+        self.reported = Some(now);
+    }
+
+    /// Add a filter of the frames exchanged with the guest, after the
+    /// existing ones, with a guest forward to each of its streams.
+    ///
+    /// The streams at the host or nameserver address, which libslirp
+    /// reserves, are forwarded at the network address instead.
+    pub fn add_filter(&mut self, filter: Box<dyn Filter>) -> io::Result<()> {
+        let inner = &mut *self.inner as *mut Inner<H>;
+        let mut streams: Vec<Box<Stream<H>>> = Vec::new();
+        for addr in filter.streams() {
+            let alias = if self.reserved.contains(addr.ip()) {
+                self.alias
+            } else {
+                *addr.ip()
+            };
+            let stream = Box::new(Stream {
+                inner,
+                filter: self.inner.filters.len(),
+                addr,
+                alias,
+            });
+            let mut guest_addr = alias.into();
+            let ret = unsafe {
+                slirp_add_guestfwd(
+                    self.inner.context,
+                    Some(stream_write_handler::<H>),
+                    &*stream as *const _ as *mut c_void,
+                    &mut guest_addr,
+                    addr.port().into(),
+                )
+            };
+            if ret < 0 {
+                for stream in streams {
+                    unsafe {
+                        slirp_remove_guestfwd(
+                            self.inner.context,
+                            stream.alias.into(),
+                            stream.addr.port().into(),
+                        );
+                    }
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Failed to forward the guest connections to {}", addr),
+                ));
+            }
+            streams.push(stream);
+        }
+
+        self.inner.streams.extend(streams);
+        self.inner.filters.push(filter);
+        Ok(())
+    }
+
+    /// Pass the output of the streams to their guest connection, as much as
+    /// it takes.
+    fn flush_streams(&mut self) {
+        let mut buf = [0; 4096];
+        for i in 0..self.inner.streams.len() {
+            let (filter, addr, alias) = {
+                let stream = &self.inner.streams[i];
+                (stream.filter, stream.addr, stream.alias)
+            };
+            loop {
+                let room = unsafe {
+                    slirp_socket_can_recv(self.inner.context, alias.into(), addr.port().into())
+                };
+                let room = room.min(buf.len());
+                let len = match room {
+                    0 => break,
+                    _ => self.inner.filters[filter].stream_output(addr, &mut buf[..room]),
+                };
+                if len == 0 {
+                    break;
+                }
+                unsafe {
+                    slirp_socket_recv(
+                        self.inner.context,
+                        alias.into(),
+                        addr.port().into(),
+                        buf.as_ptr(),
+                        len as c_int,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Bind the sockets that libslirp opens from now on for the guest, such
+    /// as the ones of its connections, to a host interface
+    /// (`SO_BINDTODEVICE`).
+    ///
+    /// The listening sockets of the forwarded ports, and the connections
+    /// they accept, are left unbound: they face the host. A socket that
+    /// fails to be bound is disabled, failing its connection or datagrams.
+    pub fn set_outbound_interface(&mut self, interface: &str) -> io::Result<()> {
+        let device =
+            CString::new(interface).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if cfg!(not(any(target_os = "linux", target_os = "android"))) {
+            return bind_to_device(-1, &device);
+        }
+        if unsafe { libc::if_nametoindex(device.as_ptr()) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.inner.device = Some(device);
+        Ok(())
+    }
+
+    /// The parts of the options that failed to be set up by
+    /// [`new_with_opt`](Self::new_with_opt), while the others are in use.
+    pub fn setup_errors(&self) -> &[SetupError] {
+        &self.setup_errors
+    }
+
+    /// The leases of the DHCP server in Rust, if it replaces the one of
+    /// libslirp.
+    pub fn dhcp_leases(&self) -> Option<&dhcp::Leases> {
+        self.dhcp_leases.as_ref()
+    }
+
+    /// Track the connections of the guest, and receive their events.
+    pub fn connection_events(&mut self) -> Receiver<ConnectionEvent> {
+        let (tracker, events) = Tracker::channel();
+        // without streams
+        self.inner.filters.push(Box::new(tracker));
+        events
+    }
+
+    /// The file descriptors of the filters, to poll for reading.
+    pub fn filter_fds(&self) -> Vec<RawFd> {
+        self.inner.filters.iter().flat_map(|f| f.fds()).collect()
+    }
+
+    /// The file descriptors of the filters to poll for writing as well.
+    pub fn filter_write_fds(&self) -> Vec<RawFd> {
+        self.inner
+            .filters
+            .iter()
+            .flat_map(|f| f.write_fds())
+            .collect()
+    }
+
+    /// Let the filter of `fd` handle it, once it is ready.
+    pub fn filter_ready(&mut self, fd: RawFd) {
+        let mut replies = Vec::new();
+        let now = self.inner.handler.clock_get_ns();
+        for filter in &mut self.inner.filters {
+            if filter.fds().contains(&fd) {
+                filter.ready(fd, now, &mut replies);
+            }
+        }
+        self.send_replies(replies);
+        self.flush_streams();
+    }
+
+    /// Let the filters whose deadline has passed handle it, returning the
+    /// time until the next deadline, to poll for.
+    pub fn filter_timeout(&mut self) -> Option<Duration> {
+        let mut replies = Vec::new();
+        let now = self.inner.handler.clock_get_ns();
+        let mut next: Option<i64> = None;
+        for filter in &mut self.inner.filters {
+            if filter.deadline().map_or(false, |d| d <= now) {
+                filter.timeout(now, &mut replies);
+            }
+            if let Some(deadline) = filter.deadline() {
+                next = Some(next.map_or(deadline, |n| n.min(deadline)));
+            }
+        }
+        self.send_replies(replies);
+        self.flush_streams();
+        next.map(|deadline| Duration::from_nanos((deadline - now).max(0) as u64))
+    }
+
+    fn send_replies(&mut self, replies: Vec<Vec<u8>>) {
+        for reply in replies {
+            if let Err(e) = self.inner.send_packet(&reply) {
+                eprintln!("send_packet error: {}", e);
+            }
+        }
+    }
+
+    pub fn add_hostfwd(&mut self, fwd: &HostFwd) -> io::Result<()> {
+        // the listening socket faces the host, not the outbound interface
+        let device = self.inner.device.take();
+        let cleared = clear_errno();
+        let ret = unsafe {
+            slirp_add_hostfwd(
+                self.inner.context,
+                (fwd.protocol == Protocol::Udp) as c_int,
+                fwd.host_addr.unwrap_or(Ipv4Addr::UNSPECIFIED).into(),
+                fwd.host_port.into(),
+                fwd.guest_addr.unwrap_or(Ipv4Addr::UNSPECIFIED).into(),
+                fwd.guest_port.into(),
+            )
+        };
+        // the error of the listening socket, usually of its bind
+        let err = io::Error::last_os_error();
+        self.inner.device = device;
+
+        if ret < 0 {
+            let msg = format!("Failed to forward {}", fwd);
+            return Err(match err.raw_os_error() {
+                Some(errno) if cleared && errno != 0 => {
+                    io::Error::new(err.kind(), format!("{}: {}", msg, err))
+                }
+                _ => io::Error::new(io::ErrorKind::Other, msg),
+            });
         }
+        Ok(())
+    }
+
+    pub fn remove_hostfwd(&mut self, fwd: &HostFwd) -> io::Result<()> {
+        let ret = unsafe {
+            slirp_remove_hostfwd(
+                self.inner.context,
+                (fwd.protocol == Protocol::Udp) as c_int,
+                fwd.host_addr.unwrap_or(Ipv4Addr::UNSPECIFIED).into(),
+                fwd.host_port.into(),
+            )
+        };
+
+        if ret < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No forward of {} host port {}", fwd.protocol, fwd.host_port),
+            ));
+        }
+        Ok(())
     }
 
     pub fn connection_info(&mut self) -> &str {
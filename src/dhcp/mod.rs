@@ -0,0 +1,304 @@
+//! DHCP messages of the guest, for the filters adjusting the replies of
+//! the libslirp server.
+
+use crate::packet::{udp_frame, Udp};
+
+use std::net::Ipv4Addr;
+
+pub mod options;
+pub mod pxe;
+pub mod server;
+
+pub use self::options::ExtraOptions;
+pub use self::pxe::{Arch, BootProfile, Pxe};
+pub use self::server::{Lease, Leases, Server};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+pub const OP_REQUEST: u8 = 1;
+pub const OP_REPLY: u8 = 2;
+
+pub const OPT_PAD: u8 = 0;
+pub const OPT_SUBNET_MASK: u8 = 1;
+pub const OPT_ROUTER: u8 = 3;
+pub const OPT_DNS: u8 = 6;
+pub const OPT_HOSTNAME: u8 = 12;
+pub const OPT_DOMAIN_NAME: u8 = 15;
+pub const OPT_INTERFACE_MTU: u8 = 26;
+pub const OPT_REQUESTED_ADDR: u8 = 50;
+pub const OPT_LEASE_TIME: u8 = 51;
+pub const OPT_MESSAGE_TYPE: u8 = 53;
+pub const OPT_SERVER_ID: u8 = 54;
+pub const OPT_VENDOR_CLASS: u8 = 60;
+pub const OPT_TFTP_SERVER: u8 = 66;
+pub const OPT_USER_CLASS: u8 = 77;
+pub const OPT_CLIENT_ARCH: u8 = 93;
+pub const OPT_DOMAIN_SEARCH: u8 = 119;
+pub const OPT_END: u8 = 255;
+
+pub const DHCPDISCOVER: u8 = 1;
+pub const DHCPOFFER: u8 = 2;
+pub const DHCPREQUEST: u8 = 3;
+pub const DHCPDECLINE: u8 = 4;
+pub const DHCPACK: u8 = 5;
+pub const DHCPNAK: u8 = 6;
+pub const DHCPRELEASE: u8 = 7;
+pub const DHCPINFORM: u8 = 8;
+
+const MAGIC: [u8; 4] = [99, 130, 83, 99];
+// the fixed part of a BOOTP message, before the magic cookie
+const FIXED_LEN: usize = 236;
+const SNAME: usize = 44;
+const SNAME_LEN: usize = 64;
+const FILE: usize = 108;
+const FILE_LEN: usize = 128;
+// BOOTP messages are padded to this length
+const MIN_LEN: usize = 300;
+
+/// A DHCP message, with its options in order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    fixed: Vec<u8>,
+    pub options: Vec<(u8, Vec<u8>)>,
+}
+
+impl Message {
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        if msg.len() < FIXED_LEN + MAGIC.len() || msg[FIXED_LEN..FIXED_LEN + 4] != MAGIC {
+            return None;
+        }
+
+        let mut options: Vec<(u8, Vec<u8>)> = Vec::new();
+        let mut pos = FIXED_LEN + MAGIC.len();
+        while pos < msg.len() {
+            match msg[pos] {
+                OPT_PAD => pos += 1,
+                OPT_END => break,
+                code => {
+                    let len = usize::from(*msg.get(pos + 1)?);
+                    let data = msg.get(pos + 2..pos + 2 + len)?;
+                    // a long option is split in several (RFC 3396)
+                    match options.iter_mut().find(|(c, _)| *c == code) {
+                        Some((_, prev)) => prev.extend_from_slice(data),
+                        None => options.push((code, data.to_vec())),
+                    }
+                    pos += 2 + len;
+                }
+            }
+        }
+
+        Some(Self {
+            fixed: msg[..FIXED_LEN].to_vec(),
+            options,
+        })
+    }
+
+    pub fn op(&self) -> u8 {
+        self.fixed[0]
+    }
+
+    pub fn xid(&self) -> u32 {
+        u32::from_be_bytes([self.fixed[4], self.fixed[5], self.fixed[6], self.fixed[7]])
+    }
+
+    fn addr(&self, pos: usize) -> Ipv4Addr {
+        let f = &self.fixed[pos..pos + 4];
+        Ipv4Addr::new(f[0], f[1], f[2], f[3])
+    }
+
+    /// The address of the client, once it has one.
+    pub fn ciaddr(&self) -> Ipv4Addr {
+        self.addr(12)
+    }
+
+    /// The address assigned to the client.
+    pub fn yiaddr(&self) -> Ipv4Addr {
+        self.addr(16)
+    }
+
+    /// A reply to this request, assigning `yiaddr`, without any option.
+    pub fn response(&self, yiaddr: Ipv4Addr, siaddr: Ipv4Addr) -> Self {
+        let mut fixed = vec![0; FIXED_LEN];
+        fixed[0] = OP_REPLY;
+        // hardware type and address length
+        fixed[1..3].copy_from_slice(&self.fixed[1..3]);
+        // xid, then the flags and ciaddr after secs
+        fixed[4..8].copy_from_slice(&self.fixed[4..8]);
+        fixed[10..16].copy_from_slice(&self.fixed[10..16]);
+        fixed[16..20].copy_from_slice(&yiaddr.octets());
+        fixed[20..24].copy_from_slice(&siaddr.octets());
+        // giaddr and chaddr
+        fixed[24..44].copy_from_slice(&self.fixed[24..44]);
+        Self {
+            fixed,
+            options: Vec::new(),
+        }
+    }
+
+    /// The hardware address of the client.
+    pub fn chaddr(&self) -> [u8; 6] {
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&self.fixed[28..34]);
+        mac
+    }
+
+    /// The data of the first option `code`.
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, data)| &data[..])
+    }
+
+    pub fn message_type(&self) -> Option<u8> {
+        self.option(OPT_MESSAGE_TYPE)
+            .filter(|data| data.len() == 1)
+            .map(|data| data[0])
+    }
+
+    /// Replace the option `code`, or add it before the end.
+    pub fn set_option(&mut self, code: u8, data: &[u8]) {
+        self.options.retain(|(c, _)| *c != code);
+        self.options.push((code, data.to_vec()));
+    }
+
+    fn field(&self, pos: usize, len: usize) -> String {
+        let field = &self.fixed[pos..pos + len];
+        let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+        String::from_utf8_lossy(&field[..end]).into_owned()
+    }
+
+    fn set_field(&mut self, pos: usize, len: usize, value: &str) -> bool {
+        // with the terminating nul
+        if value.len() >= len {
+            return false;
+        }
+        let field = &mut self.fixed[pos..pos + len];
+        field.iter_mut().for_each(|b| *b = 0);
+        field[..value.len()].copy_from_slice(value.as_bytes());
+        true
+    }
+
+    /// The boot file name of the BOOTP header.
+    pub fn file(&self) -> String {
+        self.field(FILE, FILE_LEN)
+    }
+
+    /// Set the boot file name, if it fits in the BOOTP header.
+    pub fn set_file(&mut self, file: &str) -> bool {
+        self.set_field(FILE, FILE_LEN, file)
+    }
+
+    /// The server host name of the BOOTP header.
+    pub fn sname(&self) -> String {
+        self.field(SNAME, SNAME_LEN)
+    }
+
+    pub fn set_sname(&mut self, sname: &str) -> bool {
+        self.set_field(SNAME, SNAME_LEN, sname)
+    }
+
+    /// The message, with its options of up to 255 bytes each.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut msg = self.fixed.clone();
+        msg.extend_from_slice(&MAGIC);
+        for (code, data) in &self.options {
+            for chunk in data.chunks(255) {
+                msg.push(*code);
+                msg.push(chunk.len() as u8);
+                msg.extend_from_slice(chunk);
+            }
+        }
+        msg.push(OPT_END);
+        if msg.len() < MIN_LEN {
+            msg.resize(MIN_LEN, OPT_PAD);
+        }
+        msg
+    }
+}
+
+/// The DHCP request of the guest in a frame.
+pub fn request(frame: &[u8]) -> Option<Message> {
+    let udp = Udp::parse(frame).filter(|u| u.dst.port() == SERVER_PORT)?;
+    Message::parse(udp.payload).filter(|m| m.op() == OP_REQUEST)
+}
+
+/// The DHCP reply to the guest in a frame.
+pub fn reply(frame: &[u8]) -> Option<Message> {
+    let udp = Udp::parse(frame).filter(|u| u.src.port() == SERVER_PORT)?;
+    Message::parse(udp.payload).filter(|m| m.op() == OP_REPLY)
+}
+
+/// Replace the DHCP message of a frame, with the checksums updated.
+pub fn rewrite(frame: &mut Vec<u8>, msg: &Message) {
+    let udp = match Udp::parse(frame) {
+        Some(udp) => udp,
+        None => return,
+    };
+    let (src_mac, dst_mac, src, dst) = (udp.src_mac, udp.dst_mac, udp.src, udp.dst);
+    *frame = udp_frame(src_mac, dst_mac, src, dst, &msg.to_bytes());
+}
+
+#[cfg(test)]
+pub(crate) fn message(op: u8, xid: u32, chaddr: [u8; 6], options: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut msg = vec![0; FIXED_LEN];
+    msg[0] = op;
+    msg[1] = 1;
+    msg[2] = 6;
+    msg[4..8].copy_from_slice(&xid.to_be_bytes());
+    msg[28..34].copy_from_slice(&chaddr);
+    msg.extend_from_slice(&MAGIC);
+    for (code, data) in options {
+        msg.push(*code);
+        msg.push(data.len() as u8);
+        msg.extend_from_slice(data);
+    }
+    msg.push(OPT_END);
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message() {
+        let mac = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+        let mut raw = super::message(
+            OP_REQUEST,
+            0xdead_beef,
+            mac,
+            &[
+                (OPT_MESSAGE_TYPE, &[DHCPDISCOVER]),
+                (OPT_CLIENT_ARCH, &[0, 7]),
+            ],
+        );
+        raw.insert(FIXED_LEN + 4, OPT_PAD);
+        let mut msg = Message::parse(&raw).unwrap();
+        assert_eq!(
+            (msg.op(), msg.xid(), msg.chaddr()),
+            (OP_REQUEST, 0xdead_beef, mac)
+        );
+        assert_eq!(msg.message_type(), Some(DHCPDISCOVER));
+        assert_eq!(msg.option(OPT_CLIENT_ARCH), Some(&[0, 7][..]));
+        assert_eq!(msg.option(OPT_USER_CLASS), None);
+
+        assert!(msg.set_file("efi/boot.efi"));
+        assert!(!msg.set_file(&"x".repeat(FILE_LEN)));
+        assert!(msg.set_sname("boot.internal"));
+        msg.set_option(OPT_CLIENT_ARCH, &[0, 9]);
+        msg.set_option(OPT_VENDOR_CLASS, &[b'x'; 300]);
+        let bytes = msg.to_bytes();
+        assert_eq!(bytes.len(), 4 + FIXED_LEN + 3 + 4 + 255 + 2 + 45 + 2 + 1);
+        let msg = Message::parse(&bytes).unwrap();
+        assert_eq!(msg.file(), "efi/boot.efi");
+        assert_eq!(msg.sname(), "boot.internal");
+        assert_eq!(msg.option(OPT_CLIENT_ARCH), Some(&[0, 9][..]));
+        // split in two options, and put back together
+        assert_eq!(msg.option(OPT_VENDOR_CLASS), Some(&[b'x'; 300][..]));
+
+        assert_eq!(Message::parse(&raw[..FIXED_LEN]), None);
+        assert_eq!(Message::parse(&raw[..FIXED_LEN + 6]), None);
+    }
+}
@@ -0,0 +1,132 @@
+use super::{reply, rewrite, DHCPACK, DHCPOFFER, OPT_INTERFACE_MTU};
+use crate::filter::{Filter, Verdict};
+use crate::Opt;
+
+/// A filter adding options to the DHCP OFFER and ACK of libslirp, or
+/// replacing the ones it sets.
+#[derive(Debug, Clone, Default)]
+pub struct ExtraOptions {
+    options: Vec<(u8, Vec<u8>)>,
+}
+
+impl ExtraOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The `--dhcp-option` options, after the MTU of `--mtu`.
+    pub fn from_opt(opt: &Opt) -> Self {
+        let mut extra = Self::new();
+        if let Some(mtu) = opt.mtu {
+            // libslirp doesn't advertise it
+            extra.insert(OPT_INTERFACE_MTU, &(mtu.min(0xffff) as u16).to_be_bytes());
+        }
+        for option in &opt.dhcp_options {
+            extra.insert(option.code, &option.data);
+        }
+        extra
+    }
+
+    /// Add the option `code`, or replace it. Options longer than 255
+    /// bytes are split (RFC 3396).
+    pub fn insert(&mut self, code: u8, data: &[u8]) {
+        self.options.retain(|(c, _)| *c != code);
+        self.options.push((code, data.to_vec()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty()
+    }
+}
+
+impl Filter for ExtraOptions {
+    fn output(&mut self, frame: &mut Vec<u8>, _now: i64) -> Verdict {
+        let mut msg = match reply(frame) {
+            Some(msg) => msg,
+            None => return Verdict::Pass,
+        };
+        if let Some(DHCPOFFER) | Some(DHCPACK) = msg.message_type() {
+            for (code, data) in &self.options {
+                msg.set_option(*code, data);
+            }
+            rewrite(frame, &msg);
+        }
+        Verdict::Pass
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{message, Message, OPT_MESSAGE_TYPE, OP_REPLY, OP_REQUEST};
+    use super::*;
+    use crate::packet::{checksum, pseudo_header_sum, sum, udp_frame, Udp, ETH_HLEN};
+
+    #[test]
+    fn filter() {
+        let mut extra = ExtraOptions::new();
+        assert!(extra.is_empty());
+        extra.insert(42, &[10, 0, 2, 2]);
+        extra.insert(26, &[0x05, 0x78]);
+        extra.insert(42, &[10, 0, 2, 3]);
+
+        let mac = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+        let frame = |msg: &[u8]| {
+            udp_frame(
+                [0x52, 0x55, 10, 0, 2, 2],
+                mac,
+                "10.0.2.2:67".parse().unwrap(),
+                "10.0.2.15:68".parse().unwrap(),
+                msg,
+            )
+        };
+        let ack = message(
+            OP_REPLY,
+            1,
+            mac,
+            &[(OPT_MESSAGE_TYPE, &[DHCPACK]), (26, &[2, 0])],
+        );
+        let mut ack = frame(&ack);
+        assert_eq!(extra.output(&mut ack, 0), Verdict::Pass);
+
+        let udp = Udp::parse(&ack).unwrap();
+        let msg = Message::parse(udp.payload).unwrap();
+        assert_eq!(msg.option(42), Some(&[10, 0, 2, 3][..]));
+        assert_eq!(msg.option(26), Some(&[0x05, 0x78][..]));
+        // the checksums of the rebuilt frame
+        let ip = &ack[ETH_HLEN..ETH_HLEN + 20];
+        assert_eq!(checksum(sum(0, ip)), 0);
+        let len = ack.len() - ETH_HLEN - 20;
+        let pseudo = pseudo_header_sum(udp.src.ip(), udp.dst.ip(), 17, len);
+        assert_eq!(checksum(sum(pseudo, &ack[ETH_HLEN + 20..])), 0);
+
+        // not a reply, nor an OFFER or ACK
+        let request = frame(&message(
+            OP_REQUEST,
+            1,
+            mac,
+            &[(OPT_MESSAGE_TYPE, &[DHCPACK])],
+        ));
+        let mut out = request.clone();
+        extra.output(&mut out, 0);
+        assert_eq!(out, request);
+        let nak = frame(&message(OP_REPLY, 1, mac, &[(OPT_MESSAGE_TYPE, &[6])]));
+        let mut out = nak.clone();
+        extra.output(&mut out, 0);
+        assert_eq!(out, nak);
+    }
+
+    #[test]
+    fn mtu() {
+        use structopt::StructOpt;
+        let extra = |args: &[&str]| ExtraOptions::from_opt(&Opt::from_iter(args)).options;
+        assert_eq!(extra(&["slirp"]), vec![]);
+        assert_eq!(
+            extra(&["slirp", "--mtu=9000"]),
+            vec![(OPT_INTERFACE_MTU, vec![0x23, 0x28])]
+        );
+        assert_eq!(
+            extra(&["slirp", "--mtu=9000", "--dhcp-option=26=1400"]),
+            vec![(OPT_INTERFACE_MTU, vec![0x05, 0x78])]
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use super::{
+    reply, request, rewrite, DHCPACK, DHCPDISCOVER, DHCPOFFER, DHCPREQUEST, OPT_CLIENT_ARCH,
+    OPT_USER_CLASS,
+};
+use crate::filter::{Filter, Verdict};
+use crate::Opt;
+
+use std::collections::HashMap;
+
+/// The architecture of a PXE client, from the DHCP option 93 (RFC 4578).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Arch {
+    Bios,
+    UefiX64,
+    UefiArm64,
+    Other(u16),
+}
+
+impl Arch {
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            0 => Arch::Bios,
+            // EFI byte code, as sent by some x86-64 firmwares
+            7 | 9 => Arch::UefiX64,
+            11 => Arch::UefiArm64,
+            code => Arch::Other(code),
+        }
+    }
+}
+
+/// The boot files of the PXE clients, by architecture. The clients without
+/// one get the default `--tftp-bootfile`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BootProfile {
+    pub bios: Option<String>,
+    pub uefi_x64: Option<String>,
+    pub uefi_arm64: Option<String>,
+    /// The boot file of iPXE, once it was chainloaded, such as a script.
+    pub ipxe: Option<String>,
+}
+
+impl BootProfile {
+    pub fn from_opt(opt: &Opt) -> Self {
+        Self {
+            bios: opt.tftp.bootfile_bios.clone(),
+            uefi_x64: opt.tftp.bootfile_uefi.clone(),
+            uefi_arm64: opt.tftp.bootfile_arm64.clone(),
+            ipxe: opt.tftp.bootfile_ipxe.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The boot file of a client, unless it's the default one.
+    pub fn bootfile(&self, arch: Option<Arch>, ipxe: bool) -> Option<&str> {
+        let file = match (ipxe, arch) {
+            (true, _) if self.ipxe.is_some() => &self.ipxe,
+            (_, Some(Arch::Bios)) => &self.bios,
+            (_, Some(Arch::UefiX64)) => &self.uefi_x64,
+            (_, Some(Arch::UefiArm64)) => &self.uefi_arm64,
+            _ => return None,
+        };
+        file.as_ref().map(String::as_str)
+    }
+}
+
+fn is_ipxe(user_class: &[u8]) -> bool {
+    // iPXE sends the name alone, instead of a list of RFC 3004
+    if user_class == b"iPXE" {
+        return true;
+    }
+    let mut pos = 0;
+    while pos < user_class.len() {
+        let len = usize::from(user_class[pos]);
+        if user_class.get(pos + 1..pos + 1 + len) == Some(b"iPXE") {
+            return true;
+        }
+        pos += 1 + len;
+    }
+    false
+}
+
+/// A filter setting the boot file of the DHCP replies to the one of the
+/// client architecture, or of iPXE.
+///
+/// The file is chosen on the DISCOVER and REQUEST of a client, and set in
+/// the BOOTP header of the OFFER and ACK from libslirp.
+#[derive(Debug, Default)]
+pub struct Pxe {
+    profile: BootProfile,
+    // by hardware address
+    clients: HashMap<[u8; 6], String>,
+}
+
+impl Pxe {
+    pub fn new(profile: BootProfile) -> Self {
+        Self {
+            profile,
+            clients: HashMap::new(),
+        }
+    }
+}
+
+impl Filter for Pxe {
+    fn input(&mut self, frame: &[u8], _now: i64, _reply: &mut Vec<Vec<u8>>) -> Verdict {
+        let msg = match request(frame) {
+            Some(msg) => msg,
+            None => return Verdict::Pass,
+        };
+        if let Some(DHCPDISCOVER) | Some(DHCPREQUEST) = msg.message_type() {
+            let arch = msg
+                .option(OPT_CLIENT_ARCH)
+                .filter(|data| data.len() >= 2)
+                .map(|data| Arch::from_code(u16::from_be_bytes([data[0], data[1]])));
+            let ipxe = msg.option(OPT_USER_CLASS).map_or(false, is_ipxe);
+            match self.profile.bootfile(arch, ipxe) {
+                Some(file) => self.clients.insert(msg.chaddr(), file.to_string()),
+                None => self.clients.remove(&msg.chaddr()),
+            };
+        }
+        Verdict::Pass
+    }
+
+    fn output(&mut self, frame: &mut Vec<u8>, _now: i64) -> Verdict {
+        let mut msg = match reply(frame) {
+            Some(msg) => msg,
+            None => return Verdict::Pass,
+        };
+        if let Some(DHCPOFFER) | Some(DHCPACK) = msg.message_type() {
+            if let Some(file) = self.clients.get(&msg.chaddr()) {
+                if msg.set_file(file) {
+                    rewrite(frame, &msg);
+                }
+            }
+        }
+        Verdict::Pass
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{message, Message, OPT_MESSAGE_TYPE, OP_REPLY, OP_REQUEST, SERVER_PORT};
+    use super::*;
+    use crate::packet::{udp_frame, Udp};
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+
+    fn profile() -> BootProfile {
+        BootProfile {
+            bios: Some("pxelinux.0".into()),
+            uefi_x64: Some("efi/x64/shim.efi".into()),
+            uefi_arm64: None,
+            ipxe: Some("boot.ipxe".into()),
+        }
+    }
+
+    // the file of the OFFER, after a DISCOVER with `options`
+    fn offer(pxe: &mut Pxe, options: &[(u8, &[u8])]) -> String {
+        let mut options = options.to_vec();
+        options.push((OPT_MESSAGE_TYPE, &[DHCPDISCOVER]));
+        let discover = message(OP_REQUEST, 1, MAC, &options);
+        let frame = udp_frame(
+            MAC,
+            [0xff; 6],
+            "0.0.0.0:68".parse().unwrap(),
+            "255.255.255.255:67".parse().unwrap(),
+            &discover,
+        );
+        assert_eq!(pxe.input(&frame, 0, &mut Vec::new()), Verdict::Pass);
+
+        let mut offer = Message::parse(&message(
+            OP_REPLY,
+            1,
+            MAC,
+            &[(OPT_MESSAGE_TYPE, &[DHCPOFFER])],
+        ))
+        .unwrap();
+        offer.set_file("default.0");
+        let mut frame = udp_frame(
+            [0x52, 0x55, 10, 0, 2, 2],
+            [0xff; 6],
+            format!("10.0.2.2:{}", SERVER_PORT).parse().unwrap(),
+            "255.255.255.255:68".parse().unwrap(),
+            &offer.to_bytes(),
+        );
+        assert_eq!(pxe.output(&mut frame, 0), Verdict::Pass);
+        let udp = Udp::parse(&frame).unwrap();
+        assert_eq!(udp.dst, "255.255.255.255:68".parse().unwrap());
+        Message::parse(udp.payload).unwrap().file()
+    }
+
+    #[test]
+    fn bootfile() {
+        let profile = profile();
+        assert_eq!(
+            profile.bootfile(Some(Arch::Bios), false),
+            Some("pxelinux.0")
+        );
+        assert_eq!(
+            profile.bootfile(Some(Arch::UefiX64), true),
+            Some("boot.ipxe")
+        );
+        assert_eq!(profile.bootfile(Some(Arch::UefiArm64), false), None);
+        assert_eq!(profile.bootfile(None, false), None);
+        assert!(!profile.is_empty());
+        assert!(BootProfile::default().is_empty());
+
+        assert!(is_ipxe(b"iPXE"));
+        assert!(is_ipxe(b"\x03abc\x04iPXE"));
+        assert!(!is_ipxe(b"\x05iPXE"));
+        assert_eq!(Arch::from_code(7), Arch::UefiX64);
+        assert_eq!(Arch::from_code(2), Arch::Other(2));
+    }
+
+    #[test]
+    fn filter() {
+        let mut pxe = Pxe::new(profile());
+        assert_eq!(offer(&mut pxe, &[(OPT_CLIENT_ARCH, &[0, 0])]), "pxelinux.0");
+        assert_eq!(
+            offer(&mut pxe, &[(OPT_CLIENT_ARCH, &[0, 9])]),
+            "efi/x64/shim.efi"
+        );
+        let ipxe: &[(u8, &[u8])] = &[(OPT_CLIENT_ARCH, &[0, 9]), (OPT_USER_CLASS, b"iPXE")];
+        assert_eq!(offer(&mut pxe, ipxe), "boot.ipxe");
+        // the default one
+        assert_eq!(offer(&mut pxe, &[(OPT_CLIENT_ARCH, &[0, 11])]), "default.0");
+        assert_eq!(offer(&mut pxe, &[]), "default.0");
+    }
+}
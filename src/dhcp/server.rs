@@ -0,0 +1,445 @@
+use super::{
+    request, Message, CLIENT_PORT, DHCPACK, DHCPDECLINE, DHCPDISCOVER, DHCPINFORM, DHCPNAK,
+    DHCPOFFER, DHCPRELEASE, DHCPREQUEST, OPT_DNS, OPT_DOMAIN_NAME, OPT_DOMAIN_SEARCH, OPT_HOSTNAME,
+    OPT_LEASE_TIME, OPT_MESSAGE_TYPE, OPT_REQUESTED_ADDR, OPT_ROUTER, OPT_SERVER_ID,
+    OPT_SUBNET_MASK, OPT_TFTP_SERVER, SERVER_PORT,
+};
+use crate::dnssearch;
+use crate::filter::{Filter, Verdict};
+use crate::packet::udp_frame;
+use crate::{Opt, OptError};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+
+// as libslirp, in seconds
+pub const LEASE_TIME: u32 = 24 * 3600;
+// an offered address is kept for the client for a minute
+const OFFER_TIME: i64 = 60 * 1_000_000_000;
+// a declined address is in use on the network, and left out for an hour
+const DECLINE_TIME: i64 = 3600 * 1_000_000_000;
+
+/// An address assigned to a guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub mac: [u8; 6],
+    pub addr: Ipv4Addr,
+    /// The time it expires, from `Handler::clock_get_ns`.
+    pub expires: i64,
+}
+
+/// The leases of a DHCP [`Server`], shared with it.
+///
+/// The expired leases are removed on the next DHCP message of a guest.
+#[derive(Debug, Clone, Default)]
+pub struct Leases(Rc<RefCell<Vec<Lease>>>);
+
+impl Leases {
+    pub fn all(&self) -> Vec<Lease> {
+        self.0.borrow().clone()
+    }
+
+    /// The lease of a guest, by its MAC address.
+    pub fn by_mac(&self, mac: [u8; 6]) -> Option<Lease> {
+        self.0.borrow().iter().find(|l| l.mac == mac).cloned()
+    }
+
+    pub fn by_addr(&self, addr: Ipv4Addr) -> Option<Lease> {
+        self.0.borrow().iter().find(|l| l.addr == addr).cloned()
+    }
+
+    fn insert(&self, lease: Lease) {
+        let mut leases = self.0.borrow_mut();
+        leases.retain(|l| l.mac != lease.mac);
+        leases.push(lease);
+    }
+
+    fn remove(&self, mac: [u8; 6]) {
+        self.0.borrow_mut().retain(|l| l.mac != mac);
+    }
+
+    fn expire(&self, now: i64) {
+        self.0.borrow_mut().retain(|l| l.expires > now);
+    }
+}
+
+/// A DHCP server replacing the one of libslirp, to assign fixed addresses
+/// to some guests and a pool of any size to the others.
+///
+/// It answers with the options of libslirp. The filters of the replies,
+/// such as [`Pxe`](super::Pxe), must be added before it, to see the
+/// requests it drops.
+pub struct Server {
+    // the addresses that are never assigned
+    host: Ipv4Addr,
+    dns: Ipv4Addr,
+    pool_start: u32,
+    pool_size: u32,
+    reservations: HashMap<[u8; 6], Ipv4Addr>,
+    // the options after the lease time, such as the router
+    options: Vec<(u8, Vec<u8>)>,
+    bootfile: Option<String>,
+    leases: Leases,
+    offers: HashMap<[u8; 6], (Ipv4Addr, i64)>,
+    // the addresses the guests declined, until they expire
+    declined: HashMap<Ipv4Addr, i64>,
+}
+
+impl Server {
+    /// The server of the IPv4 network of `opt`, with its pool and
+    /// reservations, or the error of its DNS suffixes.
+    pub fn from_opt(opt: &Opt) -> Result<Self, OptError> {
+        let ipv4 = &opt.ipv4;
+        let mut options = vec![(OPT_SUBNET_MASK, ipv4.mask().octets().to_vec())];
+        if !opt.restrict {
+            options.push((OPT_ROUTER, ipv4.host().octets().to_vec()));
+            options.push((OPT_DNS, ipv4.dns().octets().to_vec()));
+        }
+        if let Some(hostname) = &opt.hostname {
+            options.push((OPT_HOSTNAME, hostname.as_bytes().to_vec()));
+        }
+        if let Some(domain) = &opt.domainname {
+            options.push((OPT_DOMAIN_NAME, domain.as_bytes().to_vec()));
+        }
+        let domains = opt.dns_search()?;
+        if !domains.is_empty() {
+            options.push((OPT_DOMAIN_SEARCH, dnssearch::encode(&domains)));
+        }
+        if let Some(name) = &opt.tftp.name {
+            options.push((OPT_TFTP_SERVER, name.as_bytes().to_vec()));
+        }
+
+        Ok(Self {
+            host: ipv4.host(),
+            dns: ipv4.dns(),
+            pool_start: u32::from(ipv4.dhcp_start()),
+            pool_size: ipv4.dhcp_pool_size(),
+            reservations: ipv4
+                .dhcp_reservations
+                .iter()
+                .map(|r| (r.mac, r.addr))
+                .collect(),
+            options,
+            bootfile: opt.tftp.bootfile.clone(),
+            leases: Leases::default(),
+            offers: HashMap::new(),
+            declined: HashMap::new(),
+        })
+    }
+
+    /// Always assign `addr` to the guest of `mac`.
+    pub fn reserve(&mut self, mac: [u8; 6], addr: Ipv4Addr) {
+        self.reservations.insert(mac, addr);
+    }
+
+    /// The table of the leases, to query them once the server is added to
+    /// a `Context`.
+    pub fn leases(&self) -> Leases {
+        self.leases.clone()
+    }
+
+    fn in_pool(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr).wrapping_sub(self.pool_start) < self.pool_size
+    }
+
+    // whether `addr` can be assigned to `mac`, unless it's reserved
+    fn available(&self, addr: Ipv4Addr, mac: [u8; 6]) -> bool {
+        addr != self.host
+            && addr != self.dns
+            && self.in_pool(addr)
+            && !self.reservations.values().any(|&a| a == addr)
+            && !self.declined.contains_key(&addr)
+            && self.leases.by_addr(addr).map_or(true, |l| l.mac == mac)
+            && !self
+                .offers
+                .iter()
+                .any(|(m, (a, _))| *a == addr && *m != mac)
+    }
+
+    /// The address for the guest of `mac`: the reserved one, the one it
+    /// already has or was offered, the one it asks for or a free one.
+    fn choose(&self, mac: [u8; 6], requested: Option<Ipv4Addr>) -> Option<Ipv4Addr> {
+        if let Some(&addr) = self.reservations.get(&mac) {
+            return Some(addr);
+        }
+        if let Some(lease) = self.leases.by_mac(mac) {
+            return Some(lease.addr);
+        }
+        if let Some(&(addr, _)) = self.offers.get(&mac) {
+            return Some(addr);
+        }
+        if let Some(addr) = requested.filter(|&a| self.available(a, mac)) {
+            return Some(addr);
+        }
+        (0..self.pool_size)
+            .map(|i| Ipv4Addr::from(self.pool_start.wrapping_add(i)))
+            .find(|&a| self.available(a, mac))
+    }
+
+    // whether the guest of `mac` may have `addr`
+    fn allowed(&self, mac: [u8; 6], addr: Ipv4Addr) -> bool {
+        match self.reservations.get(&mac) {
+            Some(&reserved) => addr == reserved,
+            None => self.available(addr, mac),
+        }
+    }
+
+    fn response(&self, req: &Message, kind: u8, yiaddr: Ipv4Addr) -> Message {
+        let mut msg = req.response(yiaddr, self.host);
+        msg.set_option(OPT_MESSAGE_TYPE, &[kind]);
+        msg.set_option(OPT_SERVER_ID, &self.host.octets());
+        if kind == DHCPNAK {
+            return msg;
+        }
+        if kind != DHCPACK || !yiaddr.is_unspecified() {
+            msg.set_option(OPT_LEASE_TIME, &LEASE_TIME.to_be_bytes());
+        }
+        for (code, data) in &self.options {
+            msg.set_option(*code, data);
+        }
+        if let Some(file) = &self.bootfile {
+            msg.set_file(file);
+        }
+        msg
+    }
+
+    fn frame(&self, msg: &Message) -> Vec<u8> {
+        // the MAC address of the host in libslirp
+        let o = self.host.octets();
+        udp_frame(
+            [0x52, 0x55, o[0], o[1], o[2], o[3]],
+            [0xff; 6],
+            SocketAddr::new(self.host.into(), SERVER_PORT),
+            SocketAddr::new(Ipv4Addr::BROADCAST.into(), CLIENT_PORT),
+            &msg.to_bytes(),
+        )
+    }
+
+    fn handle(&mut self, req: &Message, now: i64) -> Option<Message> {
+        let mac = req.chaddr();
+        let requested = req
+            .option(OPT_REQUESTED_ADDR)
+            .filter(|data| data.len() == 4)
+            .map(|d| Ipv4Addr::new(d[0], d[1], d[2], d[3]));
+        let server = req
+            .option(OPT_SERVER_ID)
+            .filter(|data| data.len() == 4)
+            .map(|d| Ipv4Addr::new(d[0], d[1], d[2], d[3]));
+
+        match req.message_type()? {
+            DHCPDISCOVER => {
+                let addr = self.choose(mac, requested)?;
+                self.offers.insert(mac, (addr, now + OFFER_TIME));
+                Some(self.response(req, DHCPOFFER, addr))
+            }
+            // the guest chose another server
+            DHCPREQUEST if server.map_or(false, |s| s != self.host) => {
+                self.offers.remove(&mac);
+                None
+            }
+            DHCPREQUEST => {
+                let addr = requested.unwrap_or_else(|| req.ciaddr());
+                self.offers.remove(&mac);
+                if !self.allowed(mac, addr) {
+                    return Some(self.response(req, DHCPNAK, Ipv4Addr::UNSPECIFIED));
+                }
+                self.leases.insert(Lease {
+                    mac,
+                    addr,
+                    expires: now + i64::from(LEASE_TIME) * 1_000_000_000,
+                });
+                Some(self.response(req, DHCPACK, addr))
+            }
+            // the address is in use by another host (RFC 2131 4.3.3)
+            DHCPDECLINE => {
+                let assigned = match self.leases.by_mac(mac) {
+                    Some(lease) => Some(lease.addr),
+                    None => self.offers.get(&mac).map(|&(addr, _)| addr),
+                };
+                // the guest only declines its own address
+                let addr = requested.or(assigned).filter(|&a| Some(a) == assigned)?;
+                self.declined.insert(addr, now + DECLINE_TIME);
+                self.leases.remove(mac);
+                self.offers.remove(&mac);
+                None
+            }
+            DHCPRELEASE => {
+                self.leases.remove(mac);
+                None
+            }
+            // the options only, to a guest with an address
+            DHCPINFORM => Some(self.response(req, DHCPACK, Ipv4Addr::UNSPECIFIED)),
+            _ => None,
+        }
+    }
+}
+
+impl Filter for Server {
+    fn input(&mut self, frame: &[u8], now: i64, reply: &mut Vec<Vec<u8>>) -> Verdict {
+        let req = match request(frame) {
+            Some(req) => req,
+            None => return Verdict::Pass,
+        };
+        self.leases.expire(now);
+        self.offers.retain(|_, (_, expires)| *expires > now);
+        self.declined.retain(|_, expires| *expires > now);
+
+        if let Some(msg) = self.handle(&req, now) {
+            reply.push(self.frame(&msg));
+        }
+        Verdict::Drop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{message, reply, OP_REQUEST};
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+    const OTHER: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x57];
+
+    fn server(args: &[&str]) -> Server {
+        use structopt::StructOpt;
+        let mut opt = Opt::from_iter([&["slirp"], args].concat());
+        opt.hostname = Some("vm".into());
+        Server::from_opt(&opt).unwrap()
+    }
+
+    // the reply to a message of the guest
+    fn exchange(
+        server: &mut Server,
+        mac: [u8; 6],
+        options: &[(u8, &[u8])],
+        now: i64,
+    ) -> Option<Message> {
+        let frame = udp_frame(
+            mac,
+            [0xff; 6],
+            "0.0.0.0:68".parse().unwrap(),
+            "255.255.255.255:67".parse().unwrap(),
+            &message(OP_REQUEST, 7, mac, options),
+        );
+        let mut replies = Vec::new();
+        assert_eq!(server.input(&frame, now, &mut replies), Verdict::Drop);
+        assert!(replies.len() <= 1);
+        replies.pop().map(|frame| reply(&frame).unwrap())
+    }
+
+    fn discover(server: &mut Server, mac: [u8; 6]) -> Ipv4Addr {
+        let offer = exchange(server, mac, &[(OPT_MESSAGE_TYPE, &[DHCPDISCOVER])], 0).unwrap();
+        assert_eq!(offer.message_type(), Some(DHCPOFFER));
+        offer.yiaddr()
+    }
+
+    fn request(server: &mut Server, mac: [u8; 6], addr: Ipv4Addr) -> Message {
+        let options: &[(u8, &[u8])] = &[
+            (OPT_MESSAGE_TYPE, &[DHCPREQUEST]),
+            (OPT_REQUESTED_ADDR, &addr.octets()),
+            (OPT_SERVER_ID, &[10, 0, 2, 2]),
+        ];
+        exchange(server, mac, options, 1).unwrap()
+    }
+
+    #[test]
+    fn pool() {
+        let mut server = server(&["--dhcp-pool-size=2", "--tftp-bootfile=pxelinux.0"]);
+        let addr = discover(&mut server, MAC);
+        assert_eq!(addr, Ipv4Addr::new(10, 0, 2, 15));
+        // the same address, until the offer expires
+        assert_eq!(discover(&mut server, MAC), addr);
+        assert_eq!(discover(&mut server, OTHER), Ipv4Addr::new(10, 0, 2, 16));
+
+        let ack = request(&mut server, MAC, addr);
+        assert_eq!((ack.message_type(), ack.yiaddr()), (Some(DHCPACK), addr));
+        assert_eq!(ack.option(OPT_ROUTER), Some(&[10, 0, 2, 2][..]));
+        assert_eq!(ack.option(OPT_DNS), Some(&[10, 0, 2, 3][..]));
+        assert_eq!(ack.option(OPT_HOSTNAME), Some(&b"vm"[..]));
+        assert_eq!(ack.file(), "pxelinux.0");
+        let lease = server.leases().by_mac(MAC).unwrap();
+        assert_eq!(
+            (lease.addr, lease.expires),
+            (addr, 1 + 86400 * 1_000_000_000)
+        );
+
+        // the pool is exhausted
+        let third = [0x52, 0x54, 0, 0x12, 0x34, 0x58];
+        assert_eq!(
+            exchange(
+                &mut server,
+                third,
+                &[(OPT_MESSAGE_TYPE, &[DHCPDISCOVER])],
+                2
+            ),
+            None
+        );
+        // the address of another guest
+        let nak = request(&mut server, OTHER, addr);
+        assert_eq!(nak.message_type(), Some(DHCPNAK));
+
+        exchange(&mut server, MAC, &[(OPT_MESSAGE_TYPE, &[DHCPRELEASE])], 3);
+        assert!(server.leases().all().is_empty());
+        // once the offer to the other guest expired
+        let expired = OFFER_TIME + 3;
+        let offer = exchange(
+            &mut server,
+            third,
+            &[(OPT_MESSAGE_TYPE, &[DHCPDISCOVER])],
+            expired,
+        );
+        assert_eq!(offer.unwrap().yiaddr(), addr);
+    }
+
+    #[test]
+    fn decline() {
+        let mut server = server(&[]);
+        let addr = discover(&mut server, MAC);
+        request(&mut server, MAC, addr);
+        let options: &[(u8, &[u8])] = &[
+            (OPT_MESSAGE_TYPE, &[DHCPDECLINE]),
+            (OPT_REQUESTED_ADDR, &addr.octets()),
+        ];
+        assert_eq!(exchange(&mut server, MAC, options, 2), None);
+        assert!(server.leases().all().is_empty());
+
+        // another address, until the declined one expires
+        let other = discover(&mut server, MAC);
+        assert_ne!(other, addr);
+        let nak = request(&mut server, OTHER, addr);
+        assert_eq!(nak.message_type(), Some(DHCPNAK));
+        let discover: &[(u8, &[u8])] = &[(OPT_MESSAGE_TYPE, &[DHCPDISCOVER])];
+        let offer = exchange(&mut server, OTHER, discover, DECLINE_TIME + 2).unwrap();
+        assert_eq!(offer.yiaddr(), addr);
+    }
+
+    #[test]
+    fn reservations() {
+        let mut server = server(&[
+            "--dhcp-reserve=52:54:00:12:34:56=10.0.2.100",
+            "--dhcp-reserve=52:54:00:12:34:57=10.0.2.15",
+        ]);
+        assert_eq!(discover(&mut server, MAC), Ipv4Addr::new(10, 0, 2, 100));
+        // the reserved addresses aren't in the pool
+        let third = [0x52, 0x54, 0, 0x12, 0x34, 0x58];
+        assert_eq!(discover(&mut server, third), Ipv4Addr::new(10, 0, 2, 16));
+
+        let nak = request(&mut server, MAC, Ipv4Addr::new(10, 0, 2, 17));
+        assert_eq!(nak.message_type(), Some(DHCPNAK));
+        let ack = request(&mut server, MAC, Ipv4Addr::new(10, 0, 2, 100));
+        assert_eq!(ack.message_type(), Some(DHCPACK));
+
+        // another server, and INFORM
+        let options: &[(u8, &[u8])] = &[
+            (OPT_MESSAGE_TYPE, &[DHCPREQUEST]),
+            (OPT_REQUESTED_ADDR, &[10, 0, 2, 15]),
+            (OPT_SERVER_ID, &[10, 0, 2, 1]),
+        ];
+        assert_eq!(exchange(&mut server, OTHER, options, 1), None);
+        let ack = exchange(&mut server, OTHER, &[(OPT_MESSAGE_TYPE, &[DHCPINFORM])], 1).unwrap();
+        assert_eq!(ack.yiaddr(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(ack.option(OPT_LEASE_TIME), None);
+        assert_eq!(server.leases().all().len(), 1);
+    }
+}
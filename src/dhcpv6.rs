@@ -0,0 +1,632 @@
+//! A DHCPv6 server (RFC 8415) assigning addresses of the IPv6 prefix to
+//! the guests, in place of the information-only one of libslirp.
+
+use crate::dns::write_name;
+use crate::filter::{Filter, Verdict};
+use crate::packet::{udp_frame, Udp};
+use crate::{Opt, OptError};
+
+use std::collections::HashMap;
+use std::net::{Ipv6Addr, SocketAddr};
+
+pub const CLIENT_PORT: u16 = 546;
+pub const SERVER_PORT: u16 = 547;
+
+pub const SOLICIT: u8 = 1;
+pub const ADVERTISE: u8 = 2;
+pub const REQUEST: u8 = 3;
+pub const CONFIRM: u8 = 4;
+pub const RENEW: u8 = 5;
+pub const REBIND: u8 = 6;
+pub const REPLY: u8 = 7;
+pub const RELEASE: u8 = 8;
+pub const DECLINE: u8 = 9;
+pub const INFORMATION_REQUEST: u8 = 11;
+
+pub const OPT_CLIENTID: u16 = 1;
+pub const OPT_SERVERID: u16 = 2;
+pub const OPT_IA_NA: u16 = 3;
+pub const OPT_IAADDR: u16 = 5;
+pub const OPT_ORO: u16 = 6;
+pub const OPT_STATUS_CODE: u16 = 13;
+pub const OPT_RAPID_COMMIT: u16 = 14;
+pub const OPT_DNS_SERVERS: u16 = 23;
+pub const OPT_DOMAIN_LIST: u16 = 24;
+pub const OPT_BOOTFILE_URL: u16 = 59;
+
+pub const STATUS_SUCCESS: u16 = 0;
+pub const STATUS_NO_ADDRS_AVAIL: u16 = 2;
+pub const STATUS_NOT_ON_LINK: u16 = 4;
+
+// in seconds, as the DHCP server
+pub const LIFETIME: u32 = 24 * 3600;
+// the renewal and rebinding times, at 0.5 and 0.8 of the lifetime
+const T1: u32 = LIFETIME / 2;
+const T2: u32 = LIFETIME / 5 * 4;
+// an advertised address is kept for the client for a minute
+const ADVERTISE_TIME: i64 = 60 * 1_000_000_000;
+// a declined address is in use on the link, and left out for an hour
+const DECLINE_TIME: i64 = 3600 * 1_000_000_000;
+
+// the pool of addresses, from ::10 to ::ff of the prefix
+const POOL_START: u128 = 0x10;
+const POOL_SIZE: u128 = 0xf0;
+
+// the link-local address of the host in libslirp
+const HOST_LINK_LOCAL: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2);
+
+/// A DHCPv6 message, with its options in order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub kind: u8,
+    pub xid: [u8; 3],
+    pub options: Vec<(u16, Vec<u8>)>,
+}
+
+fn be16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+fn be32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn parse_options(mut data: &[u8]) -> Option<Vec<(u16, Vec<u8>)>> {
+    let mut options = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return None;
+        }
+        let len = usize::from(be16(&data[2..]));
+        let value = data.get(4..4 + len)?;
+        options.push((be16(data), value.to_vec()));
+        data = &data[4 + len..];
+    }
+    Some(options)
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, data: &[u8]) {
+    buf.extend_from_slice(&code.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn status(code: u16, message: &str) -> Vec<u8> {
+    let mut data = code.to_be_bytes().to_vec();
+    data.extend_from_slice(message.as_bytes());
+    data
+}
+
+impl Message {
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        if msg.len() < 4 {
+            return None;
+        }
+        Some(Self {
+            kind: msg[0],
+            xid: [msg[1], msg[2], msg[3]],
+            options: parse_options(&msg[4..])?,
+        })
+    }
+
+    /// The data of the first option `code`.
+    pub fn option(&self, code: u16) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, data)| &data[..])
+    }
+
+    /// Whether the client asks for the option `code` (RFC 8415, 21.7).
+    pub fn requests(&self, code: u16) -> bool {
+        self.option(OPT_ORO).map_or(false, |oro| {
+            oro.chunks(2).any(|c| c.len() == 2 && be16(c) == code)
+        })
+    }
+
+    /// The identity associations for non-temporary addresses: their IAID
+    /// and the addresses of the client.
+    pub fn ia_na(&self) -> Vec<(u32, Vec<Ipv6Addr>)> {
+        self.options
+            .iter()
+            .filter(|(code, data)| *code == OPT_IA_NA && data.len() >= 12)
+            .map(|(_, data)| {
+                let addrs = parse_options(&data[12..])
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|(code, data)| *code == OPT_IAADDR && data.len() >= 24)
+                    .map(|(_, data)| {
+                        let mut addr = [0; 16];
+                        addr.copy_from_slice(&data[..16]);
+                        Ipv6Addr::from(addr)
+                    })
+                    .collect();
+                (be32(data), addrs)
+            })
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut msg = vec![self.kind];
+        msg.extend_from_slice(&self.xid);
+        for (code, data) in &self.options {
+            push_option(&mut msg, *code, data);
+        }
+        msg
+    }
+}
+
+/// An address assigned to an identity association of a guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    /// The DUID of the client.
+    pub duid: Vec<u8>,
+    pub iaid: u32,
+    pub addr: Ipv6Addr,
+    /// The time it expires, from `Handler::clock_get_ns`.
+    pub expires: i64,
+}
+
+/// A DHCPv6 server assigning addresses of the prefix, from `::10` to
+/// `::ff`, with the DNS server, search domains and boot file URL.
+///
+/// It answers all the DHCPv6 messages of the guests, which libslirp then
+/// doesn't see.
+pub struct Server {
+    duid: Vec<u8>,
+    prefix: u128,
+    mask: u128,
+    // the addresses that are never assigned
+    host: Ipv6Addr,
+    dns: Ipv6Addr,
+    options: Vec<(u16, Vec<u8>)>,
+    bootfile_url: Option<String>,
+    bindings: Vec<Binding>,
+    // the addresses the guests declined, until they expire
+    declined: HashMap<Ipv6Addr, i64>,
+}
+
+impl Server {
+    /// The server of the IPv6 prefix of `opt`.
+    ///
+    /// The boot file URL is `--dhcpv6-bootfile-url`, or the TFTP URL of
+    /// the UEFI boot file, or else of `--tftp-bootfile`.
+    pub fn from_opt(opt: &Opt) -> Result<Self, OptError> {
+        let ipv6 = &opt.ipv6;
+        let mut options = Vec::new();
+        if !opt.restrict {
+            options.push((OPT_DNS_SERVERS, ipv6.dns().octets().to_vec()));
+        }
+        let domains = opt.dns_search()?;
+        if !domains.is_empty() {
+            let mut names = Vec::new();
+            for domain in &domains {
+                write_name(&mut names, domain);
+            }
+            options.push((OPT_DOMAIN_LIST, names));
+        }
+        let bootfile = opt
+            .tftp
+            .bootfile_uefi
+            .as_ref()
+            .or(opt.tftp.bootfile.as_ref());
+        let bootfile_url = ipv6
+            .dhcpv6_bootfile_url
+            .clone()
+            .or_else(|| bootfile.map(|file| format!("tftp://[{}]/{}", ipv6.host(), file)));
+        // DUID-LL (type 3) of the ethernet (type 1) address of the host
+        let mut duid = vec![0, 3, 0, 1];
+        duid.extend_from_slice(&host_mac());
+
+        Ok(Self {
+            duid,
+            prefix: u128::from(ipv6.prefix()),
+            mask: ipv6.mask(),
+            host: ipv6.host(),
+            dns: ipv6.dns(),
+            options,
+            bootfile_url,
+            bindings: Vec::new(),
+            declined: HashMap::new(),
+        })
+    }
+
+    /// The addresses assigned to the guests, and the ones they were
+    /// advertised.
+    pub fn bindings(&self) -> &[Binding] {
+        &self.bindings
+    }
+
+    fn on_link(&self, addr: Ipv6Addr) -> bool {
+        u128::from(addr) & self.mask == self.prefix
+    }
+
+    fn available(&self, addr: Ipv6Addr) -> bool {
+        self.on_link(addr)
+            && addr != self.host
+            && addr != self.dns
+            && !self.bindings.iter().any(|b| b.addr == addr)
+            && !self.declined.contains_key(&addr)
+    }
+
+    /// The address of an identity association: the one it already has or
+    /// a free one.
+    fn choose(&self, duid: &[u8], iaid: u32) -> Option<Ipv6Addr> {
+        if let Some(b) = self
+            .bindings
+            .iter()
+            .find(|b| b.duid == duid && b.iaid == iaid)
+        {
+            return Some(b.addr);
+        }
+        (POOL_START..POOL_START + POOL_SIZE)
+            .map(|i| Ipv6Addr::from(self.prefix.wrapping_add(i)))
+            .find(|&a| self.available(a))
+    }
+
+    /// The IA_NA option of an identity association, with its address
+    /// bound until `expires`.
+    fn assign(&mut self, duid: &[u8], iaid: u32, expires: i64) -> Vec<u8> {
+        let mut data = iaid.to_be_bytes().to_vec();
+        match self.choose(duid, iaid) {
+            Some(addr) => {
+                self.bindings
+                    .retain(|b| !(b.duid == duid && b.iaid == iaid));
+                self.bindings.push(Binding {
+                    duid: duid.to_vec(),
+                    iaid,
+                    addr,
+                    expires,
+                });
+                data.extend_from_slice(&T1.to_be_bytes());
+                data.extend_from_slice(&T2.to_be_bytes());
+                let mut iaaddr = addr.octets().to_vec();
+                iaaddr.extend_from_slice(&LIFETIME.to_be_bytes());
+                iaaddr.extend_from_slice(&LIFETIME.to_be_bytes());
+                push_option(&mut data, OPT_IAADDR, &iaaddr);
+            }
+            None => {
+                data.extend_from_slice(&[0; 8]);
+                let status = status(STATUS_NO_ADDRS_AVAIL, "no addresses available");
+                push_option(&mut data, OPT_STATUS_CODE, &status);
+            }
+        }
+        data
+    }
+
+    fn response(&self, req: &Message, kind: u8, duid: &[u8]) -> Message {
+        let mut options = vec![
+            (OPT_CLIENTID, duid.to_vec()),
+            (OPT_SERVERID, self.duid.clone()),
+        ];
+        options.extend(self.options.iter().cloned());
+        if let Some(url) = &self.bootfile_url {
+            if req.requests(OPT_BOOTFILE_URL) {
+                options.push((OPT_BOOTFILE_URL, url.as_bytes().to_vec()));
+            }
+        }
+        Message {
+            kind,
+            xid: req.xid,
+            options,
+        }
+    }
+
+    fn handle(&mut self, req: &Message, now: i64) -> Option<Message> {
+        let duid = req.option(OPT_CLIENTID)?.to_vec();
+        let server = req.option(OPT_SERVERID);
+        if server.map_or(false, |s| s != &self.duid[..]) {
+            return None;
+        }
+        let lease_end = now + i64::from(LIFETIME) * 1_000_000_000;
+
+        match req.kind {
+            // the messages to any server
+            SOLICIT | CONFIRM | REBIND if server.is_some() => None,
+            // the messages to this server
+            REQUEST | RENEW | RELEASE | DECLINE if server.is_none() => None,
+            SOLICIT => {
+                let rapid = req.option(OPT_RAPID_COMMIT).is_some();
+                let (kind, expires) = if rapid {
+                    (REPLY, lease_end)
+                } else {
+                    (ADVERTISE, now + ADVERTISE_TIME)
+                };
+                let mut msg = self.response(req, kind, &duid);
+                if rapid {
+                    msg.options.push((OPT_RAPID_COMMIT, Vec::new()));
+                }
+                for (iaid, _) in req.ia_na() {
+                    msg.options
+                        .push((OPT_IA_NA, self.assign(&duid, iaid, expires)));
+                }
+                Some(msg)
+            }
+            REQUEST | RENEW | REBIND => {
+                let mut msg = self.response(req, REPLY, &duid);
+                for (iaid, _) in req.ia_na() {
+                    msg.options
+                        .push((OPT_IA_NA, self.assign(&duid, iaid, lease_end)));
+                }
+                Some(msg)
+            }
+            CONFIRM => {
+                let on_link = req
+                    .ia_na()
+                    .iter()
+                    .flat_map(|(_, addrs)| addrs)
+                    .all(|&addr| self.on_link(addr));
+                let mut msg = self.response(req, REPLY, &duid);
+                let status = if on_link {
+                    status(STATUS_SUCCESS, "")
+                } else {
+                    status(STATUS_NOT_ON_LINK, "not on link")
+                };
+                msg.options.push((OPT_STATUS_CODE, status));
+                Some(msg)
+            }
+            RELEASE => {
+                for (iaid, _) in req.ia_na() {
+                    self.bindings
+                        .retain(|b| !(b.duid == duid && b.iaid == iaid));
+                }
+                let mut msg = self.response(req, REPLY, &duid);
+                msg.options
+                    .push((OPT_STATUS_CODE, status(STATUS_SUCCESS, "")));
+                Some(msg)
+            }
+            // the addresses are in use by another host (RFC 8415 18.4.7)
+            DECLINE => {
+                for (iaid, addrs) in req.ia_na() {
+                    let declined = &mut self.declined;
+                    // the client only declines its own addresses
+                    self.bindings.retain(|b| {
+                        let own = b.duid == duid && b.iaid == iaid && addrs.contains(&b.addr);
+                        if own {
+                            declined.insert(b.addr, now + DECLINE_TIME);
+                        }
+                        !own
+                    });
+                }
+                let mut msg = self.response(req, REPLY, &duid);
+                msg.options
+                    .push((OPT_STATUS_CODE, status(STATUS_SUCCESS, "")));
+                Some(msg)
+            }
+            INFORMATION_REQUEST => Some(self.response(req, REPLY, &duid)),
+            _ => None,
+        }
+    }
+}
+
+/// The MAC address of the link-local address of the host in libslirp.
+fn host_mac() -> [u8; 6] {
+    let o = HOST_LINK_LOCAL.octets();
+    [0x52, 0x56, o[12], o[13], o[14], o[15]]
+}
+
+impl Filter for Server {
+    fn input(&mut self, frame: &[u8], now: i64, reply: &mut Vec<Vec<u8>>) -> Verdict {
+        let udp = match Udp::parse(frame) {
+            Some(udp) if udp.dst.is_ipv6() && udp.dst.port() == SERVER_PORT => udp,
+            _ => return Verdict::Pass,
+        };
+        let req = match Message::parse(udp.payload) {
+            Some(req) => req,
+            None => return Verdict::Pass,
+        };
+        self.bindings.retain(|b| b.expires > now);
+        self.declined.retain(|_, expires| *expires > now);
+
+        if let Some(msg) = self.handle(&req, now) {
+            reply.push(udp_frame(
+                host_mac(),
+                udp.src_mac,
+                SocketAddr::new(HOST_LINK_LOCAL.into(), SERVER_PORT),
+                udp.src,
+                &msg.to_bytes(),
+            ));
+        }
+        Verdict::Drop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::read_name;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+    const CLIENT: &[u8] = &[0, 3, 0, 1, 0x52, 0x54, 0, 0x12, 0x34, 0x56];
+    const OTHER: &[u8] = &[0, 3, 0, 1, 0x52, 0x54, 0, 0x12, 0x34, 0x57];
+
+    fn start(args: &[&str]) -> Server {
+        use structopt::StructOpt;
+        let opt = Opt::from_iter([&["slirp", "--dhcpv6"], args].concat());
+        Server::from_opt(&opt).unwrap()
+    }
+
+    fn ia_na(iaid: u32) -> Vec<u8> {
+        let mut data = iaid.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 8]);
+        data
+    }
+
+    // the reply to a message of the guest
+    fn exchange(
+        server: &mut Server,
+        kind: u8,
+        options: Vec<(u16, Vec<u8>)>,
+        now: i64,
+    ) -> Option<Message> {
+        let msg = Message {
+            kind,
+            xid: [1, 2, 3],
+            options,
+        };
+        let frame = udp_frame(
+            MAC,
+            [0x33, 0x33, 0, 1, 0, 2],
+            "[fe80::5054:ff:fe12:3456]:546".parse().unwrap(),
+            "[ff02::1:2]:547".parse().unwrap(),
+            &msg.to_bytes(),
+        );
+        let mut replies = Vec::new();
+        assert_eq!(server.input(&frame, now, &mut replies), Verdict::Drop);
+        assert!(replies.len() <= 1);
+        replies.pop().map(|frame| {
+            let udp = Udp::parse(&frame).unwrap();
+            assert_eq!(udp.dst_mac, MAC);
+            assert_eq!(udp.src, "[fe80::2]:547".parse().unwrap());
+            assert_eq!(udp.dst.port(), CLIENT_PORT);
+            let reply = Message::parse(udp.payload).unwrap();
+            assert_eq!(reply.xid, [1, 2, 3]);
+            reply
+        })
+    }
+
+    // the address of the first IA_NA of a reply
+    fn addr(msg: &Message) -> Option<Ipv6Addr> {
+        msg.ia_na()
+            .first()
+            .and_then(|(_, addrs)| addrs.first().cloned())
+    }
+
+    #[test]
+    fn assign() {
+        let mut server = start(&[]);
+        let solicit = vec![(OPT_CLIENTID, CLIENT.to_vec()), (OPT_IA_NA, ia_na(1))];
+        let advertise = exchange(&mut server, SOLICIT, solicit.clone(), 0).unwrap();
+        assert_eq!(advertise.kind, ADVERTISE);
+        assert_eq!(advertise.option(OPT_CLIENTID), Some(CLIENT));
+        assert_eq!(
+            advertise.option(OPT_DNS_SERVERS),
+            Some(&"fec0::3".parse::<Ipv6Addr>().unwrap().octets()[..])
+        );
+        let fec0_10 = "fec0::10".parse().unwrap();
+        assert_eq!(addr(&advertise), Some(fec0_10));
+        let server_id = advertise.option(OPT_SERVERID).unwrap().to_vec();
+
+        // to another server
+        let mut request = solicit.clone();
+        request.push((OPT_SERVERID, OTHER.to_vec()));
+        assert_eq!(exchange(&mut server, REQUEST, request, 1), None);
+        let mut request = solicit.clone();
+        request.push((OPT_SERVERID, server_id.clone()));
+        let reply = exchange(&mut server, REQUEST, request.clone(), 1).unwrap();
+        assert_eq!(reply.kind, REPLY);
+        assert_eq!(addr(&reply), Some(fec0_10));
+        assert_eq!(server.bindings().len(), 1);
+        // renewed, after the advertisement time
+        let reply = exchange(&mut server, RENEW, request.clone(), ADVERTISE_TIME * 2).unwrap();
+        assert_eq!(addr(&reply), Some(fec0_10));
+
+        // another client, with rapid commit
+        let solicit = vec![
+            (OPT_CLIENTID, OTHER.to_vec()),
+            (OPT_IA_NA, ia_na(1)),
+            (OPT_RAPID_COMMIT, Vec::new()),
+        ];
+        let reply = exchange(&mut server, SOLICIT, solicit, 2).unwrap();
+        assert_eq!(reply.kind, REPLY);
+        assert!(reply.option(OPT_RAPID_COMMIT).is_some());
+        assert_eq!(addr(&reply), Some("fec0::11".parse().unwrap()));
+
+        let reply = exchange(&mut server, RELEASE, request, 3).unwrap();
+        assert_eq!(reply.option(OPT_STATUS_CODE), Some(&[0, 0][..]));
+        assert_eq!(server.bindings().len(), 1);
+
+        let mut iaaddr = "fd00::10".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        iaaddr.extend_from_slice(&[0; 8]);
+        let mut ia = ia_na(1);
+        push_option(&mut ia, OPT_IAADDR, &iaaddr);
+        let confirm = vec![(OPT_CLIENTID, CLIENT.to_vec()), (OPT_IA_NA, ia)];
+        let reply = exchange(&mut server, CONFIRM, confirm, 4).unwrap();
+        assert_eq!(
+            &reply.option(OPT_STATUS_CODE).unwrap()[..2],
+            &STATUS_NOT_ON_LINK.to_be_bytes()
+        );
+
+        // without a client ID
+        assert_eq!(
+            exchange(&mut server, SOLICIT, vec![(OPT_IA_NA, ia_na(1))], 5),
+            None
+        );
+    }
+
+    #[test]
+    fn decline() {
+        let mut server = start(&[]);
+        let solicit = vec![
+            (OPT_CLIENTID, CLIENT.to_vec()),
+            (OPT_IA_NA, ia_na(1)),
+            (OPT_RAPID_COMMIT, Vec::new()),
+        ];
+        let reply = exchange(&mut server, SOLICIT, solicit.clone(), 0).unwrap();
+        let fec0_10 = addr(&reply).unwrap();
+        let server_id = reply.option(OPT_SERVERID).unwrap().to_vec();
+
+        let mut iaaddr = fec0_10.octets().to_vec();
+        iaaddr.extend_from_slice(&[0; 8]);
+        let mut ia = ia_na(1);
+        push_option(&mut ia, OPT_IAADDR, &iaaddr);
+        let decline = vec![
+            (OPT_CLIENTID, CLIENT.to_vec()),
+            (OPT_SERVERID, server_id),
+            (OPT_IA_NA, ia),
+        ];
+        let reply = exchange(&mut server, DECLINE, decline, 1).unwrap();
+        assert_eq!(reply.option(OPT_STATUS_CODE), Some(&[0, 0][..]));
+        assert!(server.bindings().is_empty());
+
+        // another address, until the declined one expires
+        let reply = exchange(&mut server, SOLICIT, solicit, 2).unwrap();
+        assert_eq!(addr(&reply), Some("fec0::11".parse().unwrap()));
+        let solicit = vec![(OPT_CLIENTID, OTHER.to_vec()), (OPT_IA_NA, ia_na(1))];
+        let reply = exchange(&mut server, SOLICIT, solicit, DECLINE_TIME + 1).unwrap();
+        assert_eq!(addr(&reply), Some(fec0_10));
+    }
+
+    #[test]
+    fn information() {
+        let mut server = start(&[
+            "--dns-suffixes=example.com",
+            "--tftp-bootfile=pxelinux.0",
+            "--tftp-bootfile-uefi=efi/bootx64.efi",
+        ]);
+        let request = vec![
+            (OPT_CLIENTID, CLIENT.to_vec()),
+            (OPT_ORO, vec![0, 23, 0, 24, 0, 59]),
+        ];
+        let reply = exchange(&mut server, INFORMATION_REQUEST, request, 0).unwrap();
+        assert_eq!(reply.kind, REPLY);
+        assert_eq!(reply.ia_na(), vec![]);
+        assert_eq!(
+            read_name(reply.option(OPT_DOMAIN_LIST).unwrap(), 0),
+            Some(("example.com".to_string(), 13))
+        );
+        assert_eq!(
+            reply.option(OPT_BOOTFILE_URL),
+            Some(&b"tftp://[fec0::2]/efi/bootx64.efi"[..])
+        );
+
+        // not requested
+        let request = vec![(OPT_CLIENTID, CLIENT.to_vec())];
+        let reply = exchange(&mut server, INFORMATION_REQUEST, request, 0).unwrap();
+        assert_eq!(reply.option(OPT_BOOTFILE_URL), None);
+
+        let mut server = start(&["--dhcpv6-bootfile-url=http://[fec0::2]/boot.efi"]);
+        assert_eq!(
+            server.bootfile_url.as_ref().map(String::as_str),
+            Some("http://[fec0::2]/boot.efi")
+        );
+        let frame = udp_frame(
+            MAC,
+            [0xff; 6],
+            "0.0.0.0:68".parse().unwrap(),
+            "255.255.255.255:67".parse().unwrap(),
+            &[1; 300],
+        );
+        assert_eq!(server.input(&frame, 0, &mut Vec::new()), Verdict::Pass);
+    }
+}
@@ -0,0 +1,238 @@
+use super::{Query, Record, CLASS_IN, PORT, RCODE_NOERROR, TYPE_PTR};
+use crate::dnssearch;
+use crate::filter::{Filter, Verdict};
+use crate::packet::Udp;
+use crate::Opt;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+// the answers aren't cached, the table may differ from the host's
+const TTL: u32 = 0;
+
+/// A static hosts table, answering the guest DNS queries for its names and
+/// addresses. The other queries go to the virtual nameserver of libslirp.
+#[derive(Debug, Default)]
+pub struct Hosts {
+    nameservers: Vec<IpAddr>,
+    names: HashMap<String, Vec<IpAddr>>,
+    addrs: HashMap<IpAddr, String>,
+}
+
+impl Hosts {
+    /// An empty table, answering the queries sent to `nameservers`.
+    pub fn new(nameservers: &[IpAddr]) -> Self {
+        Self {
+            nameservers: nameservers.to_vec(),
+            ..Self::default()
+        }
+    }
+
+    /// The table of the `--dns-host` entries and the `--hosts-file`.
+    pub fn from_opt(opt: &Opt) -> io::Result<Self> {
+        let mut hosts = Self::new(&super::nameservers(opt));
+        for host in &opt.dns_hosts {
+            hosts.insert(&host.name, host.addr);
+        }
+        if let Some(path) = &opt.hosts_file {
+            hosts.load(path)?;
+        }
+        Ok(hosts)
+    }
+
+    /// Add an address of `name`. The first name of an address is the one
+    /// of its PTR record.
+    pub fn insert(&mut self, name: &str, addr: IpAddr) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let addrs = self.names.entry(name.clone()).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+        self.addrs.entry(addr).or_insert(name);
+    }
+
+    /// Add the entries of a hosts file, in the /etc/hosts format.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        self.parse(&s);
+        Ok(())
+    }
+
+    /// Add the entries of the hosts file `s`. Invalid entries are ignored,
+    /// as the resolver of the C library does.
+    pub fn parse(&mut self, s: &str) {
+        for line in s.lines() {
+            let line = line.split('#').next().unwrap();
+            let mut fields = line.split_whitespace();
+            let addr = match fields.next().and_then(|a| a.parse().ok()) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            for name in fields.filter_map(|n| dnssearch::to_ascii(n).ok()) {
+                self.insert(&name, addr);
+            }
+        }
+    }
+
+    /// The addresses of `name`, if it is in the table.
+    pub fn lookup(&self, name: &str) -> Option<&[IpAddr]> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.names.get(&name).map(Vec::as_slice)
+    }
+
+    /// The answers to a query, if the table has its name.
+    fn answer(&self, query: &Query) -> Option<Vec<Record>> {
+        if query.qclass != CLASS_IN {
+            return None;
+        }
+
+        if query.qtype == TYPE_PTR {
+            if let Some(addr) = super::reverse_addr(&query.name) {
+                let name = self.addrs.get(&addr)?;
+                return Some(vec![Record::ptr(name, TTL)]);
+            }
+        }
+
+        // the name exists: other types have no data
+        let addrs = self.lookup(&query.name)?;
+        Some(
+            addrs
+                .iter()
+                .map(|&addr| Record::addr(addr, TTL))
+                .filter(|r| r.rtype == query.qtype)
+                .collect(),
+        )
+    }
+}
+
+impl Filter for Hosts {
+    fn input(&mut self, frame: &[u8], _now: i64, reply: &mut Vec<Vec<u8>>) -> Verdict {
+        let udp = match Udp::parse(frame) {
+            Some(udp) if udp.dst.port() == PORT && self.nameservers.contains(&udp.dst.ip()) => udp,
+            _ => return Verdict::Pass,
+        };
+        let query = match Query::parse(udp.payload) {
+            Some(query) => query,
+            None => return Verdict::Pass,
+        };
+
+        match self.answer(&query) {
+            Some(answers) => {
+                let msg = query.response(udp.payload, RCODE_NOERROR, &answers);
+                reply.push(udp.reply(&msg));
+                Verdict::Drop
+            }
+            None => Verdict::Pass,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{query, TYPE_A, TYPE_AAAA};
+    use super::*;
+    use crate::packet::udp_frame;
+    use std::net::SocketAddr;
+
+    const HOSTS: &str = "
+# comment
+192.168.1.10  mirror.internal mirror  # trailing comment
+fd00::10      mirror.internal
+192.168.1.11  Other.Internal.
+not-an-address ignored
+192.168.1.12  under_score
+";
+
+    fn hosts() -> Hosts {
+        let mut hosts = Hosts::new(&["10.0.2.3".parse().unwrap()]);
+        hosts.parse(HOSTS);
+        hosts
+    }
+
+    fn ask(hosts: &mut Hosts, dst: &str, name: &str, qtype: u16) -> Option<Vec<u8>> {
+        let src: SocketAddr = "10.0.2.15:5353".parse().unwrap();
+        let dst: SocketAddr = dst.parse().unwrap();
+        let frame = udp_frame([2; 6], [4; 6], src, dst, &query(7, name, qtype));
+
+        let mut reply = Vec::new();
+        match hosts.input(&frame, 0, &mut reply) {
+            Verdict::Pass => {
+                assert!(reply.is_empty());
+                None
+            }
+            Verdict::Drop => {
+                assert_eq!(reply.len(), 1);
+                let udp = Udp::parse(&reply[0]).unwrap();
+                assert_eq!((udp.src, udp.dst), (dst, src));
+                Some(udp.payload.to_vec())
+            }
+        }
+    }
+
+    // the rdata of the answers, which are all at the end
+    fn answers(msg: &[u8], len: usize) -> Vec<&[u8]> {
+        let count = usize::from(u16::from_be_bytes([msg[6], msg[7]]));
+        (0..count)
+            .map(|i| &msg[msg.len() - (count - i) * (12 + len) + 12..][..len])
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        let hosts = hosts();
+        let mirror: Vec<IpAddr> =
+            vec!["192.168.1.10".parse().unwrap(), "fd00::10".parse().unwrap()];
+        assert_eq!(hosts.lookup("mirror.internal"), Some(&mirror[..]));
+        assert_eq!(hosts.lookup("MIRROR.internal."), Some(&mirror[..]));
+        assert_eq!(hosts.lookup("mirror"), Some(&mirror[..1]));
+        assert!(hosts.lookup("other.internal").is_some());
+        assert!(hosts.lookup("ignored").is_none());
+        assert!(hosts.lookup("under_score").is_none());
+    }
+
+    #[test]
+    fn filter() {
+        let mut hosts = hosts();
+
+        let msg = ask(&mut hosts, "10.0.2.3:53", "Mirror.Internal", TYPE_A).unwrap();
+        assert_eq!(&msg[..2], &[0, 7]);
+        assert_eq!(answers(&msg, 4), vec![&[192, 168, 1, 10]]);
+
+        let msg = ask(&mut hosts, "10.0.2.3:53", "mirror.internal", TYPE_AAAA).unwrap();
+        let addr: std::net::Ipv6Addr = "fd00::10".parse().unwrap();
+        assert_eq!(answers(&msg, 16), vec![&addr.octets()]);
+
+        // no data, but no forwarding either
+        let msg = ask(&mut hosts, "10.0.2.3:53", "other.internal", TYPE_AAAA).unwrap();
+        assert!(answers(&msg, 0).is_empty());
+
+        let msg = ask(
+            &mut hosts,
+            "10.0.2.3:53",
+            "10.1.168.192.in-addr.arpa",
+            TYPE_PTR,
+        )
+        .unwrap();
+        let mut name = Vec::new();
+        super::super::write_name(&mut name, "mirror.internal");
+        assert_eq!(answers(&msg, name.len()), vec![&name[..]]);
+
+        // forwarded
+        assert!(ask(&mut hosts, "10.0.2.3:53", "example.com", TYPE_A).is_none());
+        assert!(ask(
+            &mut hosts,
+            "10.0.2.3:53",
+            "1.1.168.192.in-addr.arpa",
+            TYPE_PTR
+        )
+        .is_none());
+        // not for the virtual nameserver
+        assert!(ask(&mut hosts, "10.0.2.2:53", "mirror.internal", TYPE_A).is_none());
+        assert!(ask(&mut hosts, "10.0.2.3:5353", "mirror.internal", TYPE_A).is_none());
+    }
+}
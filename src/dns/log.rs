@@ -0,0 +1,333 @@
+use super::{rcode_name, read_name, type_name, Query, Response, PORT, TYPE_A, TYPE_AAAA};
+use crate::filter::{Filter, Verdict};
+use crate::packet::Udp;
+
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// CNAME, NS and PTR
+const NAME_TYPES: &[u16] = &[5, 2, 12];
+
+/// An answer of a DNS response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    pub name: String,
+    pub rtype: u16,
+    pub ttl: u32,
+    /// The address or name of the record, or its data in hexadecimal for
+    /// the other types.
+    pub data: String,
+}
+
+/// A DNS query of the guest, or a response to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// The time of the frame, from `Handler::clock_get_ns`.
+    pub timestamp: i64,
+    pub guest: SocketAddr,
+    pub server: SocketAddr,
+    pub id: u16,
+    pub qname: String,
+    pub qtype: u16,
+    /// The response code, `None` for a query.
+    pub rcode: Option<u8>,
+    pub answers: Vec<Answer>,
+}
+
+impl Event {
+    /// The event of a DNS message in a frame.
+    pub fn parse(frame: &[u8], timestamp: i64) -> Option<Self> {
+        let udp = Udp::parse(frame)?;
+        if udp.dst.port() == PORT {
+            if let Some(query) = Query::parse(udp.payload) {
+                return Some(Self {
+                    timestamp,
+                    guest: udp.src,
+                    server: udp.dst,
+                    id: query.id,
+                    qname: query.name,
+                    qtype: query.qtype,
+                    rcode: None,
+                    answers: Vec::new(),
+                });
+            }
+        }
+        if udp.src.port() != PORT {
+            return None;
+        }
+
+        let msg = udp.payload;
+        let resp = Response::parse(msg)?;
+        let answers = resp
+            .answers
+            .iter()
+            .map(|r| {
+                let data = match r.rtype {
+                    TYPE_A if r.data.len() == 4 => {
+                        let mut octets = [0; 4];
+                        octets.copy_from_slice(&r.data);
+                        Ipv4Addr::from(octets).to_string()
+                    }
+                    TYPE_AAAA if r.data.len() == 16 => {
+                        let mut octets = [0; 16];
+                        octets.copy_from_slice(&r.data);
+                        Ipv6Addr::from(octets).to_string()
+                    }
+                    // the name may point to the rest of the message
+                    t if NAME_TYPES.contains(&t) => read_name(msg, r.ttl_pos + 6)
+                        .map(|(name, _)| name)
+                        .unwrap_or_default(),
+                    _ => r.data.iter().map(|b| format!("{:02x}", b)).collect(),
+                };
+                Answer {
+                    name: r.name.clone(),
+                    rtype: r.rtype,
+                    ttl: r.ttl,
+                    data,
+                }
+            })
+            .collect();
+
+        Some(Self {
+            timestamp,
+            guest: udp.dst,
+            server: udp.src,
+            id: resp.id,
+            rcode: Some(resp.rcode()),
+            qname: resp.name,
+            qtype: resp.qtype,
+            answers,
+        })
+    }
+}
+
+/// A destination of the DNS events.
+pub trait Sink {
+    fn event(&mut self, event: &Event);
+}
+
+impl<F: FnMut(&Event)> Sink for F {
+    fn event(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+fn json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// The JSON object of an event, with the wall clock `time` in seconds.
+pub fn to_json(event: &Event, time: f64) -> String {
+    let mut out = String::new();
+    write!(out, "{{\"ts\":{},\"time\":{:.3}", event.timestamp, time).unwrap();
+    out.push_str(",\"guest\":");
+    json_str(&mut out, &event.guest.ip().to_string());
+    out.push_str(",\"server\":");
+    json_str(&mut out, &event.server.ip().to_string());
+    let kind = match event.rcode {
+        Some(_) => "response",
+        None => "query",
+    };
+    write!(out, ",\"type\":\"{}\",\"id\":{},\"qname\":", kind, event.id).unwrap();
+    json_str(&mut out, &event.qname);
+    out.push_str(",\"qtype\":");
+    json_str(&mut out, &type_name(event.qtype));
+
+    if let Some(rcode) = event.rcode {
+        out.push_str(",\"rcode\":");
+        json_str(&mut out, &rcode_name(rcode));
+        out.push_str(",\"answers\":[");
+        for (i, answer) in event.answers.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"name\":");
+            json_str(&mut out, &answer.name);
+            out.push_str(",\"type\":");
+            json_str(&mut out, &type_name(answer.rtype));
+            write!(out, ",\"ttl\":{},\"data\":", answer.ttl).unwrap();
+            json_str(&mut out, &answer.data);
+            out.push('}');
+        }
+        out.push(']');
+    }
+    out.push('}');
+    out
+}
+
+/// A sink writing an event per line, in JSON.
+pub struct JsonLines<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl JsonLines<File> {
+    /// Append the events to the file at `path`.
+    pub fn append<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(Self::new)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+}
+
+impl<W: Write> Sink for JsonLines<W> {
+    fn event(&mut self, event: &Event) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| {
+                d.as_secs() as f64 + f64::from(d.subsec_millis()) / 1000.0
+            });
+        let line = to_json(event, time) + "\n";
+        if let Err(e) = self
+            .out
+            .write_all(line.as_bytes())
+            .and_then(|_| self.out.flush())
+        {
+            eprintln!("Failed to log a DNS event: {}", e);
+        }
+    }
+}
+
+/// A filter passing the DNS queries of the guest over UDP, and the
+/// responses, to a sink.
+///
+/// It should be the first filter, to see the queries that other filters
+/// answer.
+pub struct Observer {
+    sink: Box<dyn Sink>,
+}
+
+impl Observer {
+    pub fn new(sink: Box<dyn Sink>) -> Self {
+        Self { sink }
+    }
+}
+
+impl Filter for Observer {
+    fn input(&mut self, frame: &[u8], now: i64, _reply: &mut Vec<Vec<u8>>) -> Verdict {
+        if let Some(event) = Event::parse(frame, now).filter(|e| e.rcode.is_none()) {
+            self.sink.event(&event);
+        }
+        Verdict::Pass
+    }
+
+    fn output(&mut self, frame: &mut Vec<u8>, now: i64) -> Verdict {
+        if let Some(event) = Event::parse(frame, now).filter(|e| e.rcode.is_some()) {
+            self.sink.event(&event);
+        }
+        Verdict::Pass
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{query, Record, RCODE_NOERROR, TYPE_PTR};
+    use super::*;
+    use crate::packet::udp_frame;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn observe() {
+        let guest: SocketAddr = "10.0.2.15:5353".parse().unwrap();
+        let server: SocketAddr = "10.0.2.3:53".parse().unwrap();
+        let msg = query(7, "mirror.internal", TYPE_A);
+        let q = Query::parse(&msg).unwrap();
+        let resp = q.response(
+            &msg,
+            RCODE_NOERROR,
+            &[
+                Record::addr("192.168.1.10".parse().unwrap(), 60),
+                Record::ptr("mirror.internal", 60),
+            ],
+        );
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let log = events.clone();
+        let mut observer =
+            Observer::new(Box::new(move |e: &Event| log.borrow_mut().push(e.clone())));
+        let mut reply = Vec::new();
+        let frame = udp_frame([2; 6], [4; 6], guest, server, &msg);
+        assert_eq!(observer.input(&frame, 1, &mut reply), Verdict::Pass);
+        let mut frame = udp_frame([4; 6], [2; 6], server, guest, &resp);
+        assert_eq!(observer.output(&mut frame, 2), Verdict::Pass);
+        // not DNS
+        let mut frame = udp_frame(
+            [4; 6],
+            [2; 6],
+            server,
+            "10.0.2.15:53".parse().unwrap(),
+            b"x",
+        );
+        observer.output(&mut frame, 3);
+
+        let events = events.borrow();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].timestamp, events[0].guest), (1, guest));
+        assert_eq!(
+            (events[0].qname.as_str(), events[0].rcode),
+            ("mirror.internal", None)
+        );
+        assert_eq!((events[1].timestamp, events[1].server), (2, server));
+        assert_eq!(events[1].rcode, Some(RCODE_NOERROR));
+        assert_eq!(events[1].answers[0].data, "192.168.1.10");
+        assert_eq!(events[1].answers[1].rtype, TYPE_PTR);
+        assert_eq!(events[1].answers[1].data, "mirror.internal");
+
+        assert_eq!(
+            to_json(&events[0], 1.5),
+            r#"{"ts":1,"time":1.500,"guest":"10.0.2.15","server":"10.0.2.3","type":"query","id":7,"qname":"mirror.internal","qtype":"A"}"#
+        );
+        assert!(to_json(&events[1], 0.0).ends_with(
+            r#""rcode":"NOERROR","answers":[{"name":"mirror.internal","type":"A","ttl":60,"data":"192.168.1.10"},{"name":"mirror.internal","type":"PTR","ttl":60,"data":"mirror.internal"}]}"#
+        ));
+
+        let mut out = String::new();
+        json_str(&mut out, "a\"b\\c\n");
+        assert_eq!(out, r#""a\"b\\c\u000a""#);
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut buf = Vec::new();
+        {
+            let mut sink = JsonLines::new(&mut buf);
+            let frame = udp_frame(
+                [2; 6],
+                [4; 6],
+                "10.0.2.15:5353".parse().unwrap(),
+                "10.0.2.3:53".parse().unwrap(),
+                &query(7, "example.com", TYPE_AAAA),
+            );
+            sink.event(&Event::parse(&frame, 1).unwrap());
+            sink.event(&Event::parse(&frame, 2).unwrap());
+        }
+        let out = String::from_utf8(buf).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with(r#"{"ts":2,"#));
+        assert!(lines[1].ends_with(r#""qname":"example.com","qtype":"AAAA"}"#));
+    }
+}
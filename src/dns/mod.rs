@@ -0,0 +1,449 @@
+//! DNS messages of the guest, for the filters answering them in Rust.
+
+use crate::Opt;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub mod hosts;
+pub mod log;
+pub mod proxy;
+
+pub use self::hosts::Hosts;
+pub use self::log::Observer;
+pub use self::proxy::Proxy;
+
+pub const PORT: u16 = 53;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_OPT: u16 = 41;
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+
+const HEADER_LEN: usize = 12;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+// the longest chain of compression pointers we follow
+const MAX_POINTERS: usize = 16;
+
+/// The addresses of the virtual nameserver.
+pub(crate) fn nameservers(opt: &Opt) -> Vec<IpAddr> {
+    let mut nameservers = Vec::new();
+    if !opt.ipv4.disable {
+        nameservers.push(opt.ipv4.dns().into());
+    }
+    if !opt.ipv6.disable {
+        nameservers.push(opt.ipv6.dns().into());
+    }
+    nameservers
+}
+
+/// The mnemonic of a record type, in the RFC 3597 form if it's unknown.
+pub fn type_name(rtype: u16) -> String {
+    match rtype {
+        TYPE_A => "A".to_string(),
+        2 => "NS".to_string(),
+        5 => "CNAME".to_string(),
+        6 => "SOA".to_string(),
+        TYPE_PTR => "PTR".to_string(),
+        15 => "MX".to_string(),
+        16 => "TXT".to_string(),
+        TYPE_AAAA => "AAAA".to_string(),
+        33 => "SRV".to_string(),
+        TYPE_OPT => "OPT".to_string(),
+        64 => "SVCB".to_string(),
+        65 => "HTTPS".to_string(),
+        255 => "ANY".to_string(),
+        _ => format!("TYPE{}", rtype),
+    }
+}
+
+/// The mnemonic of a response code.
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        RCODE_NOERROR => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        RCODE_SERVFAIL => "SERVFAIL".to_string(),
+        RCODE_NXDOMAIN => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        _ => format!("RCODE{}", rcode),
+    }
+}
+
+fn be16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+/// Read the domain name at `pos` in `msg`, following the compression
+/// pointers. Returns the name, without the trailing dot, and the position
+/// past it.
+pub fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = usize::from(*msg.get(pos)?);
+        match len & 0xc0 {
+            0 if len == 0 => return Some((name, end.unwrap_or(pos + 1))),
+            0 => {
+                let label = msg.get(pos + 1..pos + 1 + len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                pos += 1 + len;
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                pos = (len & 0x3f) << 8 | usize::from(*msg.get(pos + 1)?);
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Append the wire format of `name` to `buf`, without compression.
+pub fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+/// The name of the PTR record of an address.
+pub fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let o = addr.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::new();
+            for b in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", b & 0xf, b >> 4));
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+/// The address of the name of a PTR record.
+pub fn reverse_addr(name: &str) -> Option<IpAddr> {
+    let name = name.to_ascii_lowercase();
+    if let Some(rev) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = rev
+            .split('.')
+            .map(|o| o.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+        return Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).into());
+    }
+
+    let rev = name.strip_suffix(".ip6.arpa")?;
+    let nibbles = rev
+        .split('.')
+        .map(|n| match n.len() {
+            1 => u8::from_str_radix(n, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let mut octets = [0; 16];
+    for (i, pair) in nibbles.chunks(2).rev().enumerate() {
+        octets[i] = pair[1] << 4 | pair[0];
+    }
+    Some(Ipv6Addr::from(octets).into())
+}
+
+/// A standard query with a single question.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub id: u16,
+    pub flags: u16,
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+    // the end of the question in the message
+    end: usize,
+}
+
+/// A resource record for the name of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub rtype: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// An A or AAAA record.
+    pub fn addr(addr: IpAddr, ttl: u32) -> Self {
+        match addr {
+            IpAddr::V4(addr) => Self {
+                rtype: TYPE_A,
+                ttl,
+                data: addr.octets().to_vec(),
+            },
+            IpAddr::V6(addr) => Self {
+                rtype: TYPE_AAAA,
+                ttl,
+                data: addr.octets().to_vec(),
+            },
+        }
+    }
+
+    /// A PTR record.
+    pub fn ptr(name: &str, ttl: u32) -> Self {
+        let mut data = Vec::new();
+        write_name(&mut data, name);
+        Self {
+            rtype: TYPE_PTR,
+            ttl,
+            data,
+        }
+    }
+}
+
+impl Query {
+    /// Parse a message, if it is a standard query with a single question.
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        let flags = be16(msg, 2)?;
+        // a response, or not a standard query
+        if flags & FLAG_QR != 0 || (flags >> 11) & 0xf != 0 {
+            return None;
+        }
+        let (name, qtype, qclass, end) = question(msg)?;
+        Some(Self {
+            id: be16(msg, 0)?,
+            flags,
+            name,
+            qtype,
+            qclass,
+            end,
+        })
+    }
+
+    /// An authoritative response to the query `msg`, with `answers`.
+    pub fn response(&self, msg: &[u8], rcode: u8, answers: &[Record]) -> Vec<u8> {
+        let flags = FLAG_QR | FLAG_AA | (self.flags & FLAG_RD) | FLAG_RA | u16::from(rcode & 0xf);
+
+        let mut buf = Vec::with_capacity(512);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&flags.to_be_bytes());
+        for count in &[1, answers.len() as u16, 0, 0] {
+            buf.extend_from_slice(&count.to_be_bytes());
+        }
+        buf.extend_from_slice(&msg[HEADER_LEN..self.end]);
+
+        for answer in answers {
+            // a pointer to the name of the question
+            buf.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            buf.extend_from_slice(&answer.rtype.to_be_bytes());
+            buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+            buf.extend_from_slice(&answer.ttl.to_be_bytes());
+            buf.extend_from_slice(&(answer.data.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&answer.data);
+        }
+        buf
+    }
+}
+
+/// The single question of a message: its name, type, class, and the
+/// position past it.
+fn question(msg: &[u8]) -> Option<(String, u16, u16, usize)> {
+    if be16(msg, 4)? != 1 {
+        return None;
+    }
+    let (name, pos) = read_name(msg, HEADER_LEN)?;
+    Some((name, be16(msg, pos)?, be16(msg, pos + 2)?, pos + 4))
+}
+
+/// A resource record of a response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceRecord {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    /// The position of the TTL in the message.
+    pub ttl_pos: usize,
+    /// The data, where names may be compressed.
+    pub data: Vec<u8>,
+}
+
+/// A response to a query with a single question.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub id: u16,
+    pub flags: u16,
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+    pub answers: Vec<ResourceRecord>,
+    /// The records of the authority and additional sections.
+    pub others: Vec<ResourceRecord>,
+}
+
+impl Response {
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        let flags = be16(msg, 2)?;
+        if flags & FLAG_QR == 0 {
+            return None;
+        }
+        let (name, qtype, qclass, mut pos) = question(msg)?;
+
+        let count = |i: usize| be16(msg, 6 + 2 * i).map(usize::from);
+        let ancount = count(0)?;
+        let mut records = Vec::new();
+        for _ in 0..ancount + count(1)? + count(2)? {
+            let (name, p) = read_name(msg, pos)?;
+            let len = usize::from(be16(msg, p + 8)?);
+            let ttl = msg.get(p + 4..p + 8)?;
+            records.push(ResourceRecord {
+                name,
+                rtype: be16(msg, p)?,
+                class: be16(msg, p + 2)?,
+                ttl: u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]]),
+                ttl_pos: p + 4,
+                data: msg.get(p + 10..p + 10 + len)?.to_vec(),
+            });
+            pos = p + 10 + len;
+        }
+        let others = records.split_off(ancount);
+
+        Some(Self {
+            id: be16(msg, 0)?,
+            flags,
+            name,
+            qtype,
+            qclass,
+            answers: records,
+            others,
+        })
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0xf) as u8
+    }
+
+    /// Whether the response didn't fit in the message.
+    pub fn truncated(&self) -> bool {
+        self.flags & FLAG_TC != 0
+    }
+
+    /// The records with a TTL, that is all but the EDNS pseudo-record.
+    pub fn records(&self) -> impl Iterator<Item = &ResourceRecord> {
+        self.answers
+            .iter()
+            .chain(&self.others)
+            .filter(|r| r.rtype != TYPE_OPT)
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut msg = id.to_be_bytes().to_vec();
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    write_name(&mut msg, name);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name() {
+        let mut msg = vec![0; 4];
+        write_name(&mut msg, "mirror.internal.");
+        assert_eq!(&msg[4..], b"\x06mirror\x08internal\x00");
+        // www, then a pointer to the name above
+        msg.extend_from_slice(b"\x03www\xc0\x04");
+        assert_eq!(
+            read_name(&msg, 4),
+            Some(("mirror.internal".to_string(), 21))
+        );
+        assert_eq!(
+            read_name(&msg, 21),
+            Some(("www.mirror.internal".to_string(), 27))
+        );
+
+        // loops and truncated names
+        assert_eq!(read_name(b"\xc0\x00", 0), None);
+        assert_eq!(read_name(b"\x03ww", 0), None);
+        assert_eq!(read_name(b"\x40", 0), None);
+    }
+
+    #[test]
+    fn reverse() {
+        for addr in &["10.0.2.15", "fec0::15", "2001:db8::abcd:1"] {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(reverse_addr(&reverse_name(addr)), Some(addr));
+        }
+        assert_eq!(
+            reverse_name("10.0.2.15".parse().unwrap()),
+            "15.2.0.10.in-addr.arpa"
+        );
+        assert_eq!(reverse_addr("2.0.10.in-addr.arpa"), None);
+        assert_eq!(reverse_addr("x.2.0.10.in-addr.arpa"), None);
+        assert_eq!(reverse_addr("example.com"), None);
+    }
+
+    #[test]
+    fn response() {
+        let msg = query(0x1234, "mirror.internal", TYPE_A);
+        let q = Query::parse(&msg).unwrap();
+        assert_eq!(q.id, 0x1234);
+        assert_eq!(q.name, "mirror.internal");
+        assert_eq!((q.qtype, q.qclass), (TYPE_A, CLASS_IN));
+
+        let addr = "192.168.1.10".parse().unwrap();
+        let resp = q.response(&msg, RCODE_NOERROR, &[Record::addr(addr, 60)]);
+        assert_eq!(&resp[..4], &[0x12, 0x34, 0x85, 0x80]);
+        assert_eq!(&resp[4..12], &[0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&resp[12..msg.len()], &msg[12..]);
+        assert_eq!(
+            &resp[msg.len()..],
+            &[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 1, 10]
+        );
+        // not a query
+        assert_eq!(Query::parse(&resp), None);
+
+        let r = Response::parse(&resp).unwrap();
+        assert_eq!(
+            (r.id, r.rcode(), r.truncated()),
+            (0x1234, RCODE_NOERROR, false)
+        );
+        assert_eq!((r.name.as_str(), r.qtype), ("mirror.internal", TYPE_A));
+        assert_eq!(r.answers.len(), 1);
+        assert_eq!(r.answers[0].name, "mirror.internal");
+        assert_eq!((r.answers[0].rtype, r.answers[0].ttl), (TYPE_A, 60));
+        assert_eq!(r.answers[0].data, vec![192, 168, 1, 10]);
+        assert_eq!(&resp[r.answers[0].ttl_pos..][..4], &[0, 0, 0, 60]);
+        assert!(r.others.is_empty());
+        // truncated
+        assert_eq!(Response::parse(&resp[..resp.len() - 1]), None);
+        assert_eq!(Response::parse(&msg), None);
+    }
+}
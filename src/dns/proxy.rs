@@ -0,0 +1,811 @@
+use super::{type_name, Query, Response, PORT, RCODE_NOERROR, RCODE_NXDOMAIN, RCODE_SERVFAIL};
+use crate::filter::{Filter, Verdict};
+use crate::packet::Udp;
+use crate::Opt;
+
+use mio::net::TcpStream;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the times, in nanoseconds of the handler clock
+const SECOND: i64 = 1_000_000_000;
+// how long to wait for an answer, before switching to the next upstream
+const TIMEOUT: i64 = 5 * SECOND;
+const MAX_PENDING: usize = 1024;
+const MAX_CACHED: usize = 4096;
+// the longest time an answer is cached, in seconds
+const MAX_TTL: u32 = 3600;
+// the answers waiting for the guest connection to take them, beyond which
+// its queries are dropped
+const MAX_OUTPUT: usize = 65536;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+}
+
+impl Key {
+    fn new(name: &str, qtype: u16, qclass: u16) -> Self {
+        Self {
+            name: name.to_ascii_lowercase(),
+            qtype,
+            qclass,
+        }
+    }
+}
+
+struct Cached {
+    msg: Vec<u8>,
+    // the position and value of the TTLs in the message
+    ttls: Vec<(usize, u32)>,
+    stored: i64,
+    expires: i64,
+}
+
+/// Where the answer of a query goes.
+enum Guest {
+    // the query of the guest, without its payload
+    Udp(Udp<'static>),
+    // the number of the guest connection, and its query, to answer with a
+    // failure
+    Tcp(u32, Vec<u8>),
+}
+
+/// A query waiting for its upstream answer.
+struct Pending {
+    guest: Guest,
+    id: u16,
+    key: Key,
+    sent: i64,
+    upstream: usize,
+    // the upstream connection of a query over TCP
+    stream: Option<Stream>,
+}
+
+/// A connection to an upstream, for a single query.
+struct Stream {
+    sock: TcpStream,
+    // what remains to write of the query, and what was read of the answer,
+    // both with their length
+    output: Vec<u8>,
+    input: Vec<u8>,
+}
+
+impl Stream {
+    /// Write the query, then read the answer, returned once complete.
+    fn exchange(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while !self.output.is_empty() {
+            match self.sock.write(&self.output) {
+                Ok(len) => drop(self.output.drain(..len)),
+                // still connecting
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::NotConnected =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut buf = [0; 4096];
+        loop {
+            match self.sock.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => self.input.extend_from_slice(&buf[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            if let Some(msg) = message(&self.input) {
+                return Ok(Some(msg.to_vec()));
+            }
+        }
+    }
+}
+
+/// The first message of a TCP stream, once complete.
+fn message(stream: &[u8]) -> Option<&[u8]> {
+    if stream.len() < 2 {
+        return None;
+    }
+    let len = usize::from(u16::from_be_bytes([stream[0], stream[1]]));
+    stream.get(2..2 + len)
+}
+
+/// The guest connection over TCP, through the guest forward of libslirp.
+struct Conn {
+    // the number of the connection, to drop the answers to the previous ones
+    number: u32,
+    guest: SocketAddr,
+    // the stream of queries, until they are complete
+    queries: Vec<u8>,
+    // the answers, with their length, until the guest takes them
+    answers: Vec<u8>,
+}
+
+impl Conn {
+    fn send(&mut self, msg: &[u8]) {
+        if self.answers.len() + 2 + msg.len() > MAX_OUTPUT {
+            return;
+        }
+        self.answers
+            .extend_from_slice(&(msg.len() as u16).to_be_bytes());
+        self.answers.extend_from_slice(msg);
+    }
+}
+
+/// A caching DNS forwarder, answering the guest queries to the virtual
+/// nameserver from upstream servers, instead of the resolver
+/// configuration of the host.
+///
+/// The queries over UDP are forwarded over UDP, and the queries over TCP
+/// over TCP, one connection to the upstream per query. libslirp passes the
+/// guest connections to the IPv4 nameservers through a guest forward, which
+/// serves one connection at a time: a new one replaces the previous one.
+/// The connections to an IPv6 nameserver are left to libslirp.
+pub struct Proxy {
+    nameservers: Vec<IpAddr>,
+    upstreams: Vec<SocketAddr>,
+    // the upstream the queries go to, the next one after a timeout
+    current: usize,
+    sock4: Option<UdpSocket>,
+    sock6: Option<UdpSocket>,
+    pending: HashMap<u16, Pending>,
+    cache: HashMap<Key, Cached>,
+    conn: Option<Conn>,
+    conns: u32,
+    rand: u32,
+    log: bool,
+}
+
+fn socket(addr: IpAddr) -> io::Result<UdpSocket> {
+    let sock = UdpSocket::bind(SocketAddr::new(addr, 0))?;
+    sock.set_nonblocking(true)?;
+    Ok(sock)
+}
+
+impl Proxy {
+    /// Forward the queries sent to `nameservers` to `upstreams`, which
+    /// must not be empty.
+    pub fn new(nameservers: &[IpAddr], upstreams: &[SocketAddr]) -> io::Result<Self> {
+        if upstreams.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no upstream DNS server",
+            ));
+        }
+        let sock4 = if upstreams.iter().any(SocketAddr::is_ipv4) {
+            Some(socket(Ipv4Addr::UNSPECIFIED.into())?)
+        } else {
+            None
+        };
+        let sock6 = if upstreams.iter().any(SocketAddr::is_ipv6) {
+            Some(socket(Ipv6Addr::UNSPECIFIED.into())?)
+        } else {
+            None
+        };
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+
+        Ok(Self {
+            nameservers: nameservers.to_vec(),
+            upstreams: upstreams.to_vec(),
+            current: 0,
+            sock4,
+            sock6,
+            pending: HashMap::new(),
+            cache: HashMap::new(),
+            conn: None,
+            conns: 0,
+            rand: (seed ^ process::id()) | 1,
+            log: false,
+        })
+    }
+
+    /// The proxy to the `--dns-upstream` servers.
+    pub fn from_opt(opt: &Opt) -> io::Result<Self> {
+        let upstreams: Vec<_> = opt.dns_upstreams.iter().map(|u| u.0).collect();
+        let mut proxy = Self::new(&super::nameservers(opt), &upstreams)?;
+        proxy.set_log(opt.dns_proxy_log);
+        Ok(proxy)
+    }
+
+    /// Log the queries to stderr, with the address of the guest.
+    pub fn set_log(&mut self, log: bool) {
+        self.log = log;
+    }
+
+    fn socket(&self, addr: &SocketAddr) -> Option<&UdpSocket> {
+        match addr {
+            SocketAddr::V4(_) => self.sock4.as_ref(),
+            SocketAddr::V6(_) => self.sock6.as_ref(),
+        }
+    }
+
+    // xorshift
+    fn next_rand(&mut self) -> u32 {
+        self.rand ^= self.rand << 13;
+        self.rand ^= self.rand >> 17;
+        self.rand ^= self.rand << 5;
+        self.rand
+    }
+
+    // a random ID of the upstream query, as the guest IDs may collide
+    fn next_id(&mut self) -> u16 {
+        loop {
+            let id = (self.next_rand() >> 16) as u16;
+            if !self.pending.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Fail the queries that timed out.
+    fn expire(&mut self, now: i64, reply: &mut Vec<Vec<u8>>) {
+        let expired: Vec<u16> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| now >= p.sent + TIMEOUT)
+            .map(|(&id, _)| id)
+            .collect();
+        let current = self.current;
+        if expired
+            .iter()
+            .any(|id| self.pending[id].upstream == current)
+        {
+            self.current = (current + 1) % self.upstreams.len();
+        }
+        for id in expired {
+            let pending = self.pending.remove(&id).unwrap();
+            self.fail(pending, reply);
+        }
+    }
+
+    /// The cached answer to a query, with its ID and the remaining TTLs.
+    fn cached(&self, key: &Key, id: u16, now: i64) -> Option<Vec<u8>> {
+        let cached = self.cache.get(key).filter(|c| now < c.expires)?;
+        let elapsed = ((now - cached.stored) / SECOND) as u32;
+
+        let mut msg = cached.msg.clone();
+        msg[0..2].copy_from_slice(&id.to_be_bytes());
+        for &(pos, ttl) in &cached.ttls {
+            msg[pos..pos + 4].copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+        Some(msg)
+    }
+
+    fn store(&mut self, key: Key, msg: &[u8], resp: &Response, now: i64) {
+        if resp.truncated() || (resp.rcode() != RCODE_NOERROR && resp.rcode() != RCODE_NXDOMAIN) {
+            return;
+        }
+        let ttl = match resp.records().map(|r| r.ttl).min() {
+            Some(ttl) if ttl > 0 => ttl.min(MAX_TTL),
+            // nothing tells how long it is valid
+            _ => return,
+        };
+        if self.cache.len() >= MAX_CACHED && !self.cache.contains_key(&key) {
+            self.cache.retain(|_, c| now < c.expires);
+            if self.cache.len() >= MAX_CACHED {
+                return;
+            }
+        }
+
+        let ttls = resp.records().map(|r| (r.ttl_pos, r.ttl)).collect();
+        self.cache.insert(
+            key,
+            Cached {
+                msg: msg.to_vec(),
+                ttls,
+                stored: now,
+                expires: now + i64::from(ttl) * SECOND,
+            },
+        );
+    }
+
+    fn log(&self, guest: IpAddr, query: &Query, cached: bool) {
+        if self.log {
+            eprintln!(
+                "dns: {} {} {}{}",
+                guest,
+                type_name(query.qtype),
+                query.name,
+                if cached { " (cached)" } else { "" }
+            );
+        }
+    }
+
+    /// Forward the query `msg` upstream, returning whether it was sent.
+    fn forward(&mut self, guest: Guest, msg: &[u8], query: &Query, now: i64) -> bool {
+        if self.pending.len() >= MAX_PENDING {
+            return false;
+        }
+        let upstream = self.current;
+        let addr = self.upstreams[upstream];
+        let id = self.next_id();
+
+        let mut msg = msg.to_vec();
+        msg[0..2].copy_from_slice(&id.to_be_bytes());
+        let stream = match guest {
+            Guest::Udp(_) => {
+                let sent = match self.socket(&addr) {
+                    Some(sock) => sock.send_to(&msg, addr),
+                    None => return false,
+                };
+                if let Err(e) = sent {
+                    eprintln!("dns: failed to forward to {}: {}", addr, e);
+                    return false;
+                }
+                None
+            }
+            Guest::Tcp(..) => {
+                let sock = match TcpStream::connect(&addr) {
+                    Ok(sock) => sock,
+                    Err(e) => {
+                        eprintln!("dns: failed to forward to {}: {}", addr, e);
+                        return false;
+                    }
+                };
+                let mut output = (msg.len() as u16).to_be_bytes().to_vec();
+                output.extend(msg);
+                Some(Stream {
+                    sock,
+                    output,
+                    input: Vec::new(),
+                })
+            }
+        };
+
+        self.pending.insert(
+            id,
+            Pending {
+                guest,
+                id: query.id,
+                key: Key::new(&query.name, query.qtype, query.qclass),
+                sent: now,
+                upstream,
+                stream,
+            },
+        );
+        true
+    }
+
+    /// Send an answer to the guest of a query.
+    fn reply(&mut self, guest: Guest, msg: &[u8], reply: &mut Vec<Vec<u8>>) {
+        match guest {
+            Guest::Udp(udp) => reply.push(udp.reply(msg)),
+            Guest::Tcp(number, _) => match &mut self.conn {
+                Some(conn) if conn.number == number => conn.send(msg),
+                // replaced meanwhile
+                _ => (),
+            },
+        }
+    }
+
+    /// A query got no answer: the guest retries over UDP, and is told over
+    /// TCP.
+    fn fail(&mut self, pending: Pending, reply: &mut Vec<Vec<u8>>) {
+        let msg = match &pending.guest {
+            Guest::Tcp(_, query) => match Query::parse(query) {
+                Some(q) => q.response(query, RCODE_SERVFAIL, &[]),
+                None => return,
+            },
+            Guest::Udp(_) => return,
+        };
+        self.reply(pending.guest, &msg, reply);
+    }
+
+    /// The upstream answer to a pending query.
+    fn answer(&mut self, pending: Pending, msg: &mut [u8], now: i64, reply: &mut Vec<Vec<u8>>) {
+        if let Some(resp) = Response::parse(msg) {
+            let key = Key::new(&resp.name, resp.qtype, resp.qclass);
+            if key == pending.key {
+                self.store(key, msg, &resp, now);
+                msg[0..2].copy_from_slice(&pending.id.to_be_bytes());
+                self.reply(pending.guest, msg, reply);
+                return;
+            }
+        }
+        self.fail(pending, reply);
+    }
+
+    /// An answer over UDP.
+    fn datagram(&mut self, from: SocketAddr, msg: &mut [u8], now: i64, reply: &mut Vec<Vec<u8>>) {
+        if !self.upstreams.contains(&from) {
+            return;
+        }
+        let resp = match Response::parse(msg) {
+            Some(resp) => resp,
+            None => return,
+        };
+        let key = Key::new(&resp.name, resp.qtype, resp.qclass);
+        match self.pending.get(&resp.id) {
+            Some(pending) if pending.key == key && pending.stream.is_none() => (),
+            // a late or spoofed answer
+            _ => return,
+        }
+        let pending = self.pending.remove(&resp.id).unwrap();
+        self.answer(pending, msg, now, reply);
+    }
+
+    /// The upstream connection of the query `id` is ready.
+    fn stream_ready(&mut self, id: u16, now: i64, reply: &mut Vec<Vec<u8>>) {
+        let mut pending = self.pending.remove(&id).unwrap();
+        let res = pending.stream.as_mut().unwrap().exchange();
+        match res {
+            Ok(None) => drop(self.pending.insert(id, pending)),
+            Ok(Some(mut msg)) if msg.starts_with(&id.to_be_bytes()) => {
+                self.answer(pending, &mut msg, now, reply)
+            }
+            Ok(Some(_)) => self.fail(pending, reply),
+            Err(e) => {
+                let addr = self.upstreams[pending.upstream];
+                eprintln!("dns: failed to forward to {}: {}", addr, e);
+                self.fail(pending, reply);
+            }
+        }
+    }
+
+    /// A query over UDP.
+    fn udp(&mut self, udp: &Udp, now: i64, reply: &mut Vec<Vec<u8>>) -> Verdict {
+        let query = match Query::parse(udp.payload) {
+            Some(query) => query,
+            None => return Verdict::Pass,
+        };
+
+        let key = Key::new(&query.name, query.qtype, query.qclass);
+        if let Some(msg) = self.cached(&key, query.id, now) {
+            self.log(udp.src.ip(), &query, true);
+            reply.push(udp.reply(&msg));
+            return Verdict::Drop;
+        }
+
+        self.log(udp.src.ip(), &query, false);
+        let guest = Guest::Udp(Udp {
+            src_mac: udp.src_mac,
+            dst_mac: udp.dst_mac,
+            src: udp.src,
+            dst: udp.dst,
+            payload: &[],
+        });
+        if self.forward(guest, udp.payload, &query, now) {
+            Verdict::Drop
+        } else {
+            // left to libslirp
+            Verdict::Pass
+        }
+    }
+
+    /// A query of the guest connection.
+    fn query(&mut self, msg: Vec<u8>, now: i64) {
+        // not something to forward, or to answer with a failure
+        let query = match Query::parse(&msg) {
+            Some(query) => query,
+            None => return,
+        };
+        let (number, guest) = match &self.conn {
+            Some(conn) => (conn.number, conn.guest.ip()),
+            None => return,
+        };
+
+        let key = Key::new(&query.name, query.qtype, query.qclass);
+        let answer = match self.cached(&key, query.id, now) {
+            Some(answer) => {
+                self.log(guest, &query, true);
+                answer
+            }
+            None => {
+                self.log(guest, &query, false);
+                if self.forward(Guest::Tcp(number, msg.clone()), &msg, &query, now) {
+                    return;
+                }
+                query.response(&msg, RCODE_SERVFAIL, &[])
+            }
+        };
+        if let Some(conn) = &mut self.conn {
+            conn.send(&answer);
+        }
+    }
+}
+
+impl Filter for Proxy {
+    fn input(&mut self, frame: &[u8], now: i64, reply: &mut Vec<Vec<u8>>) -> Verdict {
+        if let Some(udp) = Udp::parse(frame) {
+            if udp.dst.port() == PORT && self.nameservers.contains(&udp.dst.ip()) {
+                return self.udp(&udp, now, reply);
+            }
+        }
+        Verdict::Pass
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        let streams = self.pending.values().filter_map(|p| p.stream.as_ref());
+        self.sock4
+            .iter()
+            .chain(&self.sock6)
+            .map(AsRawFd::as_raw_fd)
+            .chain(streams.map(|s| s.sock.as_raw_fd()))
+            .collect()
+    }
+
+    fn write_fds(&self) -> Vec<RawFd> {
+        self.pending
+            .values()
+            .filter_map(|p| p.stream.as_ref())
+            .filter(|s| !s.output.is_empty())
+            .map(|s| s.sock.as_raw_fd())
+            .collect()
+    }
+
+    fn ready(&mut self, fd: RawFd, now: i64, reply: &mut Vec<Vec<u8>>) {
+        let stream = self
+            .pending
+            .iter()
+            .find(|(_, p)| p.stream.as_ref().map(|s| s.sock.as_raw_fd()) == Some(fd));
+        if let Some((&id, _)) = stream {
+            self.stream_ready(id, now, reply);
+            return;
+        }
+
+        let mut buf = [0; 65536];
+        loop {
+            let sock = match self
+                .sock4
+                .iter()
+                .chain(&self.sock6)
+                .find(|s| s.as_raw_fd() == fd)
+            {
+                Some(sock) => sock,
+                None => return,
+            };
+            let (len, from) = match sock.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    eprintln!("dns: failed to receive an answer: {}", e);
+                    return;
+                }
+            };
+            self.datagram(from, &mut buf[..len], now, reply);
+        }
+    }
+
+    fn deadline(&self) -> Option<i64> {
+        self.pending.values().map(|p| p.sent + TIMEOUT).min()
+    }
+
+    fn timeout(&mut self, now: i64, reply: &mut Vec<Vec<u8>>) {
+        self.expire(now, reply);
+    }
+
+    fn streams(&self) -> Vec<SocketAddrV4> {
+        let v4 = self.nameservers.iter().filter_map(|ip| match ip {
+            IpAddr::V4(ip) => Some(SocketAddrV4::new(*ip, PORT)),
+            IpAddr::V6(_) => None,
+        });
+        v4.collect()
+    }
+
+    fn stream_open(&mut self, _addr: SocketAddrV4, guest: SocketAddr, _now: i64) {
+        self.conns = self.conns.wrapping_add(1);
+        self.conn = Some(Conn {
+            number: self.conns,
+            guest,
+            queries: Vec::new(),
+            answers: Vec::new(),
+        });
+    }
+
+    fn stream_input(&mut self, _addr: SocketAddrV4, data: &[u8], now: i64) {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => return,
+        };
+        conn.queries.extend_from_slice(data);
+        let mut queries = Vec::new();
+        while let Some(msg) = message(&conn.queries) {
+            queries.push(msg.to_vec());
+            conn.queries.drain(..2 + msg.len());
+        }
+        for msg in queries {
+            self.query(msg, now);
+        }
+    }
+
+    fn stream_output(&mut self, _addr: SocketAddrV4, buf: &mut [u8]) -> usize {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => return 0,
+        };
+        let len = buf.len().min(conn.answers.len());
+        buf[..len].copy_from_slice(&conn.answers[..len]);
+        conn.answers.drain(..len);
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{query, Record, TYPE_A};
+    use super::*;
+    use crate::packet::udp_frame;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    const GUEST: &str = "10.0.2.15:5353";
+    const NAMESERVER: &str = "10.0.2.3:53";
+
+    fn ask(proxy: &mut Proxy, id: u16, name: &str) -> (Verdict, Vec<Vec<u8>>) {
+        let frame = udp_frame(
+            [2; 6],
+            [4; 6],
+            GUEST.parse().unwrap(),
+            NAMESERVER.parse().unwrap(),
+            &query(id, name, TYPE_A),
+        );
+        let mut reply = Vec::new();
+        let verdict = proxy.input(&frame, 0, &mut reply);
+        (verdict, reply)
+    }
+
+    fn wait_reply(proxy: &mut Proxy) -> Vec<u8> {
+        for _ in 0..100 {
+            let mut reply = Vec::new();
+            for fd in proxy.fds() {
+                proxy.ready(fd, 0, &mut reply);
+            }
+            if let Some(frame) = reply.pop() {
+                return frame;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no answer");
+    }
+
+    fn wait_output(proxy: &mut Proxy, addr: SocketAddrV4) -> Vec<u8> {
+        let mut buf = [0; 512];
+        for _ in 0..100 {
+            for fd in proxy.fds() {
+                proxy.ready(fd, 0, &mut Vec::new());
+            }
+            let len = proxy.stream_output(addr, &mut buf);
+            if len > 0 {
+                return buf[..len].to_vec();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no answer");
+    }
+
+    #[test]
+    fn forward() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut proxy = Proxy::new(
+            &[NAMESERVER.parse::<SocketAddr>().unwrap().ip()],
+            &[server.local_addr().unwrap()],
+        )
+        .unwrap();
+
+        let (verdict, reply) = ask(&mut proxy, 7, "Mirror.Internal");
+        assert_eq!(verdict, Verdict::Drop);
+        assert!(reply.is_empty());
+
+        let mut buf = [0; 512];
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        let msg = &buf[..len];
+        let query = Query::parse(msg).unwrap();
+        assert_eq!(query.name, "Mirror.Internal");
+        let addr = "192.168.1.10".parse().unwrap();
+        let answer = query.response(msg, RCODE_NOERROR, &[Record::addr(addr, 300)]);
+        // a spoofed answer is ignored
+        let mut spoofed = answer.clone();
+        spoofed[0] ^= 0xff;
+        server.send_to(&spoofed, from).unwrap();
+        server.send_to(&answer, from).unwrap();
+
+        let frame = wait_reply(&mut proxy);
+        let udp = Udp::parse(&frame).unwrap();
+        assert_eq!(udp.src, NAMESERVER.parse().unwrap());
+        assert_eq!(udp.dst, GUEST.parse().unwrap());
+        let resp = Response::parse(udp.payload).unwrap();
+        assert_eq!(resp.id, 7);
+        assert_eq!(resp.answers[0].data, vec![192, 168, 1, 10]);
+
+        // from the cache, whatever the case
+        let (verdict, reply) = ask(&mut proxy, 8, "mirror.internal");
+        assert_eq!(verdict, Verdict::Drop);
+        let udp = Udp::parse(&reply[0]).unwrap();
+        let resp = Response::parse(udp.payload).unwrap();
+        assert_eq!(resp.id, 8);
+        assert!(resp.answers[0].ttl <= 300);
+        server.set_nonblocking(true).unwrap();
+        assert!(server.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn tcp() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = server.local_addr().unwrap();
+        let thread = thread::spawn(move || {
+            let (mut stream, _) = server.accept().unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut msg = vec![0; usize::from(u16::from_be_bytes(len))];
+            stream.read_exact(&mut msg).unwrap();
+            let query = Query::parse(&msg).unwrap();
+            assert_eq!(query.name, "mirror.internal");
+            let addr = "192.168.1.10".parse().unwrap();
+            let answer = query.response(&msg, RCODE_NOERROR, &[Record::addr(addr, 300)]);
+            stream
+                .write_all(&(answer.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&answer).unwrap();
+        });
+        let mut proxy = Proxy::new(&["10.0.2.3".parse().unwrap()], &[upstream]).unwrap();
+        let addr = NAMESERVER.parse().unwrap();
+        assert_eq!(proxy.streams(), vec![addr]);
+        let mut buf = [0; 512];
+
+        proxy.stream_open(addr, GUEST.parse().unwrap(), 0);
+        let mut msg = vec![0, 33];
+        msg.extend(query(7, "mirror.internal", TYPE_A));
+        // in two parts
+        proxy.stream_input(addr, &msg[..10], 0);
+        proxy.stream_input(addr, &msg[10..], 0);
+        assert_eq!(proxy.pending.len(), 1);
+        assert_eq!(proxy.stream_output(addr, &mut buf), 0);
+
+        let answer = wait_output(&mut proxy, addr);
+        thread.join().unwrap();
+        let resp = Response::parse(message(&answer).unwrap()).unwrap();
+        assert_eq!(resp.id, 7);
+        assert_eq!(resp.answers[0].data, vec![192, 168, 1, 10]);
+
+        // from the cache, as much as the guest takes
+        proxy.stream_input(addr, &msg, 0);
+        assert_eq!(proxy.stream_output(addr, &mut buf[..10]), 10);
+        let len = proxy.stream_output(addr, &mut buf[10..]);
+        assert_eq!(&buf[..10 + len], &answer[..]);
+
+        // a new connection drops the answers to the previous one
+        proxy.stream_input(addr, &msg, 0);
+        proxy.stream_open(addr, GUEST.parse().unwrap(), 0);
+        assert_eq!(proxy.stream_output(addr, &mut buf), 0);
+    }
+
+    #[test]
+    fn failover() {
+        let servers = [
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        ];
+        let mut proxy = Proxy::new(&["10.0.2.3".parse().unwrap()], &servers).unwrap();
+        let mut reply = Vec::new();
+
+        let (verdict, _) = ask(&mut proxy, 7, "example.com");
+        assert_eq!(verdict, Verdict::Drop);
+        assert_eq!(proxy.pending.len(), 1);
+        assert_eq!(proxy.deadline(), Some(TIMEOUT));
+
+        proxy.expire(0, &mut reply);
+        assert_eq!((proxy.pending.len(), proxy.current), (1, 0));
+        proxy.expire(TIMEOUT, &mut reply);
+        assert_eq!((proxy.pending.len(), proxy.current), (0, 1));
+        assert!(reply.is_empty());
+
+        let err = Proxy::new(&["10.0.2.3".parse().unwrap()], &[])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
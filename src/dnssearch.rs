@@ -0,0 +1,135 @@
+//! The DNS search list, sent by the DHCP server as option 119 (RFC 3397).
+
+use std::collections::HashMap;
+
+// from RFC 1035
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+// a DHCP option is at most 255 bytes, a longer one is split (RFC 3396)
+const MAX_OPT_LEN: usize = 255;
+const OPT_HEADER_LEN: usize = 2;
+
+/// Convert a search domain to ASCII (with IDNA, for Unicode names), and
+/// check it is a valid host name.
+pub fn to_ascii(domain: &str) -> Result<String, String> {
+    let name = domain.strip_suffix('.').unwrap_or(domain);
+    let name =
+        idna::domain_to_ascii(name).map_err(|_| format!("invalid IDNA name '{}'", domain))?;
+
+    if name.is_empty() {
+        return Err("empty domain".to_string());
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(format!(
+                "label '{}' of '{}' must have 1 to {} characters",
+                label, domain, MAX_LABEL_LEN
+            ));
+        }
+        if label.starts_with('-')
+            || label.ends_with('-')
+            || !label
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'-')
+        {
+            return Err(format!("invalid label '{}' of '{}'", label, domain));
+        }
+    }
+    if wire_len(&name) > MAX_NAME_LEN {
+        return Err(format!(
+            "'{}' is longer than {} bytes on the wire",
+            domain, MAX_NAME_LEN
+        ));
+    }
+
+    Ok(name)
+}
+
+// the length of the labels, and the root
+fn wire_len(name: &str) -> usize {
+    name.split('.').map(|l| l.len() + 1).sum::<usize>() + 1
+}
+
+/// The data of the DHCP option carrying `domains` (already converted to
+/// ASCII), with the names compressed as in a DNS message.
+pub fn encode<S: AsRef<str>>(domains: &[S]) -> Vec<u8> {
+    // the name suffixes already encoded, which can be pointed to
+    let mut suffixes = HashMap::new();
+    let mut data = Vec::new();
+
+    for domain in domains {
+        let labels: Vec<_> = domain.as_ref().split('.').collect();
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".");
+            if let Some(&pos) = suffixes.get(&suffix) {
+                data.extend_from_slice(&(0xc000 | pos as u16).to_be_bytes());
+                break;
+            }
+            suffixes.insert(suffix, data.len());
+            data.push(labels[i].len() as u8);
+            data.extend_from_slice(labels[i].as_bytes());
+            if i == labels.len() - 1 {
+                data.push(0);
+            }
+        }
+    }
+    data
+}
+
+/// The size of the DHCP option carrying `domains` (already converted to
+/// ASCII), and the headers of the options it is split into.
+pub fn encoded_len<S: AsRef<str>>(domains: &[S]) -> usize {
+    let len = encode(domains).len();
+    if len == 0 {
+        return 0;
+    }
+    len + OPT_HEADER_LEN * ((len + MAX_OPT_LEN - 1) / MAX_OPT_LEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii() {
+        assert_eq!(to_ascii("Example.COM."), Ok("example.com".to_string()));
+        assert_eq!(
+            to_ascii("bücher.example"),
+            Ok("xn--bcher-kva.example".to_string())
+        );
+        assert!(to_ascii("").is_err());
+        assert!(to_ascii("a..example").is_err());
+        assert!(to_ascii("-a.example").is_err());
+        assert!(to_ascii("a_b.example").is_err());
+        assert!(to_ascii(&format!("{}.example", "a".repeat(64))).is_err());
+        assert!(to_ascii(&vec!["a".repeat(63); 4].join(".")).is_err());
+        assert!(to_ascii(&vec!["a".repeat(62); 4].join(".")).is_ok());
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(
+            super::encode(&["example.com", "eng.example.com", "org"]),
+            b"\x07example\x03com\x00\x03eng\xc0\x00\x03org\x00".to_vec()
+        );
+    }
+
+    #[test]
+    fn len() {
+        assert_eq!(encoded_len::<&str>(&[]), 0);
+        // 3example3com0
+        assert_eq!(encoded_len(&["example.com"]), 2 + 13);
+        // 3eng + pointer
+        assert_eq!(
+            encoded_len(&["example.com", "eng.example.com"]),
+            2 + 13 + 4 + 2
+        );
+        // 3org0, 7example + pointer
+        assert_eq!(encoded_len(&["com", "org", "example.org"]), 2 + 5 + 5 + 10);
+
+        let domains: Vec<_> = (0..50).map(|i| format!("d{:02}.example", i)).collect();
+        // 3d00 7example 0, then 3dNN + pointer, split in 2 options
+        let len = 13 + 49 * 6;
+        assert_eq!(encoded_len(&domains), len + 2 * 2);
+    }
+}
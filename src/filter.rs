@@ -0,0 +1,83 @@
+//! Processing of the guest frames in Rust, around libslirp.
+//!
+//! The filters added to a [`Context`](crate::Context) see the frames from
+//! the guest before they are passed to libslirp, and the frames to the
+//! guest before they are sent by the [`Handler`](crate::Handler), in the
+//! order they were added. They are also given the time of the frames, from
+//! [`Handler::clock_get_ns`](crate::Handler::clock_get_ns).
+//!
+//! A filter may also have its own sockets, which the event loop polls for
+//! reading, such as the upstream sockets of a DNS proxy, and a deadline,
+//! by which the event loop lets it expire its state.
+//!
+//! The TCP connections of the guest to the addresses of its `streams` are
+//! left to libslirp, which passes their data to the filter through guest
+//! forwards, and sends back what the filter outputs.
+
+use std::net::{SocketAddr, SocketAddrV4};
+use std::os::unix::io::RawFd;
+
+/// What to do with a frame once a filter has seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Pass it on, to the next filter and then libslirp or the guest.
+    Pass,
+    /// The filter took care of it: it goes no further.
+    Drop,
+}
+
+pub trait Filter {
+    /// A frame from the guest. Frames to send back to the guest, such as an
+    /// answer, are pushed to `reply`.
+    fn input(&mut self, _frame: &[u8], _now: i64, _reply: &mut Vec<Vec<u8>>) -> Verdict {
+        Verdict::Pass
+    }
+
+    /// A frame to the guest, which may be modified.
+    fn output(&mut self, _frame: &mut Vec<u8>, _now: i64) -> Verdict {
+        Verdict::Pass
+    }
+
+    /// The non-blocking file descriptors to poll for reading.
+    fn fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    /// The file descriptors of `fds` to poll for writing as well, such as a
+    /// connecting socket.
+    fn write_fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    /// A file descriptor of `fds` is readable, or writable if it is in
+    /// `write_fds`. Frames to send to the guest are pushed to `reply`.
+    fn ready(&mut self, _fd: RawFd, _now: i64, _reply: &mut Vec<Vec<u8>>) {}
+
+    /// The time at which `timeout` is to be called, on the clock of `now`.
+    fn deadline(&self) -> Option<i64> {
+        None
+    }
+
+    /// The `deadline` has passed. Frames to send to the guest are pushed to
+    /// `reply`.
+    fn timeout(&mut self, _now: i64, _reply: &mut Vec<Vec<u8>>) {}
+
+    /// The addresses of the virtual network whose TCP connections the
+    /// filter serves, read once when it is added.
+    fn streams(&self) -> Vec<SocketAddrV4> {
+        Vec::new()
+    }
+
+    /// The guest opens a connection to `addr` from `guest`. libslirp sends
+    /// the output to the latest one, which replaces the previous ones.
+    fn stream_open(&mut self, _addr: SocketAddrV4, _guest: SocketAddr, _now: i64) {}
+
+    /// Data of the guest connection to `addr`.
+    fn stream_input(&mut self, _addr: SocketAddrV4, _data: &[u8], _now: i64) {}
+
+    /// Fill `buf` with the data to send over the guest connection to `addr`,
+    /// as much as it takes, returning its length.
+    fn stream_output(&mut self, _addr: SocketAddrV4, _buf: &mut [u8]) -> usize {
+        0
+    }
+}
@@ -1,9 +1,23 @@
+pub mod conntrack;
 pub mod context;
+pub mod dhcp;
+pub mod dhcpv6;
+pub mod dns;
+pub mod dnssearch;
+pub mod filter;
 pub mod mio;
 pub mod opt;
+pub mod packet;
+pub mod ra;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod sandbox;
+pub mod tftp;
+pub mod transport;
 pub mod version;
 
-pub use self::context::{Context, Handler, PollEvents};
+pub use self::context::{Config, Context, Handler, PollEvents, SetupError};
+pub use self::filter::{Filter, Verdict};
 pub use self::mio::*;
 pub use self::opt::*;
+pub use self::transport::Transport;
 pub use self::version::{state_version, version};
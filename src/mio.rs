@@ -1,5 +1,9 @@
-use crate::context::{Context, Handler, PollEvents};
+use crate::conntrack::ConnectionEvent;
+use crate::context::{Context, Handler, PollEvents, SetupError};
+use crate::dhcp::Leases;
+use crate::filter::Filter;
 use crate::opt::Opt;
+use crate::transport::{Datagram, Transport};
 
 use mio::unix::{EventedFd, UnixReady};
 use mio::*;
@@ -7,11 +11,10 @@ use mio_extras::timer::Timer as MioTimer;
 use slab::Slab;
 use std::cell::RefCell;
 use std::fmt;
-use std::fs::File;
 use std::io;
-use std::io::prelude::*;
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::io::RawFd;
 use std::rc::Rc;
+use std::sync::mpsc::Receiver;
 use std::time::{Duration, Instant};
 
 struct MyTimer {
@@ -46,15 +49,41 @@ impl MyFd {
 enum MyToken {
     Fd(MyFd),
     Timer(MyTimer),
+    // a socket of a filter
+    Filter(RawFd),
 }
 
 struct Inner<'a> {
     start: Instant,
-    stream: File,
+    transport: Box<dyn Transport>,
+    writable: bool,
     poll: &'a Poll,
     tokens: Slab<MyToken>,
 }
 
+impl<'a> Inner<'a> {
+    fn update_interest(&mut self) -> io::Result<()> {
+        let writable = self.transport.wants_write();
+        if writable == self.writable {
+            return Ok(());
+        }
+
+        let mut ready = Ready::readable();
+        if writable {
+            ready.insert(Ready::writable());
+        }
+        self.poll.reregister(
+            &EventedFd(&self.transport.as_raw_fd()),
+            SOCKET,
+            ready,
+            PollOpt::level(),
+        )?;
+        self.writable = writable;
+
+        Ok(())
+    }
+}
+
 pub struct MioHandler<'a> {
     inner: Rc<RefCell<Inner<'a>>>,
     ctxt: Context<Rc<RefCell<Inner<'a>>>>,
@@ -109,7 +138,9 @@ impl<'a> Handler for Inner<'a> {
     }
 
     fn send_packet(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.stream.write(buf)
+        let len = self.transport.send(buf)?;
+        self.update_interest()?;
+        Ok(len)
     }
 
     fn guest_error(&mut self, msg: &str) {
@@ -173,6 +204,50 @@ mod tests {
         // bug, see https://github.com/carllerche/mio/pull/897
         assert!(!ev.is_writable());
     }
+
+    #[test]
+    fn frame_too_large() {
+        use crate::transport::Stream;
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let poll = Poll::new().unwrap();
+        let transport = Box::new(Stream::new(a).unwrap());
+        let mut slirp = MioHandler::with_transport(&Opt::default(), &poll, transport).unwrap();
+
+        let len = 70_000u32;
+        b.write_all(&len.to_be_bytes()).unwrap();
+        b.write_all(&vec![0; len as usize]).unwrap();
+
+        // who-has 10.0.2.2 tell 10.0.2.15
+        let mac = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x0f];
+        let mut arp = vec![0xff; 6];
+        arp.extend_from_slice(&mac);
+        arp.extend_from_slice(&[0x08, 0x06, 0, 1, 0x08, 0, 6, 4, 0, 1]);
+        arp.extend_from_slice(&mac);
+        arp.extend_from_slice(&[10, 0, 2, 15, 0, 0, 0, 0, 0, 0, 10, 0, 2, 2]);
+        b.write_all(&(arp.len() as u32).to_be_bytes()).unwrap();
+        b.write_all(&arp).unwrap();
+
+        let mut events = Events::with_capacity(16);
+        b.set_nonblocking(true).unwrap();
+        let mut reply = Vec::new();
+        let start = Instant::now();
+        while reply.len() < 4 + 42 && start.elapsed() < Duration::from_secs(5) {
+            poll.poll(&mut events, Some(Duration::from_millis(100)))
+                .unwrap();
+            slirp.dispatch(&events).unwrap();
+            let mut buf = [0; 128];
+            if let Ok(n) = b.read(&mut buf) {
+                reply.extend_from_slice(&buf[..n]);
+            }
+        }
+        // is-at, from the host
+        assert_eq!(&reply[4 + 12..4 + 14], &[0x08, 0x06]);
+        assert_eq!(&reply[4 + 20..4 + 22], &[0, 2]);
+        assert_eq!(&reply[4 + 28..4 + 32], &[10, 0, 2, 2]);
+    }
 }
 
 fn from_mio_ready(ready: mio::Ready) -> PollEvents {
@@ -203,46 +278,99 @@ fn from_mio_ready(ready: mio::Ready) -> PollEvents {
 const SOCKET: Token = Token(10_000_000);
 
 impl<'a> MioHandler<'a> {
-    pub fn new(opt: &Opt, poll: &'a Poll, fd: RawFd) -> Self {
-        poll.register(&EventedFd(&fd), SOCKET, Ready::readable(), PollOpt::level())
-            .unwrap();
+    /// Exchange the frames with the guest over `fd`, a datagram socket or
+    /// a TAP device. It is owned by the handler, and made non-blocking.
+    pub fn new(opt: &Opt, poll: &'a Poll, fd: RawFd) -> io::Result<Self> {
+        let transport = unsafe { Datagram::from_fd(fd)? };
+        Self::with_transport(opt, poll, Box::new(transport))
+    }
+
+    pub fn with_transport(
+        opt: &Opt,
+        poll: &'a Poll,
+        transport: Box<dyn Transport>,
+    ) -> io::Result<Self> {
+        poll.register(
+            &EventedFd(&transport.as_raw_fd()),
+            SOCKET,
+            Ready::readable(),
+            PollOpt::level(),
+        )?;
 
         let inner = Rc::new(RefCell::new(Inner {
             start: Instant::now(),
             poll,
-            stream: unsafe { File::from_raw_fd(fd) },
+            transport,
+            writable: false,
             tokens: Slab::with_capacity(1024),
         }));
 
-        Self {
+        Ok(Self {
             inner: inner.clone(),
-            ctxt: Context::new_with_opt(opt, inner.clone()),
-        }
+            ctxt: Context::new_with_opt(opt, inner.clone())?,
+        })
+    }
+
+    /// Add a filter of the frames exchanged with the guest.
+    pub fn add_filter(&mut self, filter: Box<dyn Filter>) -> io::Result<()> {
+        self.ctxt.add_filter(filter)
+    }
+
+    /// Track the connections of the guest, and receive their events.
+    pub fn connection_events(&mut self) -> Receiver<ConnectionEvent> {
+        self.ctxt.connection_events()
+    }
+
+    /// The parts of the options that failed to be set up.
+    pub fn setup_errors(&self) -> &[SetupError] {
+        self.ctxt.setup_errors()
+    }
+
+    /// The leases of the DHCP server in Rust, if it replaces the one of
+    /// libslirp.
+    pub fn dhcp_leases(&self) -> Option<&Leases> {
+        self.ctxt.dhcp_leases()
     }
 
     pub fn dispatch(&mut self, events: &Events) -> io::Result<Option<Duration>> {
         let inner = self.inner.clone();
 
         for (_, token) in inner.borrow().tokens.iter() {
-            if let MyToken::Fd(fd) = token {
-                let ev = EventedFd(&fd.fd);
-                inner.borrow().poll.deregister(&ev)?;
-            }
+            let fd = match token {
+                MyToken::Fd(fd) => fd.fd,
+                MyToken::Filter(fd) => *fd,
+                MyToken::Timer(_) => continue,
+            };
+            inner.borrow().poll.deregister(&EventedFd(&fd))?;
         }
 
+        let mut filter_fds = Vec::new();
+
         for event in events {
             match event.token() {
                 SOCKET => {
-                    const NET_BUFSIZE: usize = 4096 + 65536; // defined by Emu
+                    // defined by Emu, and larger than the largest MRU of libslirp
+                    const NET_BUFSIZE: usize = 4096 + 65536;
                     let mut buffer = [0; NET_BUFSIZE];
 
-                    let len = self
-                        .inner
-                        .borrow_mut()
-                        .stream
-                        .read(&mut buffer[..])
-                        .unwrap();
-                    self.ctxt.input(&buffer[..len]);
+                    if event.readiness().is_writable() {
+                        let mut inner = inner.borrow_mut();
+                        inner.transport.flush()?;
+                        inner.update_interest()?;
+                    }
+
+                    while event.readiness().is_readable() {
+                        let len = inner.borrow_mut().transport.recv(&mut buffer[..]);
+                        match len {
+                            Ok(Some(len)) => self.ctxt.input(&buffer[..len]),
+                            Ok(None) => break,
+                            // a malformed frame was dropped, the stream is still usable
+                            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                                inner.borrow_mut().guest_error(&e.to_string())
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
                 }
                 i => {
                     let events = from_mio_ready(event.readiness());
@@ -260,11 +388,17 @@ impl<'a> MioHandler<'a> {
                             let func = &mut **func.borrow_mut();
                             func();
                         }
+                        MyToken::Filter(fd) => filter_fds.push(*fd),
                     }
                 }
             }
         }
 
+        for fd in filter_fds {
+            self.ctxt.filter_ready(fd);
+        }
+        let filter_timeout = self.ctxt.filter_timeout();
+
         self.ctxt.pollfds_poll(false, |idx| {
             let token = &mut inner.borrow_mut().tokens[idx as usize];
             if let MyToken::Fd(fd) = token {
@@ -274,10 +408,26 @@ impl<'a> MioHandler<'a> {
             }
         });
 
-        inner
-            .borrow_mut()
-            .tokens
-            .retain(|_, v| if let MyToken::Fd(_) = v { false } else { true });
+        inner.borrow_mut().tokens.retain(|_, v| {
+            if let MyToken::Timer(_) = v {
+                true
+            } else {
+                false
+            }
+        });
+
+        let write_fds = self.ctxt.filter_write_fds();
+        for fd in self.ctxt.filter_fds() {
+            let mut ready = Ready::readable();
+            if write_fds.contains(&fd) {
+                ready |= Ready::writable();
+            }
+            let mut inner = inner.borrow_mut();
+            let tok = inner.tokens.insert(MyToken::Filter(fd));
+            inner
+                .poll
+                .register(&EventedFd(&fd), Token(tok), ready, PollOpt::level())?;
+        }
 
         let mut timeout = 0;
         self.ctxt.pollfds_fill(&mut timeout, |fd, events| {
@@ -303,6 +453,9 @@ impl<'a> MioHandler<'a> {
             Some(Duration::from_millis(timeout as u64))
         };
 
-        Ok(duration)
+        Ok(match (duration, filter_timeout) {
+            (Some(d), Some(f)) => Some(d.min(f)),
+            (d, f) => d.or(f),
+        })
     }
 }
@@ -1,54 +1,568 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use crate::dnssearch;
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
+use std::str::FromStr;
+#[cfg(feature = "config")]
+use std::{fs, io, path::Path};
+use structopt::clap::ArgMatches;
 use structopt::StructOpt;
 
+// (de)serialize as a string, with FromStr and Display
+macro_rules! impl_serde_str {
+    ($ty:ty) => {
+        #[cfg(feature = "serde")]
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+/// An IPv4 network, in CIDR notation (such as 10.0.2.0/24).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ipv4Cidr {
+    pub addr: Ipv4Addr,
+    pub len: u8,
+}
+
+/// An IPv6 network, in CIDR notation (such as fd00::/64).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ipv6Cidr {
+    pub addr: Ipv6Addr,
+    pub len: u8,
+}
+
+macro_rules! impl_cidr {
+    ($cidr:ty, $max:expr) => {
+        impl FromStr for $cidr {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let mut parts = s.splitn(2, '/');
+                let addr = parts.next().unwrap();
+                let len = parts
+                    .next()
+                    .ok_or_else(|| format!("Missing prefix length in '{}'", s))?;
+                let addr = addr
+                    .parse()
+                    .map_err(|e| format!("Invalid address '{}': {}", addr, e))?;
+                let len = len
+                    .parse()
+                    .ok()
+                    .filter(|&len| len <= $max)
+                    .ok_or_else(|| format!("Invalid prefix length '{}'", len))?;
+                Ok(Self { addr, len })
+            }
+        }
+
+        impl fmt::Display for $cidr {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}/{}", self.addr, self.len)
+            }
+        }
+
+        impl_serde_str!($cidr);
+    };
+}
+
+impl_cidr!(Ipv4Cidr, 32);
+impl_cidr!(Ipv6Cidr, 128);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// A host port forwarded to the guest, in QEMU syntax:
+/// `[tcp|udp]:[hostaddr]:hostport-[guestaddr]:guestport`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostFwd {
+    pub protocol: Protocol,
+    /// The host address to listen on, any if unset.
+    pub host_addr: Option<Ipv4Addr>,
+    pub host_port: u16,
+    /// The guest address, the first DHCP address if unset.
+    pub guest_addr: Option<Ipv4Addr>,
+    pub guest_port: u16,
+}
+
+impl FromStr for HostFwd {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |what: &str| format!("Invalid {} in host forward '{}'", what, s);
+        let addr = |addr: &str| match addr {
+            "" => Ok(None),
+            addr => addr.parse().map(Some).map_err(|_| err("address")),
+        };
+        let port = |port: &str| port.parse().map_err(|_| err("port"));
+
+        let mut parts = s.splitn(2, ':');
+        let protocol = match parts.next().unwrap() {
+            "" | "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => return Err(err("protocol")),
+        };
+        let mut parts = parts.next().ok_or_else(|| err("syntax"))?.splitn(2, '-');
+        let host = parts.next().unwrap();
+        let guest = parts.next().ok_or_else(|| err("syntax"))?;
+
+        let host = host.rsplitn(2, ':').collect::<Vec<_>>();
+        let guest = guest.rsplitn(2, ':').collect::<Vec<_>>();
+        if host.len() != 2 || guest.len() != 2 {
+            return Err(err("syntax"));
+        }
+
+        Ok(Self {
+            protocol,
+            host_addr: addr(host[1])?,
+            host_port: port(host[0])?,
+            guest_addr: addr(guest[1])?,
+            guest_port: port(guest[0])?,
+        })
+    }
+}
+
+impl fmt::Display for HostFwd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addr = |addr: Option<Ipv4Addr>| addr.map_or(String::new(), |a| a.to_string());
+        write!(
+            f,
+            "{}:{}:{}-{}:{}",
+            self.protocol,
+            addr(self.host_addr),
+            self.host_port,
+            addr(self.guest_addr),
+            self.guest_port
+        )
+    }
+}
+
+impl_serde_str!(HostFwd);
+
+/// A name the DNS queries of the guest are answered for: `name=address`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsHost {
+    pub name: String,
+    pub addr: IpAddr,
+}
+
+impl FromStr for DnsHost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap();
+        let addr = parts
+            .next()
+            .ok_or_else(|| format!("Missing address in DNS host '{}'", s))?;
+
+        Ok(Self {
+            name: dnssearch::to_ascii(name)?,
+            addr: addr
+                .parse()
+                .map_err(|e| format!("Invalid address '{}': {}", addr, e))?,
+        })
+    }
+}
+
+impl fmt::Display for DnsHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.addr)
+    }
+}
+
+impl_serde_str!(DnsHost);
+
+/// A DNS server to forward the guest queries to: an address, with an
+/// optional port (such as 127.0.0.53 or [::1]:5353).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DnsUpstream(pub SocketAddr);
+
+impl FromStr for DnsUpstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<IpAddr>() {
+            return Ok(DnsUpstream(SocketAddr::new(addr, 53)));
+        }
+        s.parse()
+            .map(DnsUpstream)
+            .map_err(|_| format!("Invalid DNS server '{}'", s))
+    }
+}
+
+impl fmt::Display for DnsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl_serde_str!(DnsUpstream);
+
+// the options of a list of IPv4 addresses, such as the NTP servers (42)
+const DHCP_ADDR_OPTIONS: &[u8] = &[
+    1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 28, 41, 42, 44, 45, 48, 49, 65, 69, 70, 71, 72, 73, 74, 75, 76,
+];
+// the options of a 16 bits integer, such as the interface MTU (26)
+const DHCP_U16_OPTIONS: &[u8] = &[22, 26, 57];
+// the options of a 32 bits integer, such as the lease time (51)
+const DHCP_U32_OPTIONS: &[u8] = &[24, 35, 38, 51, 58, 59];
+// the classless static routes (RFC 3442)
+const DHCP_ROUTES_OPTION: u8 = 121;
+
+/// An extra option of the DHCP replies: `code=value`.
+///
+/// The value is in hexadecimal if it starts with `0x` (such as the
+/// vendor-specific information of the option 43). Otherwise, its format
+/// depends on the option:
+///
+/// - addresses, such as `42=10.0.2.2,10.0.2.3` for the NTP servers
+/// - integers, such as `26=1400` for the interface MTU
+/// - routes, such as `121=10.1.0.0/16-10.0.2.2,0.0.0.0/0-10.0.2.2`
+/// - text, for the other options
+#[derive(Debug, Clone, PartialEq)]
+pub struct DhcpOption {
+    pub code: u8,
+    pub data: Vec<u8>,
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let s: String = s.chars().filter(|c| *c != ':').collect();
+    // before slicing the string by bytes
+    if !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hexadecimal '{}'", s));
+    }
+    if s.len() % 2 != 0 {
+        return Err(format!("Odd number of hexadecimal digits in '{}'", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("Invalid hexadecimal '{}'", s))
+        })
+        .collect()
+}
+
+fn parse_route(s: &str, data: &mut Vec<u8>) -> Result<(), String> {
+    let mut parts = s.splitn(2, '-');
+    let dest: Ipv4Cidr = parts.next().unwrap().trim().parse()?;
+    let gw = parts
+        .next()
+        .ok_or_else(|| format!("Missing router in route '{}'", s))?;
+    let gw: Ipv4Addr = gw
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid router '{}': {}", gw, e))?;
+    data.push(dest.len);
+    // the significant octets of the destination
+    let octets = (usize::from(dest.len) + 7) / 8;
+    data.extend_from_slice(&dest.addr.octets()[..octets]);
+    data.extend_from_slice(&gw.octets());
+    Ok(())
+}
+
+impl FromStr for DhcpOption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let code = parts.next().unwrap();
+        let value = parts
+            .next()
+            .ok_or_else(|| format!("Missing value in DHCP option '{}'", s))?;
+        let code: u8 = code
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&c| c != 0 && c != 255)
+            .ok_or_else(|| format!("Invalid DHCP option code '{}'", code))?;
+
+        let mut data = Vec::new();
+        if value.starts_with("0x") {
+            data = parse_hex(&value[2..])?;
+        } else if DHCP_ADDR_OPTIONS.contains(&code) {
+            for addr in parse_list::<Ipv4Addr>(value)? {
+                data.extend_from_slice(&addr.octets());
+            }
+        } else if DHCP_U16_OPTIONS.contains(&code) {
+            data.extend_from_slice(&parse::<u16>(value)?.to_be_bytes());
+        } else if DHCP_U32_OPTIONS.contains(&code) {
+            data.extend_from_slice(&parse::<u32>(value)?.to_be_bytes());
+        } else if code == DHCP_ROUTES_OPTION {
+            for route in value.split(',').filter(|r| !r.trim().is_empty()) {
+                parse_route(route, &mut data)?;
+            }
+        } else {
+            data = value.as_bytes().to_vec();
+        }
+        if data.is_empty() {
+            return Err(format!("Empty DHCP option '{}'", s));
+        }
+
+        Ok(Self { code, data })
+    }
+}
+
+impl fmt::Display for DhcpOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}=0x", self.code)?;
+        for b in &self.data {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl_serde_str!(DhcpOption);
+
+/// A fixed address of a guest, by its MAC address: `52:54:00:12:34:56=10.0.2.20`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DhcpReservation {
+    pub mac: [u8; 6],
+    pub addr: Ipv4Addr,
+}
+
+impl FromStr for DhcpReservation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let mac = parts.next().unwrap();
+        let addr = parts
+            .next()
+            .ok_or_else(|| format!("Missing address in DHCP reservation '{}'", s))?;
+
+        let octets = mac
+            .trim()
+            .split(&[':', '-'][..])
+            .map(|o| u8::from_str_radix(o, 16).ok().filter(|_| o.len() == 2))
+            .collect::<Option<Vec<_>>>()
+            .filter(|o| o.len() == 6)
+            .ok_or_else(|| format!("Invalid MAC address '{}'", mac))?;
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&octets);
+
+        Ok(Self {
+            mac,
+            addr: addr
+                .trim()
+                .parse()
+                .map_err(|e| format!("Invalid address '{}': {}", addr, e))?,
+        })
+    }
+}
+
+impl fmt::Display for DhcpReservation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self.mac;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}={}",
+            m[0], m[1], m[2], m[3], m[4], m[5], self.addr
+        )
+    }
+}
+
+impl_serde_str!(DhcpReservation);
+
 #[derive(Debug, StructOpt)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
 pub struct OptIpv4 {
     /// Whether to disable IPv4
     #[structopt(name = "disable-ipv4", long = "disable-ipv4")]
     pub disable: bool,
-    /// IP network address that the guest will see
-    #[structopt(long, short, default_value = "10.0.2.0")]
-    pub net: Ipv4Addr,
-    /// IP network mask
-    #[structopt(long, short, default_value = "255.255.255.0")]
-    pub mask: Ipv4Addr,
-    /// Guest-visible address of the host
-    #[structopt(long, default_value = "10.0.2.2")]
-    pub host: Ipv4Addr,
-    /// The first of the 16 IPs the built-in DHCP server can assign
-    #[structopt(
-        name = "dhcp-start",
-        long = "dhcp-start",
-        short,
-        default_value = "10.0.2.15"
-    )]
-    pub dhcp_start: Ipv4Addr,
-    /// Guest-visible address of the virtual nameserver
-    #[structopt(long, default_value = "10.0.2.3")]
-    pub dns: Ipv4Addr,
+    /// IP network that the guest will see, in CIDR notation (instead of --net and --mask)
+    #[structopt(long)]
+    pub cidr: Option<Ipv4Cidr>,
+    /// IP network address that the guest will see [default: 10.0.2.0]
+    #[structopt(long, short)]
+    pub net: Option<Ipv4Addr>,
+    /// IP network mask [default: 255.255.255.0]
+    #[structopt(long, short)]
+    pub mask: Option<Ipv4Addr>,
+    /// Guest-visible address of the host [default: .2 of the network]
+    #[structopt(long)]
+    pub host: Option<Ipv4Addr>,
+    /// The first of the IPs the built-in DHCP server can assign [default: .15 of the network]
+    #[structopt(name = "dhcp-start", long = "dhcp-start", short)]
+    pub dhcp_start: Option<Ipv4Addr>,
+    /// Number of IPs the DHCP server can assign, served from Rust unless 16 [default: 16]
+    #[structopt(name = "dhcp-pool-size", long = "dhcp-pool-size")]
+    pub dhcp_pool_size: Option<u32>,
+    /// Assign a fixed IP to a guest, with the DHCP server in Rust: mac=address
+    #[structopt(name = "dhcp-reserve", long = "dhcp-reserve")]
+    pub dhcp_reservations: Vec<DhcpReservation>,
+    /// Guest-visible address of the virtual nameserver [default: .3 of the network]
+    #[structopt(long)]
+    pub dns: Option<Ipv4Addr>,
+}
+
+impl OptIpv4 {
+    pub fn mask(&self) -> Ipv4Addr {
+        match self.cidr {
+            Some(cidr) => Ipv4Addr::from(
+                u32::max_value()
+                    .checked_shl(32 - u32::from(cidr.len))
+                    .unwrap_or(0),
+            ),
+            None => self.mask.unwrap_or(Ipv4Addr::new(255, 255, 255, 0)),
+        }
+    }
+
+    pub fn net(&self) -> Ipv4Addr {
+        let net = match self.cidr {
+            Some(cidr) => cidr.addr,
+            None => self.net.unwrap_or(Ipv4Addr::new(10, 0, 2, 0)),
+        };
+        Ipv4Addr::from(u32::from(net) & u32::from(self.mask()))
+    }
+
+    /// The network, in CIDR notation.
+    pub fn cidr(&self) -> Ipv4Cidr {
+        Ipv4Cidr {
+            addr: self.net(),
+            len: u32::from(self.mask()).count_ones() as u8,
+        }
+    }
+
+    fn nth(&self, n: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.net()).wrapping_add(n))
+    }
+
+    pub fn host(&self) -> Ipv4Addr {
+        self.host.unwrap_or_else(|| self.nth(2))
+    }
+
+    pub fn dhcp_start(&self) -> Ipv4Addr {
+        self.dhcp_start.unwrap_or_else(|| self.nth(15))
+    }
+
+    pub fn dhcp_pool_size(&self) -> u32 {
+        self.dhcp_pool_size.unwrap_or(NB_DHCP_ADDR)
+    }
+
+    /// Whether the DHCP server in Rust replaces the one of libslirp, for
+    /// the reservations or another pool size.
+    pub fn rust_dhcp(&self) -> bool {
+        self.dhcp_pool_size() != NB_DHCP_ADDR || !self.dhcp_reservations.is_empty()
+    }
+
+    pub fn dns(&self) -> Ipv4Addr {
+        self.dns.unwrap_or_else(|| self.nth(3))
+    }
 }
 
 #[derive(Debug, StructOpt)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
 pub struct OptIpv6 {
     /// Whether to disable IPv6
     #[structopt(name = "disable-ipv6", long = "disable-ipv6")]
     pub disable: bool,
-    /// IPv6 network prefix
-    #[structopt(long, default_value = "fec0::", long = "prefix-ipv6")]
-    pub prefix: Ipv6Addr,
-    /// IPv6 network prefix length
-    #[structopt(name = "length", long = "prefix-length-ipv6", default_value = "64")]
-    pub prefix_len: u8,
-    /// Guest-visible IPv6 address of the host
-    #[structopt(name = "host-ipv6", long = "host-ipv6", default_value = "fec0::2")]
-    pub host: Ipv6Addr,
-    /// Guest-visible address of the virtual nameserver
-    #[structopt(name = "dns-ipv6", long, default_value = "fec0::3")]
-    pub dns: Ipv6Addr,
+    /// IPv6 network prefix, in CIDR notation (instead of --prefix-ipv6 and --prefix-length-ipv6)
+    #[structopt(long)]
+    pub cidr6: Option<Ipv6Cidr>,
+    /// IPv6 network prefix [default: fec0::]
+    #[structopt(long = "prefix-ipv6")]
+    pub prefix: Option<Ipv6Addr>,
+    /// IPv6 network prefix length [default: 64]
+    #[structopt(name = "length", long = "prefix-length-ipv6")]
+    pub prefix_len: Option<u8>,
+    /// Guest-visible IPv6 address of the host [default: ::2 of the prefix]
+    #[structopt(name = "host-ipv6", long = "host-ipv6")]
+    pub host: Option<Ipv6Addr>,
+    /// Guest-visible address of the virtual nameserver [default: ::3 of the prefix]
+    #[structopt(name = "dns-ipv6", long)]
+    pub dns: Option<Ipv6Addr>,
+    /// MTU advertised in the router advertisements
+    #[structopt(name = "ra-mtu", long = "ra-mtu")]
+    pub ra_mtu: Option<u32>,
+    /// Router lifetime of the router advertisements, in seconds (0: not a default router)
+    #[structopt(name = "ra-router-lifetime", long = "ra-router-lifetime")]
+    pub ra_lifetime: Option<u16>,
+    /// Set the "managed address configuration" flag of the router advertisements
+    #[structopt(name = "ra-managed", long = "ra-managed")]
+    pub ra_managed: bool,
+    /// Set the "other configuration" flag of the router advertisements
+    #[structopt(name = "ra-other", long = "ra-other")]
+    pub ra_other: bool,
+    /// Assign the addresses ::10 to ::ff of the prefix with a DHCPv6 server in Rust, and set both flags of the router advertisements
+    #[structopt(long)]
+    pub dhcpv6: bool,
+    /// Boot file URL of the UEFI IPv6 PXE clients [default: TFTP URL of the UEFI or default boot file]
+    #[structopt(name = "dhcpv6-bootfile-url", long = "dhcpv6-bootfile-url")]
+    pub dhcpv6_bootfile_url: Option<String>,
+}
+
+impl OptIpv6 {
+    pub fn prefix_len(&self) -> u8 {
+        match self.cidr6 {
+            Some(cidr) => cidr.len,
+            None => self.prefix_len.unwrap_or(64),
+        }
+    }
+
+    pub(crate) fn mask(&self) -> u128 {
+        u128::max_value()
+            .checked_shl(128u32.saturating_sub(self.prefix_len().into()))
+            .unwrap_or(0)
+    }
+
+    pub fn prefix(&self) -> Ipv6Addr {
+        let prefix = match self.cidr6 {
+            Some(cidr) => cidr.addr,
+            None => self
+                .prefix
+                .unwrap_or(Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0)),
+        };
+        Ipv6Addr::from(u128::from(prefix) & self.mask())
+    }
+
+    fn nth(&self, n: u128) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.prefix()).wrapping_add(n))
+    }
+
+    pub fn host(&self) -> Ipv6Addr {
+        self.host.unwrap_or_else(|| self.nth(2))
+    }
+
+    pub fn dns(&self) -> Ipv6Addr {
+        self.dns.unwrap_or_else(|| self.nth(3))
+    }
 }
 
 #[derive(Debug, StructOpt)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
 pub struct OptTftp {
     /// RFC2132 "TFTP server name" string
     #[structopt(name = "name", long = "tftp-name")]
@@ -59,9 +573,32 @@ pub struct OptTftp {
     /// BOOTP filename, for use with tftp
     #[structopt(long = "tftp-bootfile")]
     pub bootfile: Option<String>,
+    /// BOOTP filename of the BIOS PXE clients
+    #[structopt(name = "tftp-bootfile-bios", long = "tftp-bootfile-bios")]
+    pub bootfile_bios: Option<String>,
+    /// BOOTP filename of the UEFI x86-64 PXE clients
+    #[structopt(name = "tftp-bootfile-uefi", long = "tftp-bootfile-uefi")]
+    pub bootfile_uefi: Option<String>,
+    /// BOOTP filename of the UEFI ARM64 PXE clients
+    #[structopt(name = "tftp-bootfile-arm64", long = "tftp-bootfile-arm64")]
+    pub bootfile_arm64: Option<String>,
+    /// BOOTP filename of the iPXE clients, to chainload
+    #[structopt(name = "tftp-bootfile-ipxe", long = "tftp-bootfile-ipxe")]
+    pub bootfile_ipxe: Option<String>,
+    /// Serve the TFTP root from Rust, with the blksize, tsize and timeout options
+    #[structopt(name = "tftp-server", long = "tftp-server")]
+    pub server: bool,
+    /// Serve the files of a tar archive over TFTP, instead of the root directory
+    #[structopt(name = "tftp-archive", parse(from_os_str), long = "tftp-archive")]
+    pub archive: Option<PathBuf>,
+    /// Log the transfers of the Rust TFTP server
+    #[structopt(name = "tftp-log", long = "tftp-log")]
+    pub log: bool,
 }
 
 #[derive(Debug, StructOpt)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
 #[structopt(name = "slirp-opt")]
 pub struct Opt {
     /// Isolate guest from host
@@ -77,6 +614,42 @@ pub struct Opt {
     /// Guest-visible domain name of the virtual nameserver from DHCP server
     #[structopt(long)]
     pub domainname: Option<String>,
+    /// Forward a host port to the guest: [tcp|udp]:[hostaddr]:hostport-[guestaddr]:guestport
+    #[structopt(long)]
+    pub hostfwd: Vec<HostFwd>,
+    /// Answer the guest DNS queries for a name with an address: name=address
+    #[structopt(name = "dns-host", long = "dns-host")]
+    pub dns_hosts: Vec<DnsHost>,
+    /// Answer the guest DNS queries for the names of a hosts file
+    #[structopt(name = "hosts-file", long = "hosts-file", parse(from_os_str))]
+    pub hosts_file: Option<PathBuf>,
+    /// Forward the guest DNS queries to a server, and cache the answers: address[:port]
+    #[structopt(name = "dns-upstream", long = "dns-upstream")]
+    pub dns_upstreams: Vec<DnsUpstream>,
+    /// Log the queries forwarded to the --dns-upstream servers
+    #[structopt(name = "dns-proxy-log", long = "dns-proxy-log")]
+    pub dns_proxy_log: bool,
+    /// Add an option to the DHCP replies, or replace one: code=value
+    #[structopt(name = "dhcp-option", long = "dhcp-option")]
+    pub dhcp_options: Vec<DhcpOption>,
+    /// Append the guest DNS queries and responses to a file, as JSON lines
+    #[structopt(name = "dns-log", long = "dns-log", parse(from_os_str))]
+    pub dns_log: Option<PathBuf>,
+    /// MTU of the link with the guest, advertised by DHCP [default: 1500]
+    #[structopt(long)]
+    pub mtu: Option<u32>,
+    /// Largest frame accepted from the guest, without its ethernet header [default: the MTU]
+    #[structopt(long)]
+    pub mru: Option<u32>,
+    /// Host address of the IPv4 connections of the guest
+    #[structopt(name = "outbound-addr", long = "outbound-addr")]
+    pub outbound_addr: Option<Ipv4Addr>,
+    /// Host address of the IPv6 connections of the guest
+    #[structopt(name = "outbound-addr6", long = "outbound-addr6")]
+    pub outbound_addr6: Option<Ipv6Addr>,
+    /// Host interface of the connections of the guest (SO_BINDTODEVICE)
+    #[structopt(name = "outbound-interface", long = "outbound-interface")]
+    pub outbound_interface: Option<String>,
 
     #[structopt(flatten)]
     pub ipv4: OptIpv4,
@@ -85,3 +658,1053 @@ pub struct Opt {
     #[structopt(flatten)]
     pub tftp: OptTftp,
 }
+
+macro_rules! impl_default {
+    ($($opt:ty),*) => {
+        $(impl Default for $opt {
+            fn default() -> Self {
+                Self::from_iter(&["slirp"])
+            }
+        })*
+    };
+}
+
+impl_default!(OptIpv4, OptIpv6, OptTftp, Opt);
+
+impl Opt {
+    /// Override the options with the ones given on the command line.
+    ///
+    /// `matches` are the command line arguments `cli` was parsed from: only
+    /// the options that were explicitly given are taken.
+    pub fn override_with(&mut self, cli: Opt, matches: &ArgMatches) {
+        macro_rules! take {
+            ($($field:ident).+, $name:expr) => {
+                if matches.occurrences_of($name) > 0 {
+                    self.$($field).+ = cli.$($field).+;
+                }
+            };
+        }
+
+        take!(restrict, "restrict");
+        take!(hostname, "hostname");
+        take!(dns_suffixes, "dns_suffixes");
+        take!(domainname, "domainname");
+        take!(hostfwd, "hostfwd");
+        take!(dns_hosts, "dns-host");
+        take!(hosts_file, "hosts-file");
+        take!(dns_upstreams, "dns-upstream");
+        take!(dns_proxy_log, "dns-proxy-log");
+        take!(dns_log, "dns-log");
+        take!(dhcp_options, "dhcp-option");
+        take!(mtu, "mtu");
+        take!(mru, "mru");
+        take!(outbound_addr, "outbound-addr");
+        take!(outbound_addr6, "outbound-addr6");
+        take!(outbound_interface, "outbound-interface");
+        take!(ipv4.disable, "disable-ipv4");
+        // the network of the lower layers is replaced, not completed
+        if matches.occurrences_of("cidr") > 0 {
+            self.ipv4.net = None;
+            self.ipv4.mask = None;
+        }
+        if matches.occurrences_of("net") > 0 || matches.occurrences_of("mask") > 0 {
+            self.ipv4.cidr = None;
+        }
+        take!(ipv4.cidr, "cidr");
+        take!(ipv4.net, "net");
+        take!(ipv4.mask, "mask");
+        take!(ipv4.host, "host");
+        take!(ipv4.dhcp_start, "dhcp-start");
+        take!(ipv4.dhcp_pool_size, "dhcp-pool-size");
+        take!(ipv4.dhcp_reservations, "dhcp-reserve");
+        take!(ipv4.dns, "dns");
+        take!(ipv6.disable, "disable-ipv6");
+        if matches.occurrences_of("cidr6") > 0 {
+            self.ipv6.prefix = None;
+            self.ipv6.prefix_len = None;
+        }
+        if matches.occurrences_of("prefix") > 0 || matches.occurrences_of("length") > 0 {
+            self.ipv6.cidr6 = None;
+        }
+        take!(ipv6.cidr6, "cidr6");
+        take!(ipv6.prefix, "prefix");
+        take!(ipv6.prefix_len, "length");
+        take!(ipv6.host, "host-ipv6");
+        take!(ipv6.dns, "dns-ipv6");
+        take!(ipv6.ra_mtu, "ra-mtu");
+        take!(ipv6.ra_lifetime, "ra-router-lifetime");
+        take!(ipv6.ra_managed, "ra-managed");
+        take!(ipv6.ra_other, "ra-other");
+        take!(ipv6.dhcpv6, "dhcpv6");
+        take!(ipv6.dhcpv6_bootfile_url, "dhcpv6-bootfile-url");
+        take!(tftp.name, "name");
+        take!(tftp.root, "root-path");
+        take!(tftp.bootfile, "bootfile");
+        take!(tftp.bootfile_bios, "tftp-bootfile-bios");
+        take!(tftp.bootfile_uefi, "tftp-bootfile-uefi");
+        take!(tftp.bootfile_arm64, "tftp-bootfile-arm64");
+        take!(tftp.bootfile_ipxe, "tftp-bootfile-ipxe");
+        take!(tftp.server, "tftp-server");
+        take!(tftp.archive, "tftp-archive");
+        take!(tftp.log, "tftp-log");
+    }
+}
+
+/// An inconsistency in the network parameters of [`Opt`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptError {
+    /// Both IPv4 and IPv6 are disabled.
+    NoNetwork,
+    /// Two options (named after their flag) set the same parameter.
+    Conflict(&'static str, &'static str),
+    /// The IPv4 mask isn't a contiguous run of leading ones.
+    MaskNotContiguous(Ipv4Addr),
+    /// An IPv4 address (named after its option) is outside of the network.
+    OutsideNetwork(&'static str, Ipv4Addr),
+    /// The addresses of the DHCP pool (its start and size) don't fit in
+    /// the network.
+    DhcpPoolOverflow(Ipv4Addr, u32),
+    /// The IPv6 prefix length is larger than 128.
+    PrefixLength(u8),
+    /// An IPv6 address (named after its option) is outside of the prefix.
+    OutsidePrefix(&'static str, Ipv6Addr),
+    /// An environment variable has an invalid value.
+    Env(String, String),
+    /// A DNS search domain is invalid.
+    DnsSuffix(String),
+    /// The DNS search option doesn't fit in the DHCP replies.
+    DnsSearchTooLong { len: usize, room: usize },
+    /// A boot file name (named after its option) doesn't fit in the BOOTP
+    /// header.
+    BootfileTooLong(&'static str),
+    /// The advertised IPv6 MTU is lower than the minimum of 1280.
+    Ipv6Mtu(u32),
+    /// The MTU or MRU (named after its option) is out of the range of
+    /// libslirp.
+    LinkMtu(&'static str, u32),
+}
+
+/// The environment variable of an option: `SLIRP_` and the option in
+/// uppercase, with '_' instead of '-'.
+pub fn env_var(option: &str) -> String {
+    format!("SLIRP_{}", option.to_uppercase().replace('-', "_"))
+}
+
+struct Flag(&'static str);
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "--{} ({})", self.0, env_var(self.0))
+    }
+}
+
+impl fmt::Display for OptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptError::NoNetwork => write!(f, "IPv4 and IPv6 can't both be disabled"),
+            OptError::Conflict(a, b) => {
+                write!(f, "{} can't be used with {}", Flag(a), Flag(b))
+            }
+            OptError::MaskNotContiguous(mask) => {
+                write!(f, "the network mask {} is not contiguous", mask)
+            }
+            OptError::OutsideNetwork(name, addr) => {
+                write!(f, "{} {} is outside of the IPv4 network", Flag(name), addr)
+            }
+            OptError::DhcpPoolOverflow(start, size) => write!(
+                f,
+                "the DHCP pool of {} addresses starting at {} overflows the IPv4 network",
+                size, start
+            ),
+            OptError::PrefixLength(len) => {
+                write!(f, "the IPv6 prefix length {} is larger than 128", len)
+            }
+            OptError::Env(var, e) => write!(f, "{}: {}", var, e),
+            OptError::DnsSuffix(e) => write!(f, "invalid DNS suffix: {}", e),
+            OptError::DnsSearchTooLong { len, room } => write!(
+                f,
+                "the DNS suffixes take {} bytes in DHCP replies, out of {} left",
+                len, room
+            ),
+            OptError::OutsidePrefix(name, addr) => {
+                write!(f, "{} {} is outside of the IPv6 prefix", Flag(name), addr)
+            }
+            OptError::BootfileTooLong(name) => write!(
+                f,
+                "{} is longer than the {} bytes of a BOOTP file name",
+                Flag(name),
+                MAX_BOOTFILE_LEN
+            ),
+            OptError::Ipv6Mtu(mtu) => {
+                write!(f, "the IPv6 MTU {} is lower than {}", mtu, MIN_IPV6_MTU)
+            }
+            OptError::LinkMtu(name, mtu) => write!(
+                f,
+                "{} {} is out of the range {} to {}",
+                Flag(name),
+                mtu,
+                MIN_MTU,
+                MAX_MTU
+            ),
+        }
+    }
+}
+
+impl Error for OptError {}
+
+// the size of the DHCP pool of libslirp
+const NB_DHCP_ADDR: u32 = 16;
+// the file field of BOOTP, without its terminating nul
+const MAX_BOOTFILE_LEN: usize = 127;
+// the minimum link MTU of IPv6 (RFC 8200)
+const MIN_IPV6_MTU: u32 = 1280;
+// the MTU and MRU of libslirp, by default and at most
+const DEFAULT_MTU: u32 = 1500;
+const MIN_MTU: u32 = 68;
+const MAX_MTU: u32 = 65521;
+
+impl OptIpv4 {
+    fn validate(&self, errors: &mut Vec<OptError>) {
+        if self.cidr.is_some() {
+            if self.net.is_some() {
+                errors.push(OptError::Conflict("cidr", "net"));
+            }
+            if self.mask.is_some() {
+                errors.push(OptError::Conflict("cidr", "mask"));
+            }
+        }
+
+        let mask = u32::from(self.mask());
+        // the host part must be of the form 2^n - 1
+        if !mask & (!mask).wrapping_add(1) != 0 {
+            errors.push(OptError::MaskNotContiguous(self.mask()));
+            return;
+        }
+
+        let net = u32::from(self.net());
+        let inside = |addr: u32| addr & mask == net && addr != net && addr != net | !mask;
+        for (name, addr) in &[
+            ("host", self.host()),
+            ("dhcp-start", self.dhcp_start()),
+            ("dns", self.dns()),
+        ] {
+            if !inside(u32::from(*addr)) {
+                errors.push(OptError::OutsideNetwork(name, *addr));
+            }
+        }
+
+        for r in &self.dhcp_reservations {
+            if !inside(u32::from(r.addr)) {
+                errors.push(OptError::OutsideNetwork("dhcp-reserve", r.addr));
+            }
+        }
+
+        let start = u32::from(self.dhcp_start());
+        let size = self.dhcp_pool_size();
+        if inside(start) && (size == 0 || !inside(start.saturating_add(size - 1))) {
+            errors.push(OptError::DhcpPoolOverflow(self.dhcp_start(), size));
+        }
+    }
+}
+
+impl OptIpv6 {
+    fn validate(&self, errors: &mut Vec<OptError>) {
+        if self.cidr6.is_some() {
+            if self.prefix.is_some() {
+                errors.push(OptError::Conflict("cidr6", "prefix-ipv6"));
+            }
+            if self.prefix_len.is_some() {
+                errors.push(OptError::Conflict("cidr6", "prefix-length-ipv6"));
+            }
+        }
+
+        if self.prefix_len() > 128 {
+            errors.push(OptError::PrefixLength(self.prefix_len()));
+            return;
+        }
+
+        let mask = self.mask();
+        let prefix = u128::from(self.prefix());
+        for (name, addr) in &[("host-ipv6", self.host()), ("dns-ipv6", self.dns())] {
+            if u128::from(*addr) & mask != prefix {
+                errors.push(OptError::OutsidePrefix(name, *addr));
+            }
+        }
+
+        if let Some(mtu) = self.ra_mtu.filter(|&mtu| mtu < MIN_IPV6_MTU) {
+            errors.push(OptError::Ipv6Mtu(mtu));
+        }
+    }
+}
+
+// the DHCP options of libslirp replies
+const DHCP_OPT_LEN: usize = 312;
+// magic cookie, message type, server id, netmask, lease time, end
+const DHCP_OPT_FIXED_LEN: usize = 4 + 3 + 6 + 6 + 6 + 1;
+// router and DNS server, unless restricted
+const DHCP_OPT_ROUTER_DNS_LEN: usize = 6 + 6;
+
+impl Opt {
+    pub fn mtu(&self) -> u32 {
+        self.mtu.unwrap_or(DEFAULT_MTU)
+    }
+
+    pub fn mru(&self) -> u32 {
+        self.mru.unwrap_or_else(|| self.mtu())
+    }
+
+    /// The DNS suffixes, converted to ASCII.
+    pub fn dns_search(&self) -> Result<Vec<String>, OptError> {
+        self.dns_suffixes
+            .iter()
+            .map(|s| dnssearch::to_ascii(s).map_err(OptError::DnsSuffix))
+            .collect()
+    }
+
+    /// The size of the DHCP option carrying the DNS suffixes.
+    pub fn dns_search_len(&self) -> Result<usize, OptError> {
+        Ok(dnssearch::encoded_len(&self.dns_search()?))
+    }
+
+    /// The room left for the DNS search option in the DHCP replies, once
+    /// the other options are set: libslirp omits it if it doesn't fit.
+    fn dns_search_room(&self) -> usize {
+        let mut len = DHCP_OPT_FIXED_LEN;
+        if !self.restrict {
+            len += DHCP_OPT_ROUTER_DNS_LEN;
+        }
+        for opt in &[&self.hostname, &self.domainname, &self.tftp.name] {
+            len += opt.as_ref().map_or(0, |s| 2 + s.len());
+        }
+
+        DHCP_OPT_LEN.saturating_sub(len)
+    }
+
+    /// Check the consistency of the network parameters.
+    pub fn validate(&self) -> Result<(), Vec<OptError>> {
+        let mut errors = Vec::new();
+
+        match self.dns_search() {
+            Ok(domains) => {
+                let len = dnssearch::encoded_len(&domains);
+                let room = self.dns_search_room();
+                if len > room {
+                    errors.push(OptError::DnsSearchTooLong { len, room });
+                }
+            }
+            Err(_) => errors.extend(
+                self.dns_suffixes
+                    .iter()
+                    .filter_map(|s| dnssearch::to_ascii(s).err())
+                    .map(OptError::DnsSuffix),
+            ),
+        }
+
+        let bootfiles = [
+            ("tftp-bootfile", &self.tftp.bootfile),
+            ("tftp-bootfile-bios", &self.tftp.bootfile_bios),
+            ("tftp-bootfile-uefi", &self.tftp.bootfile_uefi),
+            ("tftp-bootfile-arm64", &self.tftp.bootfile_arm64),
+            ("tftp-bootfile-ipxe", &self.tftp.bootfile_ipxe),
+        ];
+        for (name, file) in bootfiles.iter() {
+            if file.as_ref().map_or(false, |f| f.len() > MAX_BOOTFILE_LEN) {
+                errors.push(OptError::BootfileTooLong(name));
+            }
+        }
+
+        for (name, mtu) in &[("mtu", self.mtu()), ("mru", self.mru())] {
+            if *mtu < MIN_MTU || *mtu > MAX_MTU {
+                errors.push(OptError::LinkMtu(name, *mtu));
+            }
+        }
+
+        if self.ipv4.disable && self.ipv6.disable {
+            errors.push(OptError::NoNetwork);
+        }
+        if !self.ipv4.disable {
+            self.ipv4.validate(&mut errors);
+        }
+        if !self.ipv6.disable {
+            self.ipv6.validate(&mut errors);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn parse<T: FromStr>(s: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    s.trim()
+        .parse()
+        .map_err(|e| format!("invalid value '{}': {}", s, e))
+}
+
+fn parse_list<T: FromStr>(s: &str) -> Result<Vec<T>, String>
+where
+    T::Err: fmt::Display,
+{
+    s.split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(parse)
+        .collect()
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s.trim() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "" | "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("invalid boolean '{}'", s)),
+    }
+}
+
+impl Opt {
+    /// Set the options from the `SLIRP_*` environment variables.
+    ///
+    /// Each option can be set with the variable named after its long flag,
+    /// such as `SLIRP_NET` or `SLIRP_DHCP_START` (see [`env_var`]). Lists,
+    /// such as `SLIRP_DNS_SUFFIXES` and `SLIRP_HOSTFWD`, are comma-separated,
+    /// and booleans are 1/0, true/false, yes/no or on/off.
+    ///
+    /// The command line takes precedence over the environment, which takes
+    /// precedence over a configuration file.
+    pub fn apply_env(&mut self) -> Result<(), Vec<OptError>> {
+        self.apply_vars(env::vars_os())
+    }
+
+    /// Set the options from the `SLIRP_*` variables among `vars`.
+    pub fn apply_vars<I>(&mut self, vars: I) -> Result<(), Vec<OptError>>
+    where
+        I: IntoIterator<Item = (OsString, OsString)>,
+    {
+        let vars: HashMap<_, _> = vars
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.into_string().ok()?, v)))
+            .filter(|(k, _)| k.starts_with("SLIRP_"))
+            .collect();
+        let mut errors = Vec::new();
+
+        macro_rules! var {
+            ($($field:ident).+, $option:expr, $parse:expr) => {
+                let var = env_var($option);
+                if let Some(val) = vars.get(&var) {
+                    let val = val
+                        .to_str()
+                        .ok_or_else(|| "invalid UTF-8".to_string())
+                        .and_then($parse);
+                    match val {
+                        Ok(val) => self.$($field).+ = val,
+                        Err(e) => errors.push(OptError::Env(var, e)),
+                    }
+                }
+            };
+        }
+
+        var!(restrict, "restrict", parse_bool);
+        var!(hostname, "hostname", |s| Ok(Some(s.to_string())));
+        var!(dns_suffixes, "dns-suffixes", parse_list);
+        var!(domainname, "domainname", |s| Ok(Some(s.to_string())));
+        var!(hostfwd, "hostfwd", parse_list);
+        var!(dns_hosts, "dns-host", parse_list);
+        var!(dns_upstreams, "dns-upstream", parse_list);
+        var!(dns_proxy_log, "dns-proxy-log", parse_bool);
+        // the values may be lists themselves
+        var!(dhcp_options, "dhcp-option", |s| s
+            .split(';')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.trim().parse())
+            .collect::<Result<_, _>>());
+        var!(mtu, "mtu", |s| parse(s).map(Some));
+        var!(mru, "mru", |s| parse(s).map(Some));
+        var!(outbound_addr, "outbound-addr", |s| parse(s).map(Some));
+        var!(outbound_addr6, "outbound-addr6", |s| parse(s).map(Some));
+        var!(outbound_interface, "outbound-interface", |s| Ok(Some(
+            s.to_string()
+        )));
+        var!(ipv4.disable, "disable-ipv4", parse_bool);
+        // the network of the configuration file is replaced, not completed
+        if vars.contains_key(&env_var("cidr")) {
+            self.ipv4.net = None;
+            self.ipv4.mask = None;
+        }
+        if vars.contains_key(&env_var("net")) || vars.contains_key(&env_var("mask")) {
+            self.ipv4.cidr = None;
+        }
+        var!(ipv4.cidr, "cidr", |s| parse(s).map(Some));
+        var!(ipv4.net, "net", |s| parse(s).map(Some));
+        var!(ipv4.mask, "mask", |s| parse(s).map(Some));
+        var!(ipv4.host, "host", |s| parse(s).map(Some));
+        var!(ipv4.dhcp_start, "dhcp-start", |s| parse(s).map(Some));
+        var!(ipv4.dhcp_pool_size, "dhcp-pool-size", |s| parse(s)
+            .map(Some));
+        var!(ipv4.dhcp_reservations, "dhcp-reserve", parse_list);
+        var!(ipv4.dns, "dns", |s| parse(s).map(Some));
+        var!(ipv6.disable, "disable-ipv6", parse_bool);
+        if vars.contains_key(&env_var("cidr6")) {
+            self.ipv6.prefix = None;
+            self.ipv6.prefix_len = None;
+        }
+        if vars.contains_key(&env_var("prefix-ipv6"))
+            || vars.contains_key(&env_var("prefix-length-ipv6"))
+        {
+            self.ipv6.cidr6 = None;
+        }
+        var!(ipv6.cidr6, "cidr6", |s| parse(s).map(Some));
+        var!(ipv6.prefix, "prefix-ipv6", |s| parse(s).map(Some));
+        var!(ipv6.prefix_len, "prefix-length-ipv6", |s| parse(s)
+            .map(Some));
+        var!(ipv6.host, "host-ipv6", |s| parse(s).map(Some));
+        var!(ipv6.dns, "dns-ipv6", |s| parse(s).map(Some));
+        var!(ipv6.ra_mtu, "ra-mtu", |s| parse(s).map(Some));
+        var!(ipv6.ra_lifetime, "ra-router-lifetime", |s| parse(s)
+            .map(Some));
+        var!(ipv6.ra_managed, "ra-managed", parse_bool);
+        var!(ipv6.ra_other, "ra-other", parse_bool);
+        var!(ipv6.dhcpv6, "dhcpv6", parse_bool);
+        var!(ipv6.dhcpv6_bootfile_url, "dhcpv6-bootfile-url", |s| Ok(
+            Some(s.to_string())
+        ));
+        var!(tftp.name, "tftp-name", |s| Ok(Some(s.to_string())));
+        var!(tftp.bootfile, "tftp-bootfile", |s| Ok(Some(s.to_string())));
+        var!(tftp.bootfile_bios, "tftp-bootfile-bios", |s| Ok(Some(
+            s.to_string()
+        )));
+        var!(tftp.bootfile_uefi, "tftp-bootfile-uefi", |s| Ok(Some(
+            s.to_string()
+        )));
+        var!(tftp.bootfile_arm64, "tftp-bootfile-arm64", |s| Ok(Some(
+            s.to_string()
+        )));
+        var!(tftp.bootfile_ipxe, "tftp-bootfile-ipxe", |s| Ok(Some(
+            s.to_string()
+        )));
+        var!(tftp.server, "tftp-server", parse_bool);
+        var!(tftp.log, "tftp-log", parse_bool);
+        if let Some(path) = vars.get("SLIRP_HOSTS_FILE") {
+            self.hosts_file = Some(PathBuf::from(path));
+        }
+        if let Some(path) = vars.get("SLIRP_DNS_LOG") {
+            self.dns_log = Some(PathBuf::from(path));
+        }
+        if let Some(root) = vars.get("SLIRP_TFTP_ROOT") {
+            self.tftp.root = Some(PathBuf::from(root));
+        }
+        if let Some(path) = vars.get("SLIRP_TFTP_ARCHIVE") {
+            self.tftp.archive = Some(PathBuf::from(path));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(feature = "config")]
+impl Opt {
+    /// Parse the options from TOML.
+    pub fn from_toml(s: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(s)
+    }
+
+    /// Load the options from a TOML file.
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)?;
+        Self::from_toml(&s).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn override_with() {
+        let args = [
+            "slirp",
+            "--restrict",
+            "--hostname=cli",
+            "--dns-suffixes=a.example",
+            "--domainname=cli.example",
+            "--hostfwd=tcp::2222-:22",
+            "--disable-ipv4",
+            "--cidr=10.1.0.0/16",
+            "--net=10.1.0.0",
+            "--mask=255.255.0.0",
+            "--host=10.1.0.2",
+            "--dhcp-start=10.1.0.15",
+            "--dns=10.1.0.3",
+            "--disable-ipv6",
+            "--cidr6=fd00::/48",
+            "--prefix-ipv6=fd00::",
+            "--prefix-length-ipv6=48",
+            "--host-ipv6=fd00::2",
+            "--dns-ipv6=fd00::3",
+            "--tftp-name=cli",
+            "--tftp-root=/cli",
+            "--tftp-bootfile=cli.efi",
+        ];
+        let matches = Opt::clap().get_matches_from(&args);
+        let expected = format!("{:?}", Opt::from_clap(&matches));
+
+        let mut opt = Opt::default();
+        opt.hostname = Some("file".into());
+        opt.override_with(Opt::from_clap(&matches), &matches);
+        assert_eq!(format!("{:?}", opt), expected);
+
+        let matches = Opt::clap().get_matches_from(&["slirp", "--net=10.1.0.0"]);
+        let mut opt = Opt::default();
+        opt.hostname = Some("file".into());
+        opt.ipv4.mask = "255.255.0.0".parse().ok();
+        opt.override_with(Opt::from_clap(&matches), &matches);
+        assert_eq!(opt.hostname.as_ref().map(String::as_str), Some("file"));
+        assert_eq!(opt.ipv4.net(), Ipv4Addr::new(10, 1, 0, 0));
+        assert_eq!(opt.ipv4.mask(), Ipv4Addr::new(255, 255, 0, 0));
+
+        // the CIDR replaces the network of the file
+        let matches =
+            Opt::clap().get_matches_from(&["slirp", "--cidr=10.2.0.0/24", "--cidr6=fd00::/48"]);
+        let mut opt = Opt::default();
+        opt.ipv4.net = "10.1.0.0".parse().ok();
+        opt.ipv4.mask = "255.255.0.0".parse().ok();
+        opt.ipv6.prefix = "fd01::".parse().ok();
+        opt.ipv6.prefix_len = Some(64);
+        opt.override_with(Opt::from_clap(&matches), &matches);
+        assert_eq!(opt.validate(), Ok(()));
+        assert_eq!(opt.ipv4.net(), Ipv4Addr::new(10, 2, 0, 0));
+        assert_eq!(opt.ipv4.mask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(opt.ipv6.prefix(), "fd00::".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(opt.ipv6.prefix_len(), 48);
+
+        // and the other way around
+        let matches = Opt::clap().get_matches_from(&[
+            "slirp",
+            "--net=10.2.0.0",
+            "--mask=255.255.255.0",
+            "--prefix-length-ipv6=64",
+        ]);
+        let mut opt = Opt::default();
+        opt.ipv4.cidr = "10.1.0.0/16".parse().ok();
+        opt.ipv6.cidr6 = "fd00::/48".parse().ok();
+        opt.override_with(Opt::from_clap(&matches), &matches);
+        assert_eq!(opt.validate(), Ok(()));
+        assert_eq!(opt.ipv4.net(), Ipv4Addr::new(10, 2, 0, 0));
+        assert_eq!(opt.ipv4.mask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(opt.ipv6.prefix_len(), 64);
+    }
+
+    fn errors(args: &[&str]) -> Vec<OptError> {
+        let opt = Opt::from_iter([&["slirp"], args].concat());
+        opt.validate().err().unwrap_or_default()
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(errors(&[]), vec![]);
+        assert_eq!(
+            errors(&["--disable-ipv4", "--disable-ipv6"]),
+            vec![OptError::NoNetwork]
+        );
+        assert_eq!(
+            errors(&["--mask=255.0.255.0"]),
+            vec![OptError::MaskNotContiguous(Ipv4Addr::new(255, 0, 255, 0))]
+        );
+        assert_eq!(
+            errors(&[
+                "--net=10.1.0.0",
+                "--host=10.0.2.2",
+                "--dhcp-start=10.0.2.15"
+            ]),
+            vec![
+                OptError::OutsideNetwork("host", Ipv4Addr::new(10, 0, 2, 2)),
+                OptError::OutsideNetwork("dhcp-start", Ipv4Addr::new(10, 0, 2, 15)),
+            ]
+        );
+        assert_eq!(
+            errors(&["--cidr=10.1.0.0/16", "--net=10.1.0.0"]),
+            vec![OptError::Conflict("cidr", "net")]
+        );
+        assert_eq!(
+            errors(&["--cidr6=fd00::/64", "--prefix-length-ipv6=64"]),
+            vec![OptError::Conflict("cidr6", "prefix-length-ipv6")]
+        );
+        assert_eq!(
+            errors(&["--dhcp-start=10.0.2.240"]),
+            vec![OptError::DhcpPoolOverflow(Ipv4Addr::new(10, 0, 2, 240), 16)]
+        );
+        assert_eq!(errors(&["--dhcp-start=10.0.2.239"]), vec![]);
+        assert_eq!(
+            errors(&["--dhcp-start=10.0.2.100", "--dhcp-pool-size=156"]),
+            vec![OptError::DhcpPoolOverflow(
+                Ipv4Addr::new(10, 0, 2, 100),
+                156
+            )]
+        );
+        assert_eq!(
+            errors(&["--dhcp-pool-size=155", "--dhcp-start=10.0.2.100"]),
+            vec![]
+        );
+        assert_eq!(
+            errors(&["--dhcp-reserve=52:54:00:12:34:56=10.0.3.20"]),
+            vec![OptError::OutsideNetwork(
+                "dhcp-reserve",
+                Ipv4Addr::new(10, 0, 3, 20)
+            )]
+        );
+        assert_eq!(
+            errors(&["--prefix-length-ipv6=129"]),
+            vec![OptError::PrefixLength(129)]
+        );
+        assert_eq!(errors(&["--ra-mtu=1000"]), vec![OptError::Ipv6Mtu(1000)]);
+        assert_eq!(
+            errors(&["--mtu=70000", "--mru=1500"]),
+            vec![OptError::LinkMtu("mtu", 70000)]
+        );
+        assert_eq!(errors(&["--mtu=9000"]), vec![]);
+        assert_eq!(
+            errors(&["--prefix-ipv6=fd00::", "--dns-ipv6=fec0::3"]),
+            vec![OptError::OutsidePrefix(
+                "dns-ipv6",
+                "fec0::3".parse().unwrap()
+            )]
+        );
+        assert_eq!(
+            errors(&[
+                "--disable-ipv6",
+                "--prefix-ipv6=fd00::",
+                "--dns-ipv6=fec0::3"
+            ]),
+            vec![]
+        );
+        let long = format!("--tftp-bootfile-uefi={}", "x".repeat(128));
+        assert_eq!(
+            errors(&["--tftp-bootfile-bios=pxelinux.0", &long]),
+            vec![OptError::BootfileTooLong("tftp-bootfile-uefi")]
+        );
+    }
+
+    #[test]
+    fn dns_search() {
+        assert_eq!(
+            errors(&["--dns-suffixes", "a.example", "b.example"]),
+            vec![]
+        );
+        assert_eq!(
+            errors(&["--dns-suffixes=a_b.example", "--dns-suffixes=-c.example"]).len(),
+            2
+        );
+
+        let mut opt = Opt::default();
+        opt.dns_suffixes = vec!["bücher.example".into(), "example".into()];
+        assert_eq!(opt.dns_search().unwrap()[0], "xn--bcher-kva.example");
+        assert_eq!(opt.dns_search_len(), Ok(2 + 23 + 2));
+
+        // 274 bytes left
+        opt.dns_suffixes = (0..43).map(|i| format!("d{:02}.example", i)).collect();
+        assert_eq!(opt.dns_search_len(), Ok(2 * 2 + 13 + 42 * 6));
+        assert_eq!(opt.validate(), Ok(()));
+        opt.domainname = Some("example".into());
+        assert_eq!(
+            opt.validate(),
+            Err(vec![OptError::DnsSearchTooLong {
+                len: 269,
+                room: 274 - 9
+            }])
+        );
+    }
+
+    #[test]
+    fn cidr() {
+        assert_eq!(
+            "10.1.0.0/16".parse::<Ipv4Cidr>(),
+            Ok(Ipv4Cidr {
+                addr: Ipv4Addr::new(10, 1, 0, 0),
+                len: 16
+            })
+        );
+        assert!("10.1.0.0".parse::<Ipv4Cidr>().is_err());
+        assert!("10.1.0.0/33".parse::<Ipv4Cidr>().is_err());
+        assert!("fd00::/129".parse::<Ipv6Cidr>().is_err());
+
+        let opt = Opt::from_iter(&["slirp"]);
+        assert_eq!(opt.ipv4.cidr().to_string(), "10.0.2.0/24");
+        assert_eq!(opt.ipv4.host(), Ipv4Addr::new(10, 0, 2, 2));
+        assert_eq!(opt.ipv4.dns(), Ipv4Addr::new(10, 0, 2, 3));
+        assert_eq!(opt.ipv4.dhcp_start(), Ipv4Addr::new(10, 0, 2, 15));
+        assert_eq!(opt.ipv6.prefix(), "fec0::".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(opt.ipv6.host(), "fec0::2".parse::<Ipv6Addr>().unwrap());
+
+        let opt = Opt::from_iter(&[
+            "slirp",
+            "--cidr=192.168.7.1/23",
+            "--dns=192.168.7.53",
+            "--cidr6=fd00:1::/48",
+        ]);
+        assert_eq!(opt.ipv4.net(), Ipv4Addr::new(192, 168, 6, 0));
+        assert_eq!(opt.ipv4.mask(), Ipv4Addr::new(255, 255, 254, 0));
+        assert_eq!(opt.ipv4.host(), Ipv4Addr::new(192, 168, 6, 2));
+        assert_eq!(opt.ipv4.dns(), Ipv4Addr::new(192, 168, 7, 53));
+        assert_eq!(opt.ipv4.dhcp_start(), Ipv4Addr::new(192, 168, 6, 15));
+        assert_eq!(opt.ipv6.prefix_len(), 48);
+        assert_eq!(opt.ipv6.dns(), "fd00:1::3".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(opt.validate(), Ok(()));
+    }
+
+    #[test]
+    fn env() {
+        let vars = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect::<Vec<_>>()
+        };
+
+        let mut opt = Opt::default();
+        opt.hostname = Some("file".into());
+        opt.apply_vars(vars(&[
+            ("SLIRP_RESTRICT", "1"),
+            ("SLIRP_NET", "10.1.0.0"),
+            ("SLIRP_DNS_SUFFIXES", "a.example, b.example"),
+            ("SLIRP_HOSTFWD", "tcp::2222-:22,udp::5353-:53"),
+            ("SLIRP_DISABLE_IPV6", "no"),
+            ("SLIRP_PREFIX_LENGTH_IPV6", "48"),
+            ("SLIRP_RA_MTU", "1400"),
+            ("SLIRP_RA_OTHER", "1"),
+            ("SLIRP_DHCPV6", "true"),
+            ("SLIRP_MTU", "9000"),
+            ("SLIRP_OUTBOUND_ADDR6", "2001:db8::10"),
+            ("SLIRP_OUTBOUND_INTERFACE", "eth1"),
+            ("SLIRP_DNS_HOST", "mirror.internal=192.168.1.10"),
+            ("SLIRP_DHCP_OPTION", "42=10.0.2.2,10.0.2.3; 26=1400"),
+            ("SLIRP_HOSTS_FILE", "/etc/slirp/hosts"),
+            ("SLIRP_TFTP_ROOT", "/srv/tftp"),
+            ("SLIRP_TFTP_SERVER", "yes"),
+            ("SLIRP_TFTP_ARCHIVE", "/srv/boot.tar"),
+            ("OTHER_NET", "garbage"),
+        ]))
+        .unwrap();
+        assert!(opt.restrict);
+        assert_eq!(opt.hostname.as_ref().map(String::as_str), Some("file"));
+        assert_eq!(opt.ipv4.net(), Ipv4Addr::new(10, 1, 0, 0));
+        assert_eq!(opt.dns_suffixes, vec!["a.example", "b.example"]);
+        assert_eq!(opt.hostfwd.len(), 2);
+        assert_eq!(opt.dns_hosts[0].name, "mirror.internal");
+        assert_eq!(opt.dhcp_options.len(), 2);
+        assert_eq!(opt.hosts_file, Some(PathBuf::from("/etc/slirp/hosts")));
+        assert!(!opt.ipv6.disable);
+        assert_eq!(opt.ipv6.prefix_len(), 48);
+        assert_eq!(opt.ipv6.ra_mtu, Some(1400));
+        assert!(opt.ipv6.ra_other && !opt.ipv6.ra_managed);
+        assert!(opt.ipv6.dhcpv6);
+        assert_eq!((opt.mtu(), opt.mru()), (9000, 9000));
+        assert_eq!(opt.outbound_addr, None);
+        assert_eq!(opt.outbound_addr6, "2001:db8::10".parse().ok());
+        assert_eq!(
+            opt.outbound_interface.as_ref().map(String::as_str),
+            Some("eth1")
+        );
+        assert_eq!(opt.tftp.root, Some(PathBuf::from("/srv/tftp")));
+        assert!(opt.tftp.server);
+        assert_eq!(opt.tftp.archive, Some(PathBuf::from("/srv/boot.tar")));
+
+        let errors = Opt::default()
+            .apply_vars(vars(&[
+                ("SLIRP_NET", "10.1.0"),
+                ("SLIRP_RESTRICT", "maybe"),
+            ]))
+            .unwrap_err();
+        let mut errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+        errors.sort();
+        assert!(errors[0].starts_with("SLIRP_NET: invalid value '10.1.0'"));
+        assert_eq!(errors[1], "SLIRP_RESTRICT: invalid boolean 'maybe'");
+
+        // the CIDR replaces the network of the file
+        let mut opt = Opt::default();
+        opt.ipv4.net = "10.1.0.0".parse().ok();
+        opt.ipv4.mask = "255.255.0.0".parse().ok();
+        opt.apply_vars(vars(&[("SLIRP_CIDR", "10.2.0.0/24")]))
+            .unwrap();
+        assert_eq!(opt.validate(), Ok(()));
+        assert_eq!(opt.ipv4.mask(), Ipv4Addr::new(255, 255, 255, 0));
+
+        // and the network replaces the CIDR of the file
+        let mut opt = Opt::default();
+        opt.ipv4.cidr = "10.1.0.0/16".parse().ok();
+        opt.ipv6.cidr6 = "fd00::/48".parse().ok();
+        opt.apply_vars(vars(&[
+            ("SLIRP_NET", "10.2.0.0"),
+            ("SLIRP_PREFIX_IPV6", "fd01::"),
+        ]))
+        .unwrap();
+        assert_eq!(opt.validate(), Ok(()));
+        assert_eq!(opt.ipv4.net(), Ipv4Addr::new(10, 2, 0, 0));
+        assert_eq!(opt.ipv6.prefix(), "fd01::".parse::<Ipv6Addr>().unwrap());
+
+        assert_eq!(
+            OptError::OutsideNetwork("dhcp-start", Ipv4Addr::new(10, 0, 2, 15)).to_string(),
+            "--dhcp-start (SLIRP_DHCP_START) 10.0.2.15 is outside of the IPv4 network"
+        );
+    }
+
+    #[test]
+    fn hostfwd() {
+        let fwd: HostFwd = "tcp:127.0.0.1:2222-:22".parse().unwrap();
+        assert_eq!(
+            fwd,
+            HostFwd {
+                protocol: Protocol::Tcp,
+                host_addr: Some(Ipv4Addr::LOCALHOST),
+                host_port: 2222,
+                guest_addr: None,
+                guest_port: 22,
+            }
+        );
+        assert_eq!(fwd.to_string(), "tcp:127.0.0.1:2222-:22");
+
+        let fwd: HostFwd = "udp::5353-10.0.2.15:53".parse().unwrap();
+        assert_eq!(fwd.protocol, Protocol::Udp);
+        assert_eq!(fwd.host_addr, None);
+        assert_eq!(fwd.guest_addr, Some(Ipv4Addr::new(10, 0, 2, 15)));
+        assert_eq!(
+            "::8080-:80".parse::<HostFwd>().unwrap().protocol,
+            Protocol::Tcp
+        );
+
+        for s in &[
+            "tcp:2222-:22",
+            ":8080-:80",
+            "sctp::2222-:22",
+            "tcp::2222",
+            "tcp::70000-:22",
+            "tcp:localhost:2222-:22",
+        ] {
+            assert!(s.parse::<HostFwd>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn dns_host() {
+        let host: DnsHost = "Mirror.Internal.=192.168.1.10".parse().unwrap();
+        assert_eq!(host.name, "mirror.internal");
+        assert_eq!(host.addr, IpAddr::from([192, 168, 1, 10]));
+        assert_eq!(host.to_string(), "mirror.internal=192.168.1.10");
+        assert_eq!(
+            "mirror=fd00::10".parse::<DnsHost>().unwrap().addr,
+            "fd00::10".parse::<IpAddr>().unwrap()
+        );
+
+        for s in &[
+            "mirror.internal",
+            "mirror=10.0.2",
+            "-mirror=10.0.2.1",
+            "=10.0.2.1",
+        ] {
+            assert!(s.parse::<DnsHost>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn dhcp_reservation() {
+        let r: DhcpReservation = "52:54:00:AB:cd:01 = 10.0.2.20".parse().unwrap();
+        assert_eq!(r.mac, [0x52, 0x54, 0, 0xab, 0xcd, 1]);
+        assert_eq!(r.addr, Ipv4Addr::new(10, 0, 2, 20));
+        assert_eq!(r.to_string(), "52:54:00:ab:cd:01=10.0.2.20");
+        assert_eq!("52-54-00-ab-cd-01=10.0.2.20".parse(), Ok(r));
+
+        for s in &[
+            "52:54:00:ab:cd:01",
+            "52:54:00:ab:cd=10.0.2.20",
+            "52:54:00:ab:cd:1=10.0.2.20",
+            "52:54:00:ab:cd:01=fd00::1",
+        ] {
+            assert!(s.parse::<DhcpReservation>().is_err(), "{}", s);
+        }
+
+        let mut opt = Opt::default();
+        assert!(!opt.ipv4.rust_dhcp());
+        opt.ipv4.dhcp_pool_size = Some(64);
+        assert!(opt.ipv4.rust_dhcp());
+    }
+
+    #[test]
+    fn dhcp_option() {
+        let opt = |s: &str| s.parse::<DhcpOption>().map(|o| (o.code, o.data));
+        assert_eq!(
+            opt("42=10.0.2.2, 10.0.2.3"),
+            Ok((42, vec![10, 0, 2, 2, 10, 0, 2, 3]))
+        );
+        assert_eq!(opt("26=1400"), Ok((26, vec![0x05, 0x78])));
+        assert_eq!(opt("51=86400"), Ok((51, vec![0, 1, 0x51, 0x80])));
+        assert_eq!(
+            opt("121=10.1.0.0/16-10.0.2.2,0.0.0.0/0-10.0.2.2"),
+            Ok((121, vec![16, 10, 1, 10, 0, 2, 2, 0, 10, 0, 2, 2]))
+        );
+        assert_eq!(opt("43=0x01:04:c0a8"), Ok((43, vec![1, 4, 0xc0, 0xa8])));
+        assert_eq!(opt("15=internal"), Ok((15, b"internal".to_vec())));
+
+        let option: DhcpOption = "26=1400".parse().unwrap();
+        assert_eq!(option.to_string(), "26=0x0578");
+        assert_eq!(option.to_string().parse(), Ok(option));
+
+        for s in &[
+            "42",
+            "0=1",
+            "256=1",
+            "42=10.0.2",
+            "26=70000",
+            "43=0x123",
+            "43=0xaé1",
+            "43=0x+f",
+            "121=10.1.0.0/16",
+            "15=",
+        ] {
+            assert!(s.parse::<DhcpOption>().is_err(), "{}", s);
+        }
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn toml() {
+        let opt = Opt::from_toml(
+            r#"
+            hostname = "vm"
+            dns_suffixes = ["example.com"]
+            hostfwd = ["tcp::2222-:22"]
+
+            [ipv4]
+            net = "10.1.0.0"
+
+            [ipv6]
+            cidr6 = "fd00::/64"
+
+            [tftp]
+            root = "/srv/tftp"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(opt.hostname.as_ref().map(String::as_str), Some("vm"));
+        assert_eq!(opt.dns_suffixes, vec!["example.com".to_string()]);
+        assert_eq!(opt.hostfwd[0].guest_port, 22);
+        assert_eq!(opt.ipv4.net(), Ipv4Addr::new(10, 1, 0, 0));
+        assert_eq!(opt.ipv4.mask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(opt.ipv6.prefix(), "fd00::".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(opt.tftp.root, Some(PathBuf::from("/srv/tftp")));
+
+        let err = Opt::from_toml("[ipv4]\nnet = \"10.1.0\"\n").unwrap_err();
+        assert!(err.to_string().contains("ipv4.net"), "{}", err);
+        let err = Opt::from_toml("[ipv4]\nnett = \"10.1.0.0\"\n").unwrap_err();
+        assert!(err.to_string().contains("nett"), "{}", err);
+    }
+}
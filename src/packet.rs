@@ -0,0 +1,474 @@
+//! Parsing and building of the IP frames exchanged with the guest.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const ETH_HLEN: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+const IPV4_HLEN: usize = 20;
+const IPV6_HLEN: usize = 40;
+const UDP_HLEN: usize = 8;
+const TCP_HLEN: usize = 20;
+const TCP_OPT_MSS: u8 = 2;
+const TTL: u8 = 64;
+
+fn be16(buf: &[u8], pos: usize) -> u16 {
+    u16::from_be_bytes([buf[pos], buf[pos + 1]])
+}
+
+/// The one's complement sum of `data`, added to `sum`.
+pub fn sum(mut sum: u32, data: &[u8]) -> u32 {
+    for c in data.chunks(2) {
+        sum += u32::from(c[0]) << 8 | c.get(1).map_or(0, |&b| u32::from(b));
+    }
+    sum
+}
+
+/// The Internet checksum of a sum.
+pub fn checksum(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The sum of the pseudo-header of a transport protocol.
+pub fn pseudo_header_sum(src: IpAddr, dst: IpAddr, proto: u8, len: usize) -> u32 {
+    let sum = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => self::sum(self::sum(0, &src.octets()), &dst.octets()),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => self::sum(self::sum(0, &src.octets()), &dst.octets()),
+        _ => panic!("Mixed IPv4 and IPv6 addresses"),
+    };
+    sum + u32::from(proto) + len as u32
+}
+
+/// An unfragmented IPv4 or IPv6 packet (without extension headers) in an
+/// ethernet frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ip<'a> {
+    pub src_mac: [u8; 6],
+    pub dst_mac: [u8; 6],
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub proto: u8,
+    /// The position of the payload in the frame.
+    pub offset: usize,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ip<'a> {
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        if frame.len() < ETH_HLEN {
+            return None;
+        }
+        let mut dst_mac = [0; 6];
+        let mut src_mac = [0; 6];
+        dst_mac.copy_from_slice(&frame[0..6]);
+        src_mac.copy_from_slice(&frame[6..12]);
+        let ip = &frame[ETH_HLEN..];
+
+        let (src, dst, proto, hlen, len) = match be16(frame, 12) {
+            ETHERTYPE_IPV4 => {
+                if ip.len() < IPV4_HLEN || ip[0] >> 4 != 4 {
+                    return None;
+                }
+                // more fragments, or fragment offset
+                if be16(ip, 6) & 0x3fff != 0 {
+                    return None;
+                }
+                let hlen = 4 * usize::from(ip[0] & 0xf);
+                let len = usize::from(be16(ip, 2));
+                if hlen < IPV4_HLEN || len < hlen || len > ip.len() {
+                    return None;
+                }
+                let mut src = [0; 4];
+                let mut dst = [0; 4];
+                src.copy_from_slice(&ip[12..16]);
+                dst.copy_from_slice(&ip[16..20]);
+                (
+                    IpAddr::from(Ipv4Addr::from(src)),
+                    IpAddr::from(Ipv4Addr::from(dst)),
+                    ip[9],
+                    hlen,
+                    len,
+                )
+            }
+            ETHERTYPE_IPV6 => {
+                if ip.len() < IPV6_HLEN || ip[0] >> 4 != 6 {
+                    return None;
+                }
+                let len = IPV6_HLEN + usize::from(be16(ip, 4));
+                if len > ip.len() {
+                    return None;
+                }
+                let mut src = [0; 16];
+                let mut dst = [0; 16];
+                src.copy_from_slice(&ip[8..24]);
+                dst.copy_from_slice(&ip[24..40]);
+                (
+                    IpAddr::from(Ipv6Addr::from(src)),
+                    IpAddr::from(Ipv6Addr::from(dst)),
+                    ip[6],
+                    IPV6_HLEN,
+                    len,
+                )
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            src_mac,
+            dst_mac,
+            src,
+            dst,
+            proto,
+            offset: ETH_HLEN + hlen,
+            payload: &ip[hlen..len],
+        })
+    }
+}
+
+/// A UDP datagram in an ethernet frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Udp<'a> {
+    pub src_mac: [u8; 6],
+    pub dst_mac: [u8; 6],
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Udp<'a> {
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        let ip = Ip::parse(frame).filter(|ip| ip.proto == IPPROTO_UDP)?;
+        let udp = ip.payload;
+        if udp.len() < UDP_HLEN {
+            return None;
+        }
+        let len = usize::from(be16(udp, 4));
+        if len < UDP_HLEN || len > udp.len() {
+            return None;
+        }
+
+        Some(Self {
+            src_mac: ip.src_mac,
+            dst_mac: ip.dst_mac,
+            src: SocketAddr::new(ip.src, be16(udp, 0)),
+            dst: SocketAddr::new(ip.dst, be16(udp, 2)),
+            payload: &udp[UDP_HLEN..len],
+        })
+    }
+
+    /// The frame of a reply to this datagram, from its destination.
+    pub fn reply(&self, payload: &[u8]) -> Vec<u8> {
+        udp_frame(self.dst_mac, self.src_mac, self.dst, self.src, payload)
+    }
+}
+
+/// A TCP segment in an ethernet frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tcp<'a> {
+    pub src_mac: [u8; 6],
+    pub dst_mac: [u8; 6],
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    /// The maximum segment size option, of a SYN.
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Tcp<'a> {
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        let ip = Ip::parse(frame).filter(|ip| ip.proto == IPPROTO_TCP)?;
+        let tcp = ip.payload;
+        if tcp.len() < TCP_HLEN {
+            return None;
+        }
+        let hlen = 4 * usize::from(tcp[12] >> 4);
+        if hlen < TCP_HLEN || hlen > tcp.len() {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &tcp[TCP_HLEN..hlen];
+        while let Some(&kind) = options.first() {
+            match kind {
+                // end of the options
+                0 => break,
+                // padding
+                1 => options = &options[1..],
+                _ => {
+                    let len = usize::from(*options.get(1)?);
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if kind == TCP_OPT_MSS && len == 4 {
+                        mss = Some(be16(options, 2));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        Some(Self {
+            src_mac: ip.src_mac,
+            dst_mac: ip.dst_mac,
+            src: SocketAddr::new(ip.src, be16(tcp, 0)),
+            dst: SocketAddr::new(ip.dst, be16(tcp, 2)),
+            seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+            ack: u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]),
+            flags: tcp[13],
+            mss,
+            payload: &tcp[hlen..],
+        })
+    }
+
+    pub fn has(&self, flags: u8) -> bool {
+        self.flags & flags == flags
+    }
+
+    /// The frame of a segment in reply to this one, from its destination,
+    /// with the largest window and its checksums.
+    pub fn reply(&self, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let tcp_len = TCP_HLEN + payload.len();
+        let mut frame = ip_frame(
+            self.dst_mac,
+            self.src_mac,
+            self.dst.ip(),
+            self.src.ip(),
+            IPPROTO_TCP,
+            tcp_len,
+        );
+
+        let start = frame.len();
+        frame.extend_from_slice(&self.dst.port().to_be_bytes());
+        frame.extend_from_slice(&self.src.port().to_be_bytes());
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&ack.to_be_bytes());
+        frame.extend_from_slice(&[(TCP_HLEN as u8 / 4) << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        frame.extend_from_slice(payload);
+
+        let pseudo = pseudo_header_sum(self.dst.ip(), self.src.ip(), IPPROTO_TCP, tcp_len);
+        let csum = checksum(sum(pseudo, &frame[start..]));
+        frame[start + 16..start + 18].copy_from_slice(&csum.to_be_bytes());
+
+        frame
+    }
+}
+
+/// Replace the addresses of the IPv4 TCP segment in `frame`, and update its
+/// checksums. Returns whether it is one.
+pub fn set_tcp_addrs(frame: &mut [u8], src: Ipv4Addr, dst: Ipv4Addr) -> bool {
+    let (offset, len) = match Tcp::parse(frame).and_then(|_| Ip::parse(frame)) {
+        Some(ref ip) if ip.src.is_ipv4() => (ip.offset, ip.payload.len()),
+        _ => return false,
+    };
+
+    let ip = ETH_HLEN;
+    frame[ip + 10..ip + 12].copy_from_slice(&[0, 0]);
+    frame[ip + 12..ip + 16].copy_from_slice(&src.octets());
+    frame[ip + 16..ip + 20].copy_from_slice(&dst.octets());
+    let csum = checksum(sum(0, &frame[ip..offset]));
+    frame[ip + 10..ip + 12].copy_from_slice(&csum.to_be_bytes());
+
+    frame[offset + 16..offset + 18].copy_from_slice(&[0, 0]);
+    let pseudo = pseudo_header_sum(src.into(), dst.into(), IPPROTO_TCP, len);
+    let csum = checksum(sum(pseudo, &frame[offset..offset + len]));
+    frame[offset + 16..offset + 18].copy_from_slice(&csum.to_be_bytes());
+    true
+}
+
+/// The ethernet and IP headers of a frame, with a payload of `len` bytes
+/// to append.
+///
+/// `src` and `dst` must be of the same address family.
+fn ip_frame(
+    src_mac: [u8; 6],
+    dst_mac: [u8; 6],
+    src: IpAddr,
+    dst: IpAddr,
+    proto: u8,
+    len: usize,
+) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETH_HLEN + IPV6_HLEN + len);
+    frame.extend_from_slice(&dst_mac);
+    frame.extend_from_slice(&src_mac);
+
+    match (src, dst) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+            let start = frame.len();
+            frame.extend_from_slice(&[0x45, 0]);
+            frame.extend_from_slice(&((IPV4_HLEN + len) as u16).to_be_bytes());
+            // id, don't fragment
+            frame.extend_from_slice(&[0, 0, 0x40, 0, TTL, proto, 0, 0]);
+            frame.extend_from_slice(&s.octets());
+            frame.extend_from_slice(&d.octets());
+            let csum = checksum(sum(0, &frame[start..]));
+            frame[start + 10..start + 12].copy_from_slice(&csum.to_be_bytes());
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+            frame.extend_from_slice(&[0x60, 0, 0, 0]);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+            frame.extend_from_slice(&[proto, TTL]);
+            frame.extend_from_slice(&s.octets());
+            frame.extend_from_slice(&d.octets());
+        }
+        _ => panic!("Mixed IPv4 and IPv6 addresses"),
+    }
+    frame
+}
+
+/// Build the ethernet frame of a UDP datagram, with its checksums.
+///
+/// `src` and `dst` must be of the same address family.
+pub fn udp_frame(
+    src_mac: [u8; 6],
+    dst_mac: [u8; 6],
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = UDP_HLEN + payload.len();
+    let mut frame = ip_frame(src_mac, dst_mac, src.ip(), dst.ip(), IPPROTO_UDP, udp_len);
+
+    let start = frame.len();
+    frame.extend_from_slice(&src.port().to_be_bytes());
+    frame.extend_from_slice(&dst.port().to_be_bytes());
+    frame.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(payload);
+
+    let pseudo = pseudo_header_sum(src.ip(), dst.ip(), IPPROTO_UDP, udp_len);
+    let csum = match checksum(sum(pseudo, &frame[start..])) {
+        // zero stands for no checksum
+        0 => 0xffff,
+        csum => csum,
+    };
+    frame[start + 6..start + 8].copy_from_slice(&csum.to_be_bytes());
+
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+    const HOST: [u8; 6] = [0x52, 0x55, 10, 0, 2, 2];
+
+    #[test]
+    fn udp() {
+        let src: SocketAddr = "10.0.2.15:1234".parse().unwrap();
+        let dst: SocketAddr = "10.0.2.3:53".parse().unwrap();
+        let frame = udp_frame(GUEST, HOST, src, dst, b"hello");
+        assert_eq!(frame.len(), ETH_HLEN + 20 + 8 + 5);
+        // the checksums verify
+        assert_eq!(checksum(sum(0, &frame[ETH_HLEN..ETH_HLEN + 20])), 0);
+        let pseudo = pseudo_header_sum(src.ip(), dst.ip(), IPPROTO_UDP, 8 + 5);
+        assert_eq!(checksum(sum(pseudo, &frame[ETH_HLEN + 20..])), 0);
+
+        let udp = Udp::parse(&frame).unwrap();
+        assert_eq!((udp.src_mac, udp.dst_mac), (GUEST, HOST));
+        assert_eq!((udp.src, udp.dst), (src, dst));
+        assert_eq!(udp.payload, b"hello");
+
+        let reply = udp.reply(b"world");
+        let reply = Udp::parse(&reply).unwrap();
+        assert_eq!((reply.src_mac, reply.dst_mac), (HOST, GUEST));
+        assert_eq!((reply.src, reply.dst), (dst, src));
+        assert_eq!(reply.payload, b"world");
+
+        // truncated
+        assert_eq!(Udp::parse(&frame[..frame.len() - 1]), None);
+        // fragmented
+        let mut frag = frame.clone();
+        frag[ETH_HLEN + 6] |= 0x20;
+        assert_eq!(Udp::parse(&frag), None);
+        // ethernet padding is ignored
+        let mut padded = frame.clone();
+        padded.resize(frame.len() + 10, 0);
+        assert_eq!(Udp::parse(&padded).unwrap().payload, b"hello");
+    }
+
+    #[test]
+    fn tcp() {
+        let guest: SocketAddr = "10.0.2.15:40000".parse().unwrap();
+        let server: SocketAddr = "10.0.2.3:53".parse().unwrap();
+        // a segment of the server, to build the one of the guest
+        let template = Tcp {
+            src_mac: HOST,
+            dst_mac: GUEST,
+            src: server,
+            dst: guest,
+            seq: 0,
+            ack: 0,
+            flags: 0,
+            mss: None,
+            payload: &[],
+        };
+        let frame = template.reply(1000, 2000, TCP_PSH | TCP_ACK, b"hello");
+        let pseudo = pseudo_header_sum(guest.ip(), server.ip(), IPPROTO_TCP, 20 + 5);
+        assert_eq!(checksum(sum(pseudo, &frame[ETH_HLEN + 20..])), 0);
+
+        let tcp = Tcp::parse(&frame).unwrap();
+        assert_eq!((tcp.src_mac, tcp.dst_mac), (GUEST, HOST));
+        assert_eq!((tcp.src, tcp.dst), (guest, server));
+        assert_eq!((tcp.seq, tcp.ack), (1000, 2000));
+        assert!(tcp.has(TCP_PSH | TCP_ACK) && !tcp.has(TCP_SYN));
+        assert_eq!((tcp.mss, tcp.payload), (None, &b"hello"[..]));
+
+        // a SYN with its options: NOP, MSS, end
+        let mut syn = template.reply(1000, 0, TCP_SYN, &[1, 2, 4, 5, 0xb4, 0, 0, 0]);
+        syn[ETH_HLEN + 20 + 12] = 7 << 4;
+        let tcp = Tcp::parse(&syn).unwrap();
+        assert_eq!((tcp.mss, tcp.payload), (Some(1460), &[][..]));
+        syn[ETH_HLEN + 20 + 22] = 9;
+        assert_eq!(Tcp::parse(&syn), None);
+
+        // to another address, with the checksums updated
+        let mut frame = frame;
+        let alias = Ipv4Addr::new(10, 0, 2, 0);
+        assert!(set_tcp_addrs(
+            &mut frame,
+            Ipv4Addr::new(10, 0, 2, 15),
+            alias
+        ));
+        assert_eq!(
+            Tcp::parse(&frame).unwrap().dst,
+            SocketAddr::from((alias, 53))
+        );
+        assert_eq!(checksum(sum(0, &frame[ETH_HLEN..ETH_HLEN + 20])), 0);
+        let pseudo = pseudo_header_sum(guest.ip(), alias.into(), IPPROTO_TCP, 20 + 5);
+        assert_eq!(checksum(sum(pseudo, &frame[ETH_HLEN + 20..])), 0);
+        assert!(!set_tcp_addrs(&mut frame[..ETH_HLEN + 30], alias, alias));
+    }
+
+    #[test]
+    fn udp6() {
+        let src: SocketAddr = "[fec0::15]:1234".parse().unwrap();
+        let dst: SocketAddr = "[fec0::3]:53".parse().unwrap();
+        let frame = udp_frame(GUEST, HOST, src, dst, b"hello");
+        assert_eq!(frame.len(), ETH_HLEN + 40 + 8 + 5);
+        let pseudo = pseudo_header_sum(src.ip(), dst.ip(), IPPROTO_UDP, 8 + 5);
+        assert_eq!(checksum(sum(pseudo, &frame[ETH_HLEN + 40..])), 0);
+
+        let udp = Udp::parse(&frame).unwrap();
+        assert_eq!((udp.src, udp.dst), (src, dst));
+        assert_eq!(udp.payload, b"hello");
+    }
+}
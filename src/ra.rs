@@ -0,0 +1,233 @@
+//! Rewriting of the IPv6 router advertisements of libslirp.
+
+use crate::dns::write_name;
+use crate::filter::{Filter, Verdict};
+use crate::packet::{checksum, pseudo_header_sum, sum, Ip, ETH_HLEN, IPPROTO_ICMPV6};
+use crate::{Opt, OptError};
+
+use std::net::Ipv6Addr;
+
+pub const ICMPV6_ROUTER_ADVERT: u8 = 134;
+
+// type, code, checksum, hop limit, flags, router lifetime, reachable time
+// and retransmission timer
+const RA_HLEN: usize = 16;
+const FLAG_MANAGED: u8 = 0x80;
+const FLAG_OTHER: u8 = 0x40;
+
+const OPT_MTU: u8 = 5;
+const OPT_RDNSS: u8 = 25;
+const OPT_DNSSL: u8 = 31;
+// three times the maximum interval of the advertisements of libslirp, as
+// advised by RFC 8106
+const DNS_LIFETIME: u32 = 1800;
+
+/// A filter adjusting the router advertisements sent to the guest.
+///
+/// The DNS servers and search domains replace the RDNSS and DNSSL options
+/// of libslirp (RFC 8106), and the MTU replaces its MTU option.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouterAdvert {
+    pub mtu: Option<u32>,
+    pub router_lifetime: Option<u16>,
+    /// Addresses are assigned by DHCPv6.
+    pub managed: bool,
+    /// Other parameters are given by DHCPv6.
+    pub other: bool,
+    pub dns: Vec<Ipv6Addr>,
+    pub search: Vec<String>,
+}
+
+impl RouterAdvert {
+    /// The advertisements of `opt`, or the error of its DNS suffixes.
+    pub fn from_opt(opt: &Opt) -> Result<Self, OptError> {
+        Ok(Self {
+            mtu: opt.ipv6.ra_mtu,
+            router_lifetime: opt.ipv6.ra_lifetime,
+            // the DHCPv6 server assigns the addresses and gives the rest
+            managed: opt.ipv6.ra_managed || opt.ipv6.dhcpv6,
+            other: opt.ipv6.ra_other || opt.ipv6.dhcpv6,
+            dns: vec![opt.ipv6.dns()],
+            search: opt.dns_search()?,
+        })
+    }
+
+    /// Whether the advertisements differ from the ones of libslirp.
+    pub fn changes(&self) -> bool {
+        self.mtu.is_some()
+            || self.router_lifetime.is_some()
+            || self.managed
+            || self.other
+            || !self.search.is_empty()
+    }
+
+    fn replaces(&self, option: u8) -> bool {
+        match option {
+            OPT_MTU => self.mtu.is_some(),
+            OPT_RDNSS => !self.dns.is_empty(),
+            OPT_DNSSL => !self.search.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// The advertisement `ra`, adjusted, with its checksum unset.
+    fn rewrite(&self, ra: &[u8]) -> Vec<u8> {
+        let mut msg = ra[..RA_HLEN].to_vec();
+        if self.managed {
+            msg[5] |= FLAG_MANAGED;
+        }
+        if self.other {
+            msg[5] |= FLAG_OTHER;
+        }
+        if let Some(lifetime) = self.router_lifetime {
+            msg[6..8].copy_from_slice(&lifetime.to_be_bytes());
+        }
+
+        // the options of libslirp, in units of 8 bytes
+        let mut pos = RA_HLEN;
+        while pos + 2 <= ra.len() {
+            let len = 8 * usize::from(ra[pos + 1]);
+            if len == 0 || pos + len > ra.len() {
+                break;
+            }
+            if !self.replaces(ra[pos]) {
+                msg.extend_from_slice(&ra[pos..pos + len]);
+            }
+            pos += len;
+        }
+
+        if let Some(mtu) = self.mtu {
+            msg.extend_from_slice(&[OPT_MTU, 1, 0, 0]);
+            msg.extend_from_slice(&mtu.to_be_bytes());
+        }
+        if !self.dns.is_empty() {
+            msg.extend_from_slice(&[OPT_RDNSS, 1 + 2 * self.dns.len() as u8, 0, 0]);
+            msg.extend_from_slice(&DNS_LIFETIME.to_be_bytes());
+            for addr in &self.dns {
+                msg.extend_from_slice(&addr.octets());
+            }
+        }
+        if !self.search.is_empty() {
+            let mut names = Vec::new();
+            for domain in &self.search {
+                write_name(&mut names, domain);
+            }
+            names.resize((names.len() + 7) / 8 * 8, 0);
+            msg.extend_from_slice(&[OPT_DNSSL, 1 + (names.len() / 8) as u8, 0, 0]);
+            msg.extend_from_slice(&DNS_LIFETIME.to_be_bytes());
+            msg.extend(names);
+        }
+
+        msg[2..4].copy_from_slice(&[0, 0]);
+        msg
+    }
+}
+
+impl Filter for RouterAdvert {
+    fn output(&mut self, frame: &mut Vec<u8>, _now: i64) -> Verdict {
+        let (offset, src, dst, mut msg) = match Ip::parse(frame) {
+            Some(ip)
+                if ip.proto == IPPROTO_ICMPV6
+                    && ip.src.is_ipv6()
+                    && ip.payload.len() >= RA_HLEN
+                    && ip.payload[0] == ICMPV6_ROUTER_ADVERT =>
+            {
+                (ip.offset, ip.src, ip.dst, self.rewrite(ip.payload))
+            }
+            _ => return Verdict::Pass,
+        };
+
+        let pseudo = pseudo_header_sum(src, dst, IPPROTO_ICMPV6, msg.len());
+        let sum = checksum(sum(pseudo, &msg));
+        msg[2..4].copy_from_slice(&sum.to_be_bytes());
+        frame.truncate(offset);
+        frame.extend(msg);
+        // the payload length of the IPv6 header
+        let len = (frame.len() - offset) as u16;
+        frame[ETH_HLEN + 4..ETH_HLEN + 6].copy_from_slice(&len.to_be_bytes());
+        Verdict::Pass
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::read_name;
+    use crate::packet::ETHERTYPE_IPV6;
+
+    // the options of a frame
+    fn options(frame: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let ra = Ip::parse(frame).unwrap().payload;
+        let mut options = Vec::new();
+        let mut pos = RA_HLEN;
+        while pos < ra.len() {
+            let len = 8 * usize::from(ra[pos + 1]);
+            options.push((ra[pos], ra[pos + 2..pos + len].to_vec()));
+            pos += len;
+        }
+        options
+    }
+
+    fn advertisement(options: &[&[u8]]) -> Vec<u8> {
+        let mut ra = vec![ICMPV6_ROUTER_ADVERT, 0, 0, 0, 255, 0, 0x0e, 0x10];
+        ra.extend_from_slice(&[0; 8]);
+        for option in options {
+            ra.extend_from_slice(option);
+        }
+
+        let mut frame = vec![0x33, 0x33, 0, 0, 0, 1, 0x52, 0x56, 0, 0, 0, 2];
+        frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        frame.extend_from_slice(&[0x60, 0, 0, 0]);
+        frame.extend_from_slice(&(ra.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[IPPROTO_ICMPV6, 255]);
+        frame.extend_from_slice(&"fe80::2".parse::<Ipv6Addr>().unwrap().octets());
+        frame.extend_from_slice(&"ff02::1".parse::<Ipv6Addr>().unwrap().octets());
+        frame.extend(ra);
+        frame
+    }
+
+    #[test]
+    fn rewrite() {
+        let mut ra = RouterAdvert::default();
+        assert!(!ra.changes());
+        ra.mtu = Some(1400);
+        ra.router_lifetime = Some(0);
+        ra.managed = true;
+        ra.dns = vec!["fec0::3".parse().unwrap()];
+        ra.search = vec!["example.com".into(), "internal".into()];
+        assert!(ra.changes());
+
+        let link_addr: &[u8] = &[1, 1, 0x52, 0x56, 0, 0, 0, 2];
+        let rdnss: &[u8] = &[OPT_RDNSS, 3, 0, 0, 0, 0, 0, 60];
+        let mut rdnss = rdnss.to_vec();
+        rdnss.extend_from_slice(&[0; 16]);
+        let mut frame = advertisement(&[link_addr, &rdnss, &[OPT_MTU, 1, 0, 0, 0, 0, 5, 0xdc]]);
+        assert_eq!(ra.output(&mut frame, 0), Verdict::Pass);
+
+        let ip = Ip::parse(&frame).unwrap();
+        let msg = ip.payload;
+        assert_eq!(ip.offset + msg.len(), frame.len());
+        assert_eq!(msg[5], FLAG_MANAGED);
+        assert_eq!(&msg[6..8], &[0, 0]);
+        let pseudo = pseudo_header_sum(ip.src, ip.dst, IPPROTO_ICMPV6, msg.len());
+        assert_eq!(checksum(sum(pseudo, msg)), 0);
+
+        let options = options(&frame);
+        let kinds: Vec<u8> = options.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, vec![1, OPT_MTU, OPT_RDNSS, OPT_DNSSL]);
+        assert_eq!(options[1].1, vec![0, 0, 0, 0, 5, 0x78]);
+        assert_eq!(&options[2].1[2..6], &DNS_LIFETIME.to_be_bytes());
+        assert_eq!(&options[2].1[6..], &ra.dns[0].octets());
+        let names = &options[3].1[6..];
+        assert_eq!(names.len() % 8, 0);
+        assert_eq!(read_name(names, 0), Some(("example.com".to_string(), 13)));
+        assert_eq!(read_name(names, 13), Some(("internal".to_string(), 23)));
+
+        // not an advertisement
+        let mut solicit = advertisement(&[]);
+        solicit[ETH_HLEN + 40] = 133;
+        let mut out = solicit.clone();
+        ra.output(&mut out, 0);
+        assert_eq!(out, solicit);
+    }
+}
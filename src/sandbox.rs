@@ -0,0 +1,379 @@
+//! Confinement of the slirp loop, once the sockets are set up.
+//!
+//! A seccomp filter restricts the syscalls to the ones needed to serve the
+//! guest, and Landlock restricts the file system access to the TFTP root.
+//! The seccomp filter applies to all the threads of the process, but
+//! Landlock only to the calling thread and the threads it creates later:
+//! call [`restrict_fs`] before any thread is started (such as the timer
+//! threads of [`MioHandler`](crate::MioHandler)), and [`seccomp`] once the
+//! sockets are set up.
+
+use crate::Opt;
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+// from linux/filter.h
+const BPF_LD: u16 = 0x00;
+const BPF_W: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_JMP: u16 = 0x05;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JSET: u16 = 0x40;
+const BPF_K: u16 = 0x00;
+const BPF_RET: u16 = 0x06;
+
+// from linux/seccomp.h
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+// the low half of the first argument, on little-endian architectures
+const SECCOMP_DATA_ARG0: u32 = 16;
+const SECCOMP_SET_MODE_FILTER: libc::c_uint = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+
+// from linux/audit.h
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// from linux/landlock.h
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+// all the rights of the first ABI
+const LANDLOCK_ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// The syscalls of the slirp loop: polling, socket I/O and the TFTP files.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_lseek,
+    libc::SYS_openat,
+    libc::SYS_close,
+    // the sockets that fail to be bound to the outbound interface
+    libc::SYS_dup3,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_clock_gettime,
+    libc::SYS_gettimeofday,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_getrandom,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_futex,
+    // the start of the threads
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_rt_sigaction,
+    libc::SYS_sched_yield,
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_mprotect,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_sigaltstack,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_select,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+];
+
+fn err(msg: &str) -> io::Error {
+    let err = io::Error::last_os_error();
+    io::Error::new(err.kind(), format!("{}: {}", msg, err))
+}
+
+fn no_new_privs() -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(err("Failed to set no_new_privs"));
+    }
+    Ok(())
+}
+
+fn bpf_stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn bpf_jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+/// The seccomp filter program, allowing `syscalls` only, and the creation
+/// of threads, such as the ones of the timers created by libslirp later.
+///
+/// clone3 fails with ENOSYS, so that the C library falls back to clone,
+/// whose flags can be checked.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp_filter(syscalls: &[libc::c_long]) -> Vec<libc::sock_filter> {
+    let mut filter = vec![
+        bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARCH),
+        bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+        bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR),
+        bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone3 as u32, 0, 1),
+        bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone as u32, 0, 4),
+        bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARG0),
+        bpf_jump(BPF_JMP | BPF_JSET | BPF_K, libc::CLONE_THREAD as u32, 0, 1),
+        bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+        bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+    ];
+    for nr in syscalls {
+        filter.push(bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, 0, 1));
+        filter.push(bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+    }
+    filter.push(bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS));
+    filter
+}
+
+/// Install the seccomp filter on all the threads of the process: any other
+/// syscall kills the process.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn seccomp() -> io::Result<()> {
+    let filter = seccomp_filter(ALLOWED_SYSCALLS);
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut _,
+    };
+
+    no_new_privs()?;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &prog,
+        )
+    };
+    if ret < 0 {
+        return Err(err("Failed to install the seccomp filter"));
+    }
+    if ret > 0 {
+        // the thread that couldn't be synchronized
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to install the seccomp filter on thread {}", ret),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn seccomp() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "seccomp filter not available on this architecture",
+    ))
+}
+
+/// Restrict the file system access of the calling thread to reading beneath
+/// `read`, and writing the files of `write`.
+pub fn landlock(read: &[&Path], write: &[&Path]) -> io::Result<()> {
+    let abi = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Err(err("Landlock is not supported"));
+    }
+
+    let attr = RulesetAttr {
+        handled_access_fs: LANDLOCK_ACCESS_FS_ABI_1,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr,
+            mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(err("Failed to create the Landlock ruleset"));
+    }
+    let ruleset = unsafe { File::from_raw_fd(fd as i32) };
+
+    let rules = read
+        .iter()
+        .map(|path| (path, false))
+        .chain(write.iter().map(|path| (path, true)));
+    for (path, writable) in rules {
+        let cpath = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(err(&format!("Failed to open {}", path.display())));
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let mut access = if writable {
+            LANDLOCK_ACCESS_FS_WRITE_FILE
+        } else {
+            LANDLOCK_ACCESS_FS_READ_FILE
+        };
+        if !writable && file.metadata()?.is_dir() {
+            access |= LANDLOCK_ACCESS_FS_READ_DIR;
+        }
+        let rule = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: file.as_raw_fd(),
+        };
+        let ret = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_ADD_RULE,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(err(&format!("Failed to allow {}", path.display())));
+        }
+    }
+
+    no_new_privs()?;
+    if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0) } < 0 {
+        return Err(err("Failed to enforce the Landlock ruleset"));
+    }
+    Ok(())
+}
+
+/// Restrict the file system access according to `opt`, before any thread
+/// is started.
+///
+/// When TFTP is enabled, reads are restricted to its root and archive, the
+/// hosts file and the resolver configuration, read by the DNS forwarder.
+/// The DNS log is created if needed, and can only be written.
+pub fn restrict_fs(opt: &Opt) -> io::Result<()> {
+    if opt.tftp.root.is_none() && opt.tftp.archive.is_none() {
+        return Ok(());
+    }
+
+    let mut read: Vec<&Path> = opt
+        .tftp
+        .root
+        .iter()
+        .chain(&opt.tftp.archive)
+        .map(PathBuf::as_path)
+        .collect();
+    if let Some(hosts) = &opt.hosts_file {
+        read.push(hosts);
+    }
+    let resolv = Path::new("/etc/resolv.conf");
+    if resolv.exists() {
+        read.push(resolv);
+    }
+    let mut write = Vec::new();
+    if let Some(log) = &opt.dns_log {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", log.display(), e)))?;
+        write.push(log.as_path());
+    }
+
+    landlock(&read, &write)
+}
+
+#[cfg(test)]
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter() {
+        let filter = seccomp_filter(&[libc::SYS_read, libc::SYS_write]);
+        assert_eq!(filter.len(), 11 + 2 * 2 + 1);
+        assert_eq!(filter[1].k, AUDIT_ARCH);
+        // clone is allowed for threads only, and the rest of its check skipped
+        assert_eq!(filter[6].k, libc::SYS_clone as u32);
+        assert_eq!(filter[6].jf, 4);
+        assert_eq!(filter[8].k, libc::CLONE_THREAD as u32);
+        assert_eq!(filter[9].k, SECCOMP_RET_ALLOW);
+        assert_eq!(filter[10].k, SECCOMP_RET_KILL_PROCESS);
+        assert_eq!(filter[11].k, libc::SYS_read as u32);
+        // a mismatch skips the allow statement
+        assert_eq!((filter[11].jt, filter[11].jf), (0, 1));
+        assert_eq!(filter[12].k, SECCOMP_RET_ALLOW);
+        assert_eq!(filter[15].k, SECCOMP_RET_KILL_PROCESS);
+    }
+}
@@ -0,0 +1,650 @@
+//! A TFTP server answering the guest from Rust, with the options of RFC
+//! 2347 (negotiation), 2348 (blksize) and 2349 (timeout and tsize).
+//!
+//! The files come from a [`TftpSource`], such as a directory, files in
+//! memory or a tar archive. They are read-only.
+
+pub mod source;
+
+pub use self::source::{Dir, Memory, Tar, TftpFile, TftpSource};
+
+use crate::filter::{Filter, Verdict};
+use crate::packet::{udp_frame, Udp};
+use crate::Opt;
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+
+pub const PORT: u16 = 69;
+
+const OP_RRQ: u16 = 1;
+const OP_WRQ: u16 = 2;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+const ERR_UNDEFINED: u16 = 0;
+const ERR_NOT_FOUND: u16 = 1;
+const ERR_ACCESS: u16 = 2;
+const ERR_ILLEGAL: u16 = 4;
+
+const BLKSIZE: usize = 512;
+const MIN_BLKSIZE: usize = 8;
+// the IP, UDP and TFTP headers of a block, for its largest size in the MTU
+const HLEN4: usize = 20 + 8 + 4;
+const HLEN6: usize = 40 + 8 + 4;
+const MTU: usize = 1500;
+// seconds
+const TIMEOUT: i64 = 5;
+// the last packet is sent again that many times without an ACK, before the
+// transfer is given up
+const RETRIES: u32 = 5;
+// the ports of the transfers, from the dynamic range
+const FIRST_PORT: u16 = 49152;
+// the concurrent transfers, of a guest address and in total
+const MAX_GUEST_TRANSFERS: usize = 8;
+const MAX_TRANSFERS: usize = 64;
+
+struct Transfer {
+    // the request of the guest, without its payload, to send the packets
+    guest: Udp<'static>,
+    path: String,
+    data: Box<dyn Read>,
+    blksize: usize,
+    // in nanoseconds
+    timeout: i64,
+    // the last block sent, 0 for the OACK
+    block: u16,
+    // the last packet sent, for a retransmission, when it was sent, and the
+    // timeouts since the last ACK
+    last: Vec<u8>,
+    last_sent: i64,
+    retries: u32,
+    done: bool,
+    sent: u64,
+    start: i64,
+}
+
+/// A filter serving the TFTP read requests of the guest to the host
+/// addresses, instead of libslirp.
+///
+/// The last packet of a transfer is sent again once its timeout passes
+/// without an ACK, or when the guest retransmits its own ACK. The transfers
+/// that the guest stopped acknowledging are dropped after a few timeouts,
+/// and the requests beyond a few concurrent transfers are refused.
+pub struct Tftp {
+    addrs: Vec<IpAddr>,
+    source: Box<dyn TftpSource>,
+    // by the guest address and the port of the transfer
+    transfers: HashMap<(SocketAddr, u16), Transfer>,
+    next_port: u16,
+    mtu: usize,
+    log: bool,
+}
+
+fn packet(op: u16, block: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + data.len());
+    buf.extend_from_slice(&op.to_be_bytes());
+    buf.extend_from_slice(&block.to_be_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+fn error(code: u16, msg: &str) -> Vec<u8> {
+    let mut buf = packet(OP_ERROR, code, msg.as_bytes());
+    buf.push(0);
+    buf
+}
+
+fn io_error(e: &io::Error) -> Vec<u8> {
+    match e.kind() {
+        io::ErrorKind::NotFound => error(ERR_NOT_FOUND, "File not found"),
+        io::ErrorKind::PermissionDenied => error(ERR_ACCESS, "Access violation"),
+        _ => error(ERR_UNDEFINED, &e.to_string()),
+    }
+}
+
+// read a whole block, unless at the end of the file
+fn read_block(data: &mut dyn Read, size: usize) -> io::Result<Vec<u8>> {
+    let mut block = vec![0; size];
+    let mut len = 0;
+    while len < size {
+        match data.read(&mut block[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    block.truncate(len);
+    Ok(block)
+}
+
+struct Request {
+    path: String,
+    mode: String,
+    // lowercase names, and values
+    options: Vec<(String, String)>,
+}
+
+fn request(msg: &[u8]) -> Option<Request> {
+    let mut fields = msg[2..]
+        .split(|&b| b == 0)
+        .map(|f| String::from_utf8_lossy(f).into_owned());
+    let path = fields.next()?;
+    let mode = fields.next()?.to_ascii_lowercase();
+    if path.is_empty() || !msg.ends_with(&[0]) {
+        return None;
+    }
+    let mut options = Vec::new();
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        options.push((name.to_ascii_lowercase(), value));
+    }
+    Some(Request {
+        path,
+        mode,
+        options,
+    })
+}
+
+impl Tftp {
+    /// Serve the files of `source` on the `addrs` of the host.
+    pub fn new(addrs: &[IpAddr], source: Box<dyn TftpSource>) -> Self {
+        Self {
+            addrs: addrs.to_vec(),
+            source,
+            transfers: HashMap::new(),
+            next_port: FIRST_PORT,
+            mtu: MTU,
+            log: false,
+        }
+    }
+
+    /// Serve the files of `source` on the host addresses of `opt`, logging
+    /// as set by `--tftp-log`.
+    pub fn with_opt(opt: &Opt, source: Box<dyn TftpSource>) -> Self {
+        let mut addrs = Vec::new();
+        if !opt.ipv4.disable {
+            addrs.push(opt.ipv4.host().into());
+        }
+        if !opt.ipv6.disable {
+            addrs.push(opt.ipv6.host().into());
+        }
+        let mut tftp = Self::new(&addrs, source);
+        tftp.set_log(opt.tftp.log);
+        tftp.set_mtu(opt.mtu() as usize);
+        tftp
+    }
+
+    /// The server of the `--tftp-archive`, or else of the `--tftp-root`.
+    pub fn from_opt(opt: &Opt) -> io::Result<Self> {
+        let source: Box<dyn TftpSource> = match (&opt.tftp.archive, &opt.tftp.root) {
+            (Some(archive), _) => Box::new(Tar::open(archive)?),
+            (None, Some(root)) => Box::new(Dir::new(root)),
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no TFTP root or archive",
+                ))
+            }
+        };
+        Ok(Self::with_opt(opt, source))
+    }
+
+    /// Log the transfers to stderr, with the address of the guest.
+    pub fn set_log(&mut self, log: bool) {
+        self.log = log;
+    }
+
+    /// The MTU of the link, which bounds the block size, 1500 by default.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+    }
+
+    fn port(&mut self, guest: SocketAddr) -> u16 {
+        loop {
+            let port = self.next_port;
+            self.next_port = self.next_port.checked_add(1).unwrap_or(FIRST_PORT);
+            if !self.transfers.contains_key(&(guest, port)) {
+                return port;
+            }
+        }
+    }
+
+    fn finish(&mut self, key: (SocketAddr, u16), now: i64, result: Result<(), &str>) {
+        let t = match self.transfers.remove(&key) {
+            Some(t) => t,
+            None => return,
+        };
+        if !self.log {
+            return;
+        }
+        match result {
+            Ok(()) => eprintln!(
+                "tftp: {} {} {} bytes in {} ms",
+                key.0.ip(),
+                t.path,
+                t.sent,
+                (now - t.start) / 1_000_000
+            ),
+            Err(e) => eprintln!(
+                "tftp: {} {} {} after {} bytes",
+                key.0.ip(),
+                t.path,
+                e,
+                t.sent
+            ),
+        }
+    }
+
+    /// Send the last packets again once their timeout passed, and give up
+    /// the transfers without an ACK after a few times.
+    fn retransmit(&mut self, now: i64, reply: &mut Vec<Vec<u8>>) {
+        let mut expired = Vec::new();
+        for (key, t) in &mut self.transfers {
+            if now < t.last_sent + t.timeout {
+                continue;
+            }
+            if t.retries >= RETRIES {
+                expired.push(*key);
+                continue;
+            }
+            t.retries += 1;
+            t.last_sent = now;
+            reply.push(send(&t.guest, key.1, &t.last));
+        }
+        for key in expired {
+            self.finish(key, now, Err("timed out"));
+        }
+    }
+
+    fn read_request(&mut self, udp: &Udp, now: i64, reply: &mut Vec<Vec<u8>>) {
+        let msg = udp.payload;
+        let op = if msg.len() >= 2 {
+            u16::from_be_bytes([msg[0], msg[1]])
+        } else {
+            0
+        };
+        if op == OP_WRQ {
+            reply.push(udp.reply(&error(ERR_ACCESS, "Read-only server")));
+            return;
+        }
+        let req = if op == OP_RRQ { request(msg) } else { None };
+        let Request {
+            path,
+            mode,
+            options,
+        } = match req {
+            Some(req) => req,
+            None => {
+                reply.push(udp.reply(&error(ERR_ILLEGAL, "Illegal TFTP operation")));
+                return;
+            }
+        };
+        // netascii files are sent as they are
+        if mode != "octet" && mode != "netascii" {
+            reply.push(udp.reply(&error(ERR_ILLEGAL, "Unsupported mode")));
+            return;
+        }
+        let guest = self
+            .transfers
+            .keys()
+            .filter(|(addr, _)| addr.ip() == udp.src.ip())
+            .count();
+        if guest >= MAX_GUEST_TRANSFERS || self.transfers.len() >= MAX_TRANSFERS {
+            if self.log {
+                eprintln!("tftp: {} {} too many transfers", udp.src.ip(), path);
+            }
+            reply.push(udp.reply(&error(ERR_UNDEFINED, "Too many transfers")));
+            return;
+        }
+        let file = match self.source.open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                if self.log {
+                    eprintln!("tftp: {} {} {}", udp.src.ip(), path, e);
+                }
+                reply.push(udp.reply(&io_error(&e)));
+                return;
+            }
+        };
+
+        let max_blksize = match udp.dst {
+            SocketAddr::V4(_) => self.mtu.saturating_sub(HLEN4),
+            SocketAddr::V6(_) => self.mtu.saturating_sub(HLEN6),
+        }
+        .max(MIN_BLKSIZE);
+        let mut oack = Vec::new();
+        let mut blksize = BLKSIZE;
+        let mut timeout = TIMEOUT;
+        for (name, value) in options {
+            let value = match name.as_str() {
+                "blksize" => match value.parse::<usize>() {
+                    Ok(size) if size >= MIN_BLKSIZE => {
+                        blksize = size.min(max_blksize);
+                        blksize.to_string()
+                    }
+                    _ => continue,
+                },
+                "timeout" => match value.parse::<i64>() {
+                    Ok(secs) if (1..=255).contains(&secs) => {
+                        timeout = secs;
+                        value
+                    }
+                    _ => continue,
+                },
+                "tsize" => file.size.to_string(),
+                _ => continue,
+            };
+            oack.extend_from_slice(name.as_bytes());
+            oack.push(0);
+            oack.extend_from_slice(value.as_bytes());
+            oack.push(0);
+        }
+
+        let port = self.port(udp.src);
+        let key = (udp.src, port);
+        self.transfers.insert(
+            key,
+            Transfer {
+                guest: Udp {
+                    src_mac: udp.src_mac,
+                    dst_mac: udp.dst_mac,
+                    src: udp.src,
+                    dst: udp.dst,
+                    payload: &[],
+                },
+                path,
+                data: file.data,
+                blksize,
+                timeout: timeout * 1_000_000_000,
+                block: 0,
+                last: Vec::new(),
+                last_sent: now,
+                retries: 0,
+                done: false,
+                sent: 0,
+                start: now,
+            },
+        );
+        if oack.is_empty() {
+            self.next_block(udp, key, now, reply);
+        } else {
+            let mut msg = OP_OACK.to_be_bytes().to_vec();
+            msg.extend(oack);
+            reply.push(send(udp, port, &msg));
+            self.transfers.get_mut(&key).unwrap().last = msg;
+        }
+    }
+
+    fn next_block(
+        &mut self,
+        udp: &Udp,
+        key: (SocketAddr, u16),
+        now: i64,
+        reply: &mut Vec<Vec<u8>>,
+    ) {
+        let t = match self.transfers.get_mut(&key) {
+            Some(t) => t,
+            None => return,
+        };
+        let block = match read_block(&mut t.data, t.blksize) {
+            Ok(block) => block,
+            Err(e) => {
+                reply.push(send(udp, key.1, &io_error(&e)));
+                self.finish(key, now, Err("failed"));
+                return;
+            }
+        };
+        // block numbers wrap around, for the files of more than 65535 blocks
+        t.block = t.block.wrapping_add(1);
+        t.done = block.len() < t.blksize;
+        t.sent += block.len() as u64;
+        t.last = packet(OP_DATA, t.block, &block);
+        t.last_sent = now;
+        t.retries = 0;
+        reply.push(send(udp, key.1, &t.last));
+    }
+
+    fn transfer(&mut self, udp: &Udp, key: (SocketAddr, u16), now: i64, reply: &mut Vec<Vec<u8>>) {
+        let msg = udp.payload;
+        if msg.len() < 4 {
+            return;
+        }
+        let op = u16::from_be_bytes([msg[0], msg[1]]);
+        let block = u16::from_be_bytes([msg[2], msg[3]]);
+        let t = match self.transfers.get_mut(&key) {
+            Some(t) => t,
+            None => return,
+        };
+
+        match op {
+            OP_ACK if block == t.block => {
+                if t.done {
+                    self.finish(key, now, Ok(()));
+                } else {
+                    self.next_block(udp, key, now, reply);
+                }
+            }
+            // the guest timed out, waiting for the last packet
+            OP_ACK if block == t.block.wrapping_sub(1) => {
+                t.last_sent = now;
+                t.retries = 0;
+                reply.push(send(udp, key.1, &t.last));
+            }
+            OP_ACK => (),
+            // such as once the guest got the tsize it asked for
+            OP_ERROR => self.finish(key, now, Err("aborted by the guest")),
+            _ => {
+                reply.push(send(
+                    udp,
+                    key.1,
+                    &error(ERR_ILLEGAL, "Illegal TFTP operation"),
+                ));
+                self.finish(key, now, Err("failed"));
+            }
+        }
+    }
+}
+
+// a packet of a transfer, from its port
+fn send(udp: &Udp, port: u16, payload: &[u8]) -> Vec<u8> {
+    let src = SocketAddr::new(udp.dst.ip(), port);
+    udp_frame(udp.dst_mac, udp.src_mac, src, udp.src, payload)
+}
+
+impl Filter for Tftp {
+    fn input(&mut self, frame: &[u8], now: i64, reply: &mut Vec<Vec<u8>>) -> Verdict {
+        let udp = match Udp::parse(frame) {
+            Some(udp) if self.addrs.contains(&udp.dst.ip()) => udp,
+            _ => return Verdict::Pass,
+        };
+
+        if udp.dst.port() == PORT {
+            self.read_request(&udp, now, reply);
+            return Verdict::Drop;
+        }
+        let key = (udp.src, udp.dst.port());
+        if !self.transfers.contains_key(&key) {
+            return Verdict::Pass;
+        }
+        self.transfer(&udp, key, now, reply);
+        Verdict::Drop
+    }
+
+    fn deadline(&self) -> Option<i64> {
+        self.transfers
+            .values()
+            .map(|t| t.last_sent + t.timeout)
+            .min()
+    }
+
+    fn timeout(&mut self, now: i64, reply: &mut Vec<Vec<u8>>) {
+        self.retransmit(now, reply);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST: &str = "10.0.2.15:2000";
+
+    fn rrq(path: &str, options: &[(&str, &str)]) -> Vec<u8> {
+        let mut msg = OP_RRQ.to_be_bytes().to_vec();
+        for s in [path, "octet"]
+            .iter()
+            .chain(options.iter().flat_map(|(n, v)| vec![n, v]))
+        {
+            msg.extend_from_slice(s.as_bytes());
+            msg.push(0);
+        }
+        msg
+    }
+
+    fn server() -> Tftp {
+        let mut memory = Memory::new();
+        memory.insert("pxelinux.0", vec![7; 1000]);
+        memory.insert("empty", Vec::new());
+        Tftp::new(&["10.0.2.2".parse().unwrap()], Box::new(memory))
+    }
+
+    // send a packet of the guest to `port`, and the packets of the server
+    fn exchange(
+        tftp: &mut Tftp,
+        port: u16,
+        msg: &[u8],
+        now: i64,
+    ) -> (Verdict, Vec<(u16, Vec<u8>)>) {
+        let frame = udp_frame(
+            [2; 6],
+            [4; 6],
+            GUEST.parse().unwrap(),
+            SocketAddr::new("10.0.2.2".parse().unwrap(), port),
+            msg,
+        );
+        let mut reply = Vec::new();
+        let verdict = tftp.input(&frame, now, &mut reply);
+        let reply = reply
+            .iter()
+            .map(|frame| {
+                let udp = Udp::parse(frame).unwrap();
+                assert_eq!(udp.dst, GUEST.parse().unwrap());
+                (udp.src.port(), udp.payload.to_vec())
+            })
+            .collect();
+        (verdict, reply)
+    }
+
+    fn ack(block: u16) -> Vec<u8> {
+        packet(OP_ACK, block, &[])
+    }
+
+    #[test]
+    fn transfer() {
+        let mut tftp = server();
+        let (verdict, reply) = exchange(&mut tftp, PORT, &rrq("/pxelinux.0", &[]), 0);
+        assert_eq!(verdict, Verdict::Drop);
+        let (port, data) = &reply[0];
+        assert_eq!(&data[..4], &[0, 3, 0, 1]);
+        assert_eq!(data.len(), 4 + 512);
+
+        // a retransmission, and the last block
+        let (_, reply) = exchange(&mut tftp, *port, &ack(0), 1);
+        assert_eq!(&reply[0].1, data);
+        let (_, reply) = exchange(&mut tftp, *port, &ack(1), 2);
+        assert_eq!(&reply[0].1[..4], &[0, 3, 0, 2]);
+        assert_eq!(reply[0].1.len(), 4 + 1000 - 512);
+        let (verdict, reply) = exchange(&mut tftp, *port, &ack(2), 3);
+        assert_eq!((verdict, reply.len()), (Verdict::Drop, 0));
+        assert!(tftp.transfers.is_empty());
+
+        // not a transfer
+        let (verdict, _) = exchange(&mut tftp, *port, &ack(2), 4);
+        assert_eq!(verdict, Verdict::Pass);
+
+        // an empty file is a single empty block
+        let (_, reply) = exchange(&mut tftp, PORT, &rrq("empty", &[]), 0);
+        assert_eq!(reply[0].1, vec![0, 3, 0, 1]);
+    }
+
+    #[test]
+    fn options() {
+        let mut tftp = server();
+        let options = [
+            ("BLKSIZE", "1432"),
+            ("tsize", "0"),
+            ("timeout", "1"),
+            ("multicast", ""),
+        ];
+        let (_, reply) = exchange(&mut tftp, PORT, &rrq("pxelinux.0", &options), 0);
+        let (port, oack) = &reply[0];
+        assert_eq!(
+            &oack[..],
+            &b"\0\x06blksize\x001432\0tsize\x001000\0timeout\x001\0"[..]
+        );
+
+        let (_, reply) = exchange(&mut tftp, *port, &ack(0), 1);
+        let data = &reply[0].1;
+        assert_eq!(data.len(), 4 + 1000);
+        // sent again on each timeout, then dropped after 5 of them
+        for i in 1..=5 {
+            let deadline = tftp.deadline().unwrap();
+            assert_eq!(deadline, 1 + i * 1_000_000_000);
+            let mut reply = Vec::new();
+            tftp.timeout(deadline, &mut reply);
+            assert_eq!(Udp::parse(&reply[0]).unwrap().payload, &data[..]);
+        }
+        let mut reply = Vec::new();
+        tftp.timeout(tftp.deadline().unwrap(), &mut reply);
+        assert!(reply.is_empty());
+        assert!(tftp.transfers.is_empty());
+        assert_eq!(tftp.deadline(), None);
+
+        // larger blocks than in a frame, and the guest aborting
+        let (_, reply) = exchange(
+            &mut tftp,
+            PORT,
+            &rrq("pxelinux.0", &[("blksize", "65464")]),
+            0,
+        );
+        let (port, oack) = &reply[0];
+        assert_eq!(&oack[..], &b"\0\x06blksize\x001468\0"[..]);
+        exchange(&mut tftp, *port, &error(0, "enough"), 1);
+        assert!(tftp.transfers.is_empty());
+
+        // in the MTU of the link
+        tftp.set_mtu(9000);
+        let (_, reply) = exchange(
+            &mut tftp,
+            PORT,
+            &rrq("pxelinux.0", &[("blksize", "65464")]),
+            0,
+        );
+        assert_eq!(&reply[0].1[..], &b"\0\x06blksize\x008968\0"[..]);
+    }
+
+    #[test]
+    fn errors() {
+        let mut tftp = server();
+        let (_, reply) = exchange(&mut tftp, PORT, &rrq("missing", &[]), 0);
+        assert_eq!(reply[0], (PORT, b"\0\x05\0\x01File not found\0".to_vec()));
+        let mut wrq = rrq("pxelinux.0", &[]);
+        wrq[1] = OP_WRQ as u8;
+        let (_, reply) = exchange(&mut tftp, PORT, &wrq, 0);
+        assert_eq!(&reply[0].1[..4], &[0, 5, 0, 2]);
+        let (_, reply) = exchange(&mut tftp, PORT, b"\0\x01truncated", 0);
+        assert_eq!(&reply[0].1[..4], &[0, 5, 0, 4]);
+        assert!(tftp.transfers.is_empty());
+
+        for _ in 0..MAX_GUEST_TRANSFERS {
+            let (_, reply) = exchange(&mut tftp, PORT, &rrq("pxelinux.0", &[]), 0);
+            assert_eq!(&reply[0].1[..4], &[0, 3, 0, 1]);
+        }
+        let (_, reply) = exchange(&mut tftp, PORT, &rrq("pxelinux.0", &[]), 0);
+        assert_eq!(reply[0], (PORT, b"\0\x05\0\0Too many transfers\0".to_vec()));
+        assert_eq!(tftp.transfers.len(), MAX_GUEST_TRANSFERS);
+    }
+}
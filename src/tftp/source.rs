@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+const BLOCK: u64 = 512;
+
+/// A file opened for a transfer.
+pub struct TftpFile {
+    pub size: u64,
+    pub data: Box<dyn Read>,
+}
+
+/// The files served over TFTP. They are read-only.
+pub trait TftpSource {
+    /// Open the file at `path`, relative to the root of the source and
+    /// without any `..` component.
+    fn open(&self, path: &str) -> io::Result<TftpFile>;
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{}: not found", path))
+}
+
+/// The normalized relative path of a requested file, or `None` if it is
+/// outside of the root.
+pub(crate) fn normalize(path: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for c in Path::new(path).components() {
+        match c {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("/"))
+    }
+}
+
+/// The files of a directory.
+#[derive(Debug, Clone)]
+pub struct Dir {
+    root: PathBuf,
+}
+
+impl Dir {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl TftpSource for Dir {
+    fn open(&self, path: &str) -> io::Result<TftpFile> {
+        let path = normalize(path).ok_or_else(|| not_found(path))?;
+        let file = File::open(self.root.join(&path))?;
+        let meta = file.metadata()?;
+        if !meta.is_file() {
+            return Err(not_found(&path));
+        }
+        Ok(TftpFile {
+            size: meta.len(),
+            data: Box::new(file),
+        })
+    }
+}
+
+/// Files in memory, such as generated boot images.
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    files: HashMap<String, Rc<[u8]>>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace the file at `path`.
+    pub fn insert<D: Into<Vec<u8>>>(&mut self, path: &str, data: D) {
+        if let Some(path) = normalize(path) {
+            self.files.insert(path, data.into().into());
+        }
+    }
+}
+
+impl TftpSource for Memory {
+    fn open(&self, path: &str) -> io::Result<TftpFile> {
+        let data = normalize(path)
+            .and_then(|p| self.files.get(&p))
+            .ok_or_else(|| not_found(path))?;
+        Ok(TftpFile {
+            size: data.len() as u64,
+            data: Box::new(Cursor::new(data.clone())),
+        })
+    }
+}
+
+/// The regular files of a tar archive, read from the archive on demand.
+///
+/// The archive stays open, so that its files can still be read once the
+/// file system access is restricted.
+#[derive(Debug, Clone)]
+pub struct Tar {
+    file: Rc<File>,
+    // offset and size of the files
+    files: HashMap<String, (u64, u64)>,
+}
+
+/// A file of an archive, read at its offset in the shared archive file.
+struct Entry {
+    file: Rc<File>,
+    pos: u64,
+    end: u64,
+}
+
+impl Read for Entry {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.end - self.pos) as usize;
+        let len = self.file.read_at(&mut buf[..len], self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+fn octal(field: &[u8]) -> Option<u64> {
+    let s = std::str::from_utf8(field).ok()?;
+    let s = s.trim_matches(|c| c == '\0' || c == ' ');
+    if s.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(s, 8).ok()
+}
+
+fn field(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+fn invalid(path: &Path, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), what),
+    )
+}
+
+impl Tar {
+    /// Index the files of the archive at `path`, in the ustar or GNU format.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let mut file = File::open(&path)?;
+        let len = fs::metadata(&path)?.len();
+        let mut files = HashMap::new();
+        let mut long_name = None;
+        let mut offset = 0;
+
+        loop {
+            let mut header = [0; BLOCK as usize];
+            if offset + BLOCK > len {
+                break;
+            }
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut header)?;
+            // the end of the archive
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+
+            let size = octal(&header[124..136]).ok_or_else(|| invalid(&path, "bad size"))?;
+            let data = offset + BLOCK;
+            if data + size > len {
+                return Err(invalid(&path, "truncated archive"));
+            }
+            let mut name = String::from_utf8_lossy(field(&header[0..100])).into_owned();
+            if &header[257..262] == b"ustar" {
+                let prefix = field(&header[345..500]);
+                if !prefix.is_empty() {
+                    name = format!("{}/{}", String::from_utf8_lossy(prefix), name);
+                }
+            }
+
+            match header[156] {
+                b'0' | 0 => {
+                    let name = long_name.take().unwrap_or(name);
+                    if let Some(name) = normalize(&name) {
+                        files.insert(name, (data, size));
+                    }
+                }
+                // the name of the next entry
+                b'L' => {
+                    let mut buf = vec![0; size as usize];
+                    file.read_exact(&mut buf)?;
+                    long_name = Some(String::from_utf8_lossy(field(&buf)).into_owned());
+                }
+                _ => long_name = None,
+            }
+            offset = data + (size + BLOCK - 1) / BLOCK * BLOCK;
+        }
+
+        Ok(Self {
+            file: Rc::new(file),
+            files,
+        })
+    }
+
+    /// The paths of the files of the archive.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+}
+
+impl TftpSource for Tar {
+    fn open(&self, path: &str) -> io::Result<TftpFile> {
+        let &(offset, size) = normalize(path)
+            .and_then(|p| self.files.get(&p))
+            .ok_or_else(|| not_found(path))?;
+        Ok(TftpFile {
+            size,
+            data: Box::new(Entry {
+                file: self.file.clone(),
+                pos: offset,
+                end: offset + size,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn read(source: &dyn TftpSource, path: &str) -> io::Result<Vec<u8>> {
+        let mut file = source.open(path)?;
+        let mut data = Vec::new();
+        file.data.read_to_end(&mut data)?;
+        assert_eq!(file.size, data.len() as u64);
+        Ok(data)
+    }
+
+    fn header(name: &str, size: usize, kind: u8) -> Vec<u8> {
+        let mut h = vec![0; 512];
+        h[..name.len()].copy_from_slice(name.as_bytes());
+        h[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        h[156] = kind;
+        h[257..263].copy_from_slice(b"ustar\0");
+        h
+    }
+
+    fn entry(tar: &mut Vec<u8>, name: &str, data: &[u8], kind: u8) {
+        tar.extend(header(name, data.len(), kind));
+        tar.extend_from_slice(data);
+        tar.resize((tar.len() + 511) / 512 * 512, 0);
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(normalize("/pxelinux.0"), Some("pxelinux.0".to_string()));
+        assert_eq!(
+            normalize("./efi//boot.efi"),
+            Some("efi/boot.efi".to_string())
+        );
+        assert_eq!(normalize("efi/../../etc/passwd"), None);
+        assert_eq!(normalize("/"), None);
+    }
+
+    #[test]
+    fn sources() {
+        let mut memory = Memory::new();
+        memory.insert("/boot/vmlinuz", &b"kernel"[..]);
+        assert_eq!(read(&memory, "boot/vmlinuz").unwrap(), b"kernel");
+        assert!(read(&memory, "boot/initrd").is_err());
+
+        let dir = env::temp_dir().join(format!("slirp-tftp-{}", process::id()));
+        fs::create_dir_all(dir.join("efi")).unwrap();
+        fs::write(dir.join("efi/boot.efi"), b"efi").unwrap();
+        let mut tar = Vec::new();
+        entry(&mut tar, "./pxelinux.0", &[1; 600], b'0');
+        entry(&mut tar, "efi", b"", b'5');
+        let long = format!("{}/boot.efi", "x".repeat(120));
+        entry(&mut tar, "././@LongLink", long.as_bytes(), b'L');
+        entry(&mut tar, "xxx", b"long", b'0');
+        tar.extend(vec![0; 1024]);
+        fs::write(dir.join("boot.tar"), &tar).unwrap();
+
+        let source = Dir::new(&dir);
+        assert_eq!(read(&source, "/efi/boot.efi").unwrap(), b"efi");
+        assert!(read(&source, "efi").is_err());
+        assert!(read(&source, "../boot.efi").is_err());
+
+        let tar = Tar::open(dir.join("boot.tar")).unwrap();
+        let mut paths: Vec<_> = tar.paths().collect();
+        paths.sort();
+        assert_eq!(paths, vec!["pxelinux.0", &long[..]]);
+        assert_eq!(read(&tar, "pxelinux.0").unwrap(), vec![1; 600]);
+        assert_eq!(read(&tar, &long).unwrap(), b"long");
+        assert!(read(&tar, "efi").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
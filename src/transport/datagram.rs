@@ -0,0 +1,55 @@
+use super::{set_nonblocking, Transport};
+
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+/// A file descriptor where each read or write is a whole frame, such as a
+/// datagram socket or a TAP device.
+#[derive(Debug)]
+pub struct Datagram {
+    file: File,
+}
+
+impl Datagram {
+    pub fn new<T: IntoRawFd>(fd: T) -> io::Result<Self> {
+        let file = unsafe { File::from_raw_fd(fd.into_raw_fd()) };
+        set_nonblocking(file.as_raw_fd())?;
+        Ok(Self { file })
+    }
+
+    /// Take ownership of `fd`, and make it non-blocking.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open file descriptor, owned by nothing else.
+    pub unsafe fn from_fd(fd: RawFd) -> io::Result<Self> {
+        let file = File::from_raw_fd(fd);
+        set_nonblocking(fd)?;
+        Ok(Self { file })
+    }
+}
+
+impl AsRawFd for Datagram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Transport for Datagram {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.file.read(buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.file.write(buf) {
+            // the frame is dropped, as a NIC would do
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            res => res,
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+pub mod datagram;
+pub mod stream;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod tap;
+pub mod vde;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod vhost_user;
+
+pub use self::datagram::Datagram;
+pub use self::stream::Stream;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::tap::Tap;
+pub use self::vde::Vde;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::vhost_user::VhostUser;
+
+/// A way of exchanging ethernet frames with the guest.
+///
+/// The file descriptor is non-blocking, and polled for readability by the
+/// event loop, which then calls `recv` until it returns `None`.
+pub trait Transport: AsRawFd {
+    /// Receive the next frame in `buf`, returning its length, or `None` if
+    /// no complete frame is available without blocking.
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+
+    /// Send a frame. It may be buffered, in which case `flush` has to be
+    /// called when the file descriptor becomes writable.
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Try to write the buffered output.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether some output is waiting for the file descriptor to be writable.
+    fn wants_write(&self) -> bool {
+        false
+    }
+}
+
+pub(crate) fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
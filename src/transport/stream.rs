@@ -0,0 +1,240 @@
+use super::{set_nonblocking, Transport};
+
+use std::io::{self, prelude::*};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// beyond that, frames are dropped until the peer catches up
+const MAX_PENDING: usize = 1024 * 1024;
+
+/// A stream socket carrying frames prefixed with their length, as 32-bit big
+/// endian, like QEMU `-netdev stream` or `-netdev socket`.
+#[derive(Debug)]
+pub struct Stream<S> {
+    sock: S,
+    rbuf: Vec<u8>,
+    wbuf: Vec<u8>,
+    // the bytes left of a frame too large, to discard
+    skip: usize,
+}
+
+impl<S: Read + Write + AsRawFd> Stream<S> {
+    pub fn new(sock: S) -> io::Result<Self> {
+        set_nonblocking(sock.as_raw_fd())?;
+        Ok(Self {
+            sock,
+            rbuf: Vec::new(),
+            wbuf: Vec::new(),
+            skip: 0,
+        })
+    }
+
+    fn next_frame(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let skip = self.skip.min(self.rbuf.len());
+        self.rbuf.drain(..skip);
+        self.skip -= skip;
+        if self.skip > 0 || self.rbuf.len() < 4 {
+            return Ok(None);
+        }
+
+        let mut len = [0; 4];
+        len.copy_from_slice(&self.rbuf[..4]);
+        let len = u32::from_be_bytes(len) as usize;
+        if len > buf.len() {
+            // drop the frame, so that the next one can still be read
+            self.rbuf.drain(..4);
+            self.skip = len;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame too large: {} bytes", len),
+            ));
+        }
+        if self.rbuf.len() < 4 + len {
+            return Ok(None);
+        }
+
+        buf[..len].copy_from_slice(&self.rbuf[4..4 + len]);
+        self.rbuf.drain(..4 + len);
+        Ok(Some(len))
+    }
+}
+
+impl<S: AsRawFd> AsRawFd for Stream<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl<S: Read + Write + AsRawFd> Transport for Stream<S> {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        loop {
+            if let Some(len) = self.next_frame(buf)? {
+                return Ok(Some(len));
+            }
+
+            let start = self.rbuf.len();
+            self.rbuf.resize(start + buf.len(), 0);
+            let res = self.sock.read(&mut self.rbuf[start..]);
+            self.rbuf.truncate(start + res.as_ref().map_or(0, |&n| n));
+
+            match res {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.wbuf.len() + 4 + buf.len() > MAX_PENDING {
+            return Ok(0);
+        }
+
+        self.wbuf
+            .extend_from_slice(&(buf.len() as u32).to_be_bytes());
+        self.wbuf.extend_from_slice(buf);
+        self.flush()?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.wbuf.is_empty() {
+            match self.sock.write(&self.wbuf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.wbuf.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn wants_write(&self) -> bool {
+        !self.wbuf.is_empty()
+    }
+}
+
+/// Address of a stream socket: `unix:PATH` or `tcp:HOST:PORT`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Addr {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl FromStr for Addr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Ok(Addr::Unix(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("tcp:") {
+            Ok(Addr::Tcp(addr.to_string()))
+        } else {
+            Err(format!(
+                "Invalid stream address '{}', expected unix:PATH or tcp:HOST:PORT",
+                s
+            ))
+        }
+    }
+}
+
+/// Connect to a listening peer.
+pub fn connect(addr: &Addr) -> io::Result<Box<dyn Transport>> {
+    Ok(match addr {
+        Addr::Unix(path) => Box::new(Stream::new(UnixStream::connect(path)?)?),
+        Addr::Tcp(addr) => {
+            let sock = TcpStream::connect(addr.as_str())?;
+            sock.set_nodelay(true)?;
+            Box::new(Stream::new(sock)?)
+        }
+    })
+}
+
+/// Listen on `addr`, and wait for a peer to connect.
+pub fn accept(addr: &Addr) -> io::Result<Box<dyn Transport>> {
+    Ok(match addr {
+        Addr::Unix(path) => {
+            let (sock, _) = UnixListener::bind(path)?.accept()?;
+            Box::new(Stream::new(sock)?)
+        }
+        Addr::Tcp(addr) => {
+            let (sock, _) = TcpListener::bind(addr.as_str())?.accept()?;
+            sock.set_nodelay(true)?;
+            Box::new(Stream::new(sock)?)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_io() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut stream = Stream::new(a).unwrap();
+        let mut buf = [0; 64];
+
+        assert_eq!(stream.recv(&mut buf).unwrap(), None);
+        b.write_all(&[0, 0, 0, 3, 1]).unwrap();
+        assert_eq!(stream.recv(&mut buf).unwrap(), None);
+        b.write_all(&[2, 3, 0, 0, 0, 1, 4]).unwrap();
+        assert_eq!(stream.recv(&mut buf).unwrap(), Some(3));
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        assert_eq!(stream.recv(&mut buf).unwrap(), Some(1));
+        assert_eq!(buf[0], 4);
+        assert_eq!(stream.recv(&mut buf).unwrap(), None);
+
+        assert_eq!(stream.send(&[5, 6]).unwrap(), 2);
+        assert!(!stream.wants_write());
+        let mut out = [0; 6];
+        b.read_exact(&mut out).unwrap();
+        assert_eq!(out, [0, 0, 0, 2, 5, 6]);
+
+        drop(b);
+        assert_eq!(
+            stream.recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut stream = Stream::new(a).unwrap();
+        let mut buf = [0; 4];
+
+        b.write_all(&[0, 0, 0, 6, 1, 2, 3]).unwrap();
+        assert_eq!(
+            stream.recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(stream.recv(&mut buf).unwrap(), None);
+        b.write_all(&[4, 5, 6, 0, 0, 0, 2, 7, 8]).unwrap();
+        assert_eq!(stream.recv(&mut buf).unwrap(), Some(2));
+        assert_eq!(&buf[..2], &[7, 8]);
+    }
+
+    #[test]
+    fn addr() {
+        assert_eq!(
+            "unix:/tmp/sock".parse(),
+            Ok(Addr::Unix(PathBuf::from("/tmp/sock")))
+        );
+        assert_eq!(
+            "tcp:localhost:1234".parse(),
+            Ok(Addr::Tcp("localhost:1234".to_string()))
+        );
+        assert!("localhost:1234".parse::<Addr>().is_err());
+    }
+}
@@ -0,0 +1,316 @@
+use super::{set_nonblocking, Transport};
+
+use std::ffi::{CStr, CString};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::{mem, ptr};
+
+// from linux/if_tun.h
+const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNSETOFFLOAD: u64 = 0x4004_54d0;
+const IFF_TAP: i16 = 0x0002;
+const IFF_NO_PI: i16 = 0x1000;
+const IFF_VNET_HDR: i16 = 0x4000;
+
+// from linux/virtio_net.h
+const VNET_HDR_LEN: usize = 10;
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    data: [u8; 24],
+}
+
+impl IfReq {
+    fn new(name: &str) -> io::Result<Self> {
+        if name.len() >= libc::IFNAMSIZ || name.contains('\0') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid interface name '{}'", name),
+            ));
+        }
+
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            data: [0; 24],
+        };
+        req.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(req)
+    }
+
+    fn set_flags(&mut self, flags: i16) {
+        self.data[..2].copy_from_slice(&flags.to_ne_bytes());
+    }
+
+    fn flags(&self) -> i16 {
+        i16::from_ne_bytes([self.data[0], self.data[1]])
+    }
+
+    fn set_addr(&mut self, addr: Ipv4Addr) {
+        self.data[..2].copy_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+        self.data[4..8].copy_from_slice(&addr.octets());
+    }
+
+    fn set_int(&mut self, val: i32) {
+        self.data[..4].copy_from_slice(&val.to_ne_bytes());
+    }
+}
+
+// from net/route.h
+#[repr(C)]
+struct RtEntry {
+    pad1: libc::c_ulong,
+    dst: libc::sockaddr_in,
+    gateway: libc::sockaddr_in,
+    genmask: libc::sockaddr_in,
+    flags: libc::c_ushort,
+    pad2: libc::c_short,
+    pad3: libc::c_ulong,
+    pad4: *mut libc::c_void,
+    metric: libc::c_short,
+    dev: *const libc::c_char,
+    mtu: libc::c_ulong,
+    window: libc::c_ulong,
+    irtt: libc::c_ushort,
+}
+
+const RTF_UP: libc::c_ushort = 0x1;
+const RTF_GATEWAY: libc::c_ushort = 0x2;
+
+fn sockaddr_in(addr: Ipv4Addr) -> libc::sockaddr_in {
+    let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+    sin.sin_addr.s_addr = u32::from(addr).to_be();
+    sin
+}
+
+fn ioctl<T>(fd: RawFd, req: u64, arg: &mut T) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, req as _, arg as *mut T) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A TAP network interface, created with /dev/net/tun.
+#[derive(Debug)]
+pub struct Tap {
+    file: File,
+    name: String,
+    vnet_hdr: bool,
+}
+
+impl Tap {
+    /// Create or attach to the TAP interface `name` (which may be a
+    /// pattern such as "tap%d").
+    ///
+    /// With `vnet_hdr`, frames are exchanged with a virtio-net header, which
+    /// allows to handle the checksum offloading of the host.
+    pub fn new(name: &str, vnet_hdr: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+
+        let mut req = IfReq::new(name)?;
+        let mut flags = IFF_TAP | IFF_NO_PI;
+        if vnet_hdr {
+            flags |= IFF_VNET_HDR;
+        }
+        req.set_flags(flags);
+        ioctl(file.as_raw_fd(), TUNSETIFF, &mut req)?;
+        if vnet_hdr {
+            // don't let the host send segmentation offloaded frames
+            if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETOFFLOAD as _, 0) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        set_nonblocking(file.as_raw_fd())?;
+
+        let name = unsafe { CStr::from_ptr(req.name.as_ptr() as *const _) };
+        Ok(Self {
+            file,
+            name: name.to_string_lossy().into_owned(),
+            vnet_hdr,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn ctl<T>(&self, req: u64, arg: &mut T) -> io::Result<()> {
+        let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = unsafe { File::from_raw_fd(sock) };
+        ioctl(sock.as_raw_fd(), req, arg)
+    }
+
+    /// Bring the interface up.
+    pub fn set_up(&self) -> io::Result<()> {
+        let mut req = IfReq::new(&self.name)?;
+        self.ctl(libc::SIOCGIFFLAGS as u64, &mut req)?;
+        let flags = req.flags() | libc::IFF_UP as i16;
+        req.set_flags(flags);
+        self.ctl(libc::SIOCSIFFLAGS as u64, &mut req)
+    }
+
+    /// Set the IPv4 address of the interface.
+    pub fn set_ipv4(&self, addr: Ipv4Addr, mask: Ipv4Addr) -> io::Result<()> {
+        let mut req = IfReq::new(&self.name)?;
+        req.set_addr(addr);
+        self.ctl(libc::SIOCSIFADDR as u64, &mut req)?;
+        let mut req = IfReq::new(&self.name)?;
+        req.set_addr(mask);
+        self.ctl(libc::SIOCSIFNETMASK as u64, &mut req)
+    }
+
+    pub fn set_mtu(&self, mtu: u32) -> io::Result<()> {
+        let mut req = IfReq::new(&self.name)?;
+        req.set_int(mtu as i32);
+        self.ctl(libc::SIOCSIFMTU as u64, &mut req)
+    }
+
+    /// Route the traffic to `dst`/`mask` through the gateway `gw`, reachable
+    /// on the interface.
+    pub fn add_route(&self, dst: Ipv4Addr, mask: Ipv4Addr, gw: Ipv4Addr) -> io::Result<()> {
+        let dev = CString::new(self.name.as_str()).unwrap();
+        let mut rt = RtEntry {
+            pad1: 0,
+            dst: sockaddr_in(dst),
+            gateway: sockaddr_in(gw),
+            genmask: sockaddr_in(mask),
+            flags: RTF_UP | RTF_GATEWAY,
+            pad2: 0,
+            pad3: 0,
+            pad4: ptr::null_mut(),
+            metric: 0,
+            dev: dev.as_ptr(),
+            mtu: 0,
+            window: 0,
+            irtt: 0,
+        };
+        self.ctl(libc::SIOCADDRT as u64, &mut rt)
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Complete a partial checksum, as requested by a virtio-net header.
+fn finish_csum(hdr: &[u8; VNET_HDR_LEN], frame: &mut [u8]) -> bool {
+    if hdr[1] != VIRTIO_NET_HDR_GSO_NONE {
+        return false;
+    }
+    if hdr[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 {
+        return true;
+    }
+
+    let start = u16::from_ne_bytes([hdr[6], hdr[7]]) as usize;
+    let off = start + u16::from_ne_bytes([hdr[8], hdr[9]]) as usize;
+    if off + 2 > frame.len() {
+        return false;
+    }
+
+    // the checksum field holds the pseudo-header sum
+    let mut sum: u32 = 0;
+    for chunk in frame[start..].chunks(2) {
+        let hi = u32::from(chunk[0]) << 8;
+        sum += hi | chunk.get(1).map_or(0, |&lo| u32::from(lo));
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    frame[off..off + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+
+    true
+}
+
+impl Transport for Tap {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let mut hdr = [0u8; VNET_HDR_LEN];
+
+        loop {
+            let mut iov = [
+                libc::iovec {
+                    iov_base: hdr.as_mut_ptr() as *mut _,
+                    iov_len: if self.vnet_hdr { hdr.len() } else { 0 },
+                },
+                libc::iovec {
+                    iov_base: buf.as_mut_ptr() as *mut _,
+                    iov_len: buf.len(),
+                },
+            ];
+            let len = unsafe { libc::readv(self.file.as_raw_fd(), iov.as_mut_ptr(), 2) };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::WouldBlock => Ok(None),
+                    _ => Err(err),
+                };
+            }
+
+            let len = (len as usize).saturating_sub(iov[0].iov_len);
+            if !self.vnet_hdr || finish_csum(&hdr, &mut buf[..len]) {
+                return Ok(Some(len));
+            }
+            // offloaded frames can't be handled: drop them
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let hdr = [0u8; VNET_HDR_LEN];
+        let iov = [
+            libc::iovec {
+                iov_base: hdr.as_ptr() as *mut _,
+                iov_len: if self.vnet_hdr { hdr.len() } else { 0 },
+            },
+            libc::iovec {
+                iov_base: buf.as_ptr() as *mut _,
+                iov_len: buf.len(),
+            },
+        ];
+
+        let len = unsafe { libc::writev(self.file.as_raw_fd(), iov.as_ptr(), 2) };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                // the frame is dropped, as a NIC would do
+                io::ErrorKind::WouldBlock => Ok(0),
+                _ => Err(err),
+            };
+        }
+
+        Ok((len as usize).saturating_sub(iov[0].iov_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csum() {
+        // UDP 10.0.2.15:68 -> 10.0.2.2:67, with the pseudo-header sum
+        let mut frame = [0x00, 0x44, 0x00, 0x43, 0x00, 0x0a, 0x18, 0x2c, 0x01, 0x02];
+        let mut hdr = [0; VNET_HDR_LEN];
+        hdr[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+        hdr[8..10].copy_from_slice(&6u16.to_ne_bytes());
+
+        assert!(finish_csum(&hdr, &mut frame));
+        assert_eq!(&frame[6..8], &[0xe6, 0x40]);
+
+        hdr[8..10].copy_from_slice(&9u16.to_ne_bytes());
+        assert!(!finish_csum(&hdr, &mut frame));
+        hdr[1] = 1;
+        assert!(!finish_csum(&hdr, &mut frame));
+    }
+}
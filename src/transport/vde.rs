@@ -0,0 +1,177 @@
+use super::{Datagram, Transport};
+
+use std::fs;
+use std::io::{self, prelude::*};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const SWITCH_MAGIC: u32 = 0xfeed_face;
+const REQ_VERSION: u32 = 3;
+const REQ_NEW_CONTROL: u32 = 0;
+const SUN_PATH_LEN: usize = 108;
+const SOCKADDR_UN_LEN: usize = 2 + SUN_PATH_LEN;
+const MAXDESCR: usize = 128;
+
+static SOCKNO: AtomicUsize = AtomicUsize::new(0);
+
+/// A port of a VDE2 switch.
+///
+/// The switch is given the address of a datagram socket over its control
+/// socket, and replies with the address of its own data socket.
+#[derive(Debug)]
+pub struct Vde {
+    data: Datagram,
+    path: PathBuf,
+    // the port is released when the control connection is closed
+    _ctl: UnixStream,
+}
+
+impl Vde {
+    /// Plug into the switch whose control directory is `dir`.
+    pub fn connect<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let mut ctl = UnixStream::connect(dir.join("ctl"))?;
+
+        let sockno = SOCKNO.fetch_add(1, Ordering::SeqCst);
+        let path = dir.join(format!(".{:05}-{:05}", process::id(), sockno));
+        let data = UnixDatagram::bind(&path)?;
+        let data = Self::plug(&mut ctl, &path)
+            .and_then(|switch| data.connect(switch))
+            .and_then(|_| Datagram::new(data));
+
+        match data {
+            Ok(data) => Ok(Self {
+                data,
+                path,
+                _ctl: ctl,
+            }),
+            Err(e) => {
+                let _ = fs::remove_file(&path);
+                Err(e)
+            }
+        }
+    }
+
+    fn plug(ctl: &mut UnixStream, path: &Path) -> io::Result<PathBuf> {
+        let descr = format!("slirp-helper PID={}", process::id());
+        let descr = &descr.as_bytes()[..descr.len().min(MAXDESCR - 1)];
+
+        let mut req = Vec::with_capacity(12 + SOCKADDR_UN_LEN + MAXDESCR);
+        req.extend_from_slice(&SWITCH_MAGIC.to_ne_bytes());
+        req.extend_from_slice(&REQ_VERSION.to_ne_bytes());
+        req.extend_from_slice(&REQ_NEW_CONTROL.to_ne_bytes());
+        req.extend_from_slice(&sockaddr_un(path)?);
+        req.extend_from_slice(descr);
+        req.push(0);
+        ctl.write_all(&req)?;
+
+        let mut reply = [0; SOCKADDR_UN_LEN];
+        let len = ctl.read(&mut reply)?;
+        if len < 2 || u16::from_ne_bytes([reply[0], reply[1]]) != libc::AF_UNIX as u16 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid reply from the VDE switch",
+            ));
+        }
+
+        let sun_path = &reply[2..len];
+        let end = sun_path
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(sun_path.len());
+        Ok(PathBuf::from(
+            String::from_utf8_lossy(&sun_path[..end]).into_owned(),
+        ))
+    }
+}
+
+fn sockaddr_un(path: &Path) -> io::Result<Vec<u8>> {
+    let path = path.to_string_lossy();
+    if path.len() >= SUN_PATH_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Socket path too long: {}", path),
+        ));
+    }
+
+    let mut addr = vec![0; SOCKADDR_UN_LEN];
+    addr[..2].copy_from_slice(&(libc::AF_UNIX as u16).to_ne_bytes());
+    addr[2..2 + path.len()].copy_from_slice(path.as_bytes());
+    Ok(addr)
+}
+
+impl Drop for Vde {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl AsRawFd for Vde {
+    fn as_raw_fd(&self) -> RawFd {
+        self.data.as_raw_fd()
+    }
+}
+
+impl Transport for Vde {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.data.recv(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.send(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn plug() {
+        let dir = std::env::temp_dir().join(format!("slirp-vde-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let listener = UnixListener::bind(dir.join("ctl")).unwrap();
+        let switch_path = dir.join("data");
+        let switch = UnixDatagram::bind(&switch_path).unwrap();
+
+        let sw = switch_path.clone();
+        let ctl = thread::spawn(move || {
+            let (mut ctl, _) = listener.accept().unwrap();
+            let mut req = [0; 512];
+            let len = ctl.read(&mut req).unwrap();
+            assert!(len > 12 + SOCKADDR_UN_LEN);
+            assert_eq!(&req[..4], &SWITCH_MAGIC.to_ne_bytes());
+            assert_eq!(&req[4..8], &REQ_VERSION.to_ne_bytes());
+            ctl.write_all(&sockaddr_un(&sw).unwrap()).unwrap();
+            let sock = &req[14..12 + SOCKADDR_UN_LEN];
+            let end = sock.iter().position(|&c| c == 0).unwrap();
+            (
+                ctl,
+                PathBuf::from(String::from_utf8_lossy(&sock[..end]).into_owned()),
+            )
+        });
+
+        let mut vde = Vde::connect(&dir).unwrap();
+        let (_ctl, port) = ctl.join().unwrap();
+
+        vde.send(&[1, 2, 3]).unwrap();
+        let mut buf = [0; 16];
+        let (len, addr) = switch.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[1, 2, 3]);
+        assert_eq!(addr.as_pathname(), Some(port.as_path()));
+
+        assert_eq!(vde.recv(&mut buf).unwrap(), None);
+        switch.send_to(&[4, 5], &port).unwrap();
+        assert_eq!(vde.recv(&mut buf).unwrap(), Some(2));
+        assert_eq!(&buf[..2], &[4, 5]);
+
+        drop(vde);
+        assert!(!port.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
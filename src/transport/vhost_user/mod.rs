@@ -0,0 +1,453 @@
+use super::Transport;
+
+use self::virtqueue::{Memory, Queue};
+
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+mod virtqueue;
+
+const GET_FEATURES: u32 = 1;
+const SET_FEATURES: u32 = 2;
+const SET_OWNER: u32 = 3;
+const RESET_OWNER: u32 = 4;
+const SET_MEM_TABLE: u32 = 5;
+const SET_VRING_NUM: u32 = 8;
+const SET_VRING_ADDR: u32 = 9;
+const SET_VRING_BASE: u32 = 10;
+const GET_VRING_BASE: u32 = 11;
+const SET_VRING_KICK: u32 = 12;
+const SET_VRING_CALL: u32 = 13;
+const SET_VRING_ERR: u32 = 14;
+const GET_PROTOCOL_FEATURES: u32 = 15;
+const SET_PROTOCOL_FEATURES: u32 = 16;
+const GET_QUEUE_NUM: u32 = 17;
+const SET_VRING_ENABLE: u32 = 18;
+const SEND_RARP: u32 = 19;
+
+const FLAG_VERSION: u32 = 0x1;
+const FLAG_REPLY: u32 = 0x4;
+const FLAG_NEED_REPLY: u32 = 0x8;
+
+const VRING_NOFD_MASK: u64 = 0x100;
+const VRING_IDX_MASK: u64 = 0xff;
+
+const MAX_FDS: usize = 8;
+const MAX_PAYLOAD: usize = 4096;
+
+const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const FEATURES: u64 = VIRTIO_NET_F_MRG_RXBUF | VHOST_USER_F_PROTOCOL_FEATURES | VIRTIO_F_VERSION_1;
+
+const PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+const PROTOCOL_FEATURES: u64 = PROTOCOL_F_REPLY_ACK;
+
+const RX: usize = 0;
+const TX: usize = 1;
+
+struct Msg {
+    request: u32,
+    flags: u32,
+    payload: Vec<u8>,
+    fds: Vec<File>,
+}
+
+impl Msg {
+    fn u64_at(&self, off: usize) -> io::Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.payload.get(off..off + 8).ok_or_else(short)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn u32_at(&self, off: usize) -> io::Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.payload.get(off..off + 4).ok_or_else(short)?);
+        Ok(u32::from_le_bytes(b))
+    }
+}
+
+fn short() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "vhost-user message too short")
+}
+
+/// A vhost-user-net device backend.
+///
+/// The frontend (QEMU, cloud-hypervisor...) shares the guest memory and the
+/// virtqueues over the control socket. Frames sent by the guest on the TX
+/// queue are received, and frames are sent in the buffers of the RX queue.
+pub struct VhostUser {
+    sock: UnixStream,
+    epoll: File,
+    mem: Memory,
+    queues: [Queue; 2],
+    features: u64,
+    protocol_features: u64,
+}
+
+impl VhostUser {
+    pub fn new(sock: UnixStream) -> io::Result<Self> {
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let dev = Self {
+            sock,
+            epoll: unsafe { File::from_raw_fd(epoll) },
+            mem: Memory::default(),
+            queues: Default::default(),
+            features: 0,
+            protocol_features: 0,
+        };
+        dev.epoll_ctl(libc::EPOLL_CTL_ADD, dev.sock.as_raw_fd())?;
+
+        Ok(dev)
+    }
+
+    /// Listen on the socket `path`, and wait for the frontend to connect.
+    pub fn accept<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let (sock, _) = UnixListener::bind(path)?.accept()?;
+        Self::new(sock)
+    }
+
+    fn epoll_ctl(&self, op: libc::c_int, fd: RawFd) -> io::Result<()> {
+        let mut ev = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: fd as u64,
+        };
+        if unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), op, fd, &mut ev) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn hdr_len(&self) -> usize {
+        if self.features & (VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MRG_RXBUF) != 0 {
+            12
+        } else {
+            10
+        }
+    }
+
+    fn recv_msg(&mut self) -> io::Result<Option<Msg>> {
+        let mut hdr = [0u8; 12];
+        let mut iov = libc::iovec {
+            iov_base: hdr.as_mut_ptr() as *mut libc::c_void,
+            iov_len: hdr.len(),
+        };
+        let mut cmsg = [0u64; 8];
+        let cmsg_len = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_len as _;
+
+        let len = unsafe {
+            libc::recvmsg(
+                self.sock.as_raw_fd(),
+                &mut msg,
+                libc::MSG_DONTWAIT | libc::MSG_CMSG_CLOEXEC,
+            )
+        };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        let mut fds = Vec::new();
+        unsafe {
+            let mut c = libc::CMSG_FIRSTHDR(&msg);
+            while !c.is_null() {
+                if (*c).cmsg_level == libc::SOL_SOCKET && (*c).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(c) as *const RawFd;
+                    let n = ((*c).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                        / mem::size_of::<RawFd>();
+                    for i in 0..n {
+                        fds.push(File::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                    }
+                }
+                c = libc::CMSG_NXTHDR(&msg, c);
+            }
+        }
+
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if (len as usize) < hdr.len() {
+            self.sock.read_exact(&mut hdr[len as usize..])?;
+        }
+
+        let u32_at = |off: usize| {
+            let mut b = [0; 4];
+            b.copy_from_slice(&hdr[off..off + 4]);
+            u32::from_le_bytes(b)
+        };
+        let size = u32_at(8) as usize;
+        if size > MAX_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "vhost-user message too large",
+            ));
+        }
+        let mut payload = vec![0; size];
+        self.sock.read_exact(&mut payload)?;
+
+        Ok(Some(Msg {
+            request: u32_at(0),
+            flags: u32_at(4),
+            payload,
+            fds,
+        }))
+    }
+
+    fn reply(&mut self, request: u32, payload: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(12 + payload.len());
+        buf.extend_from_slice(&request.to_le_bytes());
+        buf.extend_from_slice(&(FLAG_VERSION | FLAG_REPLY).to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(payload);
+        self.sock.write_all(&buf)
+    }
+
+    fn reply_u64(&mut self, request: u32, val: u64) -> io::Result<()> {
+        self.reply(request, &val.to_le_bytes())
+    }
+
+    fn vring_state(&self, msg: &Msg) -> io::Result<(usize, u32)> {
+        let idx = msg.u32_at(0)? as usize;
+        if idx >= self.queues.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid vring index {}", idx),
+            ));
+        }
+        Ok((idx, msg.u32_at(4)?))
+    }
+
+    fn vring_fd(&mut self, msg: &mut Msg) -> io::Result<(usize, Option<File>)> {
+        let val = msg.u64_at(0)?;
+        let idx = (val & VRING_IDX_MASK) as usize;
+        if idx >= self.queues.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid vring index {}", idx),
+            ));
+        }
+        let fd = if val & VRING_NOFD_MASK != 0 {
+            None
+        } else {
+            msg.fds.pop()
+        };
+        Ok((idx, fd))
+    }
+
+    fn handle_msg(&mut self, mut msg: Msg) -> io::Result<()> {
+        let mut ack = 0;
+
+        match msg.request {
+            GET_FEATURES => self.reply_u64(msg.request, FEATURES)?,
+            SET_FEATURES => {
+                self.features = msg.u64_at(0)? & FEATURES;
+                if self.features & VHOST_USER_F_PROTOCOL_FEATURES == 0 {
+                    for q in self.queues.iter_mut() {
+                        q.enabled = true;
+                    }
+                }
+            }
+            GET_PROTOCOL_FEATURES => self.reply_u64(msg.request, PROTOCOL_FEATURES)?,
+            SET_PROTOCOL_FEATURES => self.protocol_features = msg.u64_at(0)? & PROTOCOL_FEATURES,
+            GET_QUEUE_NUM => self.reply_u64(msg.request, self.queues.len() as u64)?,
+            SET_OWNER | SEND_RARP => {}
+            RESET_OWNER => {
+                for q in self.queues.iter_mut() {
+                    q.reset();
+                }
+                self.features = 0;
+            }
+            SET_MEM_TABLE => {
+                let nregions = msg.u32_at(0)? as usize;
+                if nregions > MAX_FDS || nregions != msg.fds.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid memory table",
+                    ));
+                }
+                let mut mem = Memory::default();
+                for (i, fd) in msg.fds.iter().enumerate() {
+                    let off = 8 + i * 32;
+                    mem.add_region(
+                        msg.u64_at(off)?,
+                        msg.u64_at(off + 8)?,
+                        msg.u64_at(off + 16)?,
+                        msg.u64_at(off + 24)?,
+                        fd,
+                    )?;
+                }
+                self.mem = mem;
+                for q in self.queues.iter_mut() {
+                    q.map(&self.mem)?;
+                }
+            }
+            SET_VRING_NUM => {
+                let (idx, num) = self.vring_state(&msg)?;
+                if num == 0 || num > 32768 || !num.is_power_of_two() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid vring size {}", num),
+                    ));
+                }
+                // the ring sizes changed, check them against the memory again
+                self.queues[idx].num = num as u16;
+                self.queues[idx].map(&self.mem)?;
+            }
+            SET_VRING_ADDR => {
+                let (idx, _flags) = self.vring_state(&msg)?;
+                let q = &mut self.queues[idx];
+                q.desc_addr = msg.u64_at(8)?;
+                q.used_addr = msg.u64_at(16)?;
+                q.avail_addr = msg.u64_at(24)?;
+                q.map(&self.mem)?;
+            }
+            SET_VRING_BASE => {
+                let (idx, base) = self.vring_state(&msg)?;
+                self.queues[idx].last_avail = base as u16;
+            }
+            GET_VRING_BASE => {
+                let (idx, _) = self.vring_state(&msg)?;
+                let base = self.queues[idx].last_avail;
+                if let Some(kick) = self.queues[idx].kick.take() {
+                    self.epoll_ctl(libc::EPOLL_CTL_DEL, kick.as_raw_fd())?;
+                }
+                self.queues[idx].started = false;
+                let mut state = Vec::with_capacity(8);
+                state.extend_from_slice(&(idx as u32).to_le_bytes());
+                state.extend_from_slice(&u32::from(base).to_le_bytes());
+                self.reply(msg.request, &state)?;
+            }
+            SET_VRING_KICK => {
+                let (idx, fd) = self.vring_fd(&mut msg)?;
+                if let Some(kick) = self.queues[idx].kick.take() {
+                    self.epoll_ctl(libc::EPOLL_CTL_DEL, kick.as_raw_fd())?;
+                }
+                if let Some(kick) = &fd {
+                    super::set_nonblocking(kick.as_raw_fd())?;
+                    self.epoll_ctl(libc::EPOLL_CTL_ADD, kick.as_raw_fd())?;
+                }
+                let q = &mut self.queues[idx];
+                q.kick = fd;
+                q.started = true;
+                if self.features & VHOST_USER_F_PROTOCOL_FEATURES == 0 {
+                    q.enabled = true;
+                }
+            }
+            SET_VRING_CALL => {
+                let (idx, fd) = self.vring_fd(&mut msg)?;
+                self.queues[idx].call = fd;
+            }
+            SET_VRING_ERR => {
+                self.vring_fd(&mut msg)?;
+            }
+            SET_VRING_ENABLE => {
+                let (idx, enable) = self.vring_state(&msg)?;
+                self.queues[idx].enabled = enable != 0;
+            }
+            _ => ack = 1,
+        }
+
+        if msg.flags & FLAG_NEED_REPLY != 0 && self.protocol_features & PROTOCOL_F_REPLY_ACK != 0 {
+            self.reply_u64(msg.request, ack)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for VhostUser {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl Transport for VhostUser {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        while let Some(msg) = self.recv_msg()? {
+            self.handle_msg(msg)?;
+        }
+
+        for q in self.queues.iter_mut() {
+            q.drain_kick()?;
+        }
+
+        let hdr_len = self.hdr_len();
+        let q = &mut self.queues[TX];
+        while q.is_ready() {
+            let chain = match q.pop()? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let len = chain.read(&self.mem, hdr_len, buf);
+            q.push(&chain, 0);
+            q.signal()?;
+
+            // too large or invalid frames are dropped
+            if let Some(len) = len {
+                return Ok(Some(len));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let hdr_len = self.hdr_len();
+        let mrg_rxbuf = self.features & VIRTIO_NET_F_MRG_RXBUF != 0;
+        let q = &mut self.queues[RX];
+        if !q.is_ready() {
+            return Ok(0);
+        }
+
+        let mut frame = vec![0; hdr_len];
+        frame.extend_from_slice(buf);
+
+        let mut chains = Vec::new();
+        let mut capacity = 0;
+        while capacity < frame.len() && (chains.is_empty() || mrg_rxbuf) {
+            match q.pop()? {
+                Some(chain) => {
+                    capacity += chain.capacity();
+                    chains.push(chain);
+                }
+                None => break,
+            }
+        }
+        if capacity < frame.len() {
+            // not enough buffers: drop the frame, as a NIC would do
+            q.unpop(chains.len() as u16);
+            return Ok(0);
+        }
+
+        if hdr_len == 12 {
+            frame[10..12].copy_from_slice(&(chains.len() as u16).to_le_bytes());
+        }
+        let mut off = 0;
+        for chain in chains.iter() {
+            let len = chain
+                .write(&self.mem, &frame[off..])
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid RX buffer"))?;
+            q.push(chain, len as u32);
+            off += len;
+        }
+        q.signal()?;
+
+        Ok(buf.len())
+    }
+}
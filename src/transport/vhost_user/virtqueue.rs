@@ -0,0 +1,371 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+
+const VRING_DESC_F_NEXT: u16 = 1;
+const VRING_DESC_F_WRITE: u16 = 2;
+const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+struct Region {
+    gpa: u64,
+    size: u64,
+    uaddr: u64,
+    ptr: *mut u8,
+    map: *mut libc::c_void,
+    map_len: usize,
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map, self.map_len);
+        }
+    }
+}
+
+/// The guest memory, shared by the frontend.
+#[derive(Default)]
+pub(super) struct Memory {
+    regions: Vec<Region>,
+}
+
+impl Memory {
+    pub fn add_region(
+        &mut self,
+        gpa: u64,
+        size: u64,
+        uaddr: u64,
+        offset: u64,
+        fd: &File,
+    ) -> io::Result<()> {
+        let map_len = size
+            .checked_add(offset)
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid memory region"))?;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.regions.push(Region {
+            gpa,
+            size,
+            uaddr,
+            ptr: unsafe { (map as *mut u8).add(offset as usize) },
+            map,
+            map_len,
+        });
+
+        Ok(())
+    }
+
+    fn find<F>(&self, addr: u64, len: usize, start: F) -> Option<*mut u8>
+    where
+        F: Fn(&Region) -> u64,
+    {
+        self.regions.iter().find_map(|r| {
+            let off = addr.checked_sub(start(r))?;
+            if off.checked_add(len as u64)? <= r.size {
+                Some(unsafe { r.ptr.add(off as usize) })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Translate a guest physical address.
+    fn gpa(&self, gpa: u64, len: usize) -> Option<*mut u8> {
+        self.find(gpa, len, |r| r.gpa)
+    }
+
+    /// Translate a frontend virtual address.
+    pub fn uaddr(&self, uaddr: u64, len: usize) -> Option<*mut u8> {
+        self.find(uaddr, len, |r| r.uaddr)
+    }
+
+    fn read(&self, gpa: u64, buf: &mut [u8]) -> Option<()> {
+        let src = self.gpa(gpa, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Some(())
+    }
+
+    fn write(&self, gpa: u64, buf: &[u8]) -> Option<()> {
+        let dst = self.gpa(gpa, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len()) };
+        Some(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A chain of descriptors made available by the guest.
+pub(super) struct Chain {
+    head: u16,
+    descs: Vec<Desc>,
+}
+
+impl Chain {
+    /// Copy the device-readable part of the chain, skipping `skip` bytes.
+    pub fn read(&self, mem: &Memory, mut skip: usize, buf: &mut [u8]) -> Option<usize> {
+        let mut len = 0;
+
+        for d in self
+            .descs
+            .iter()
+            .filter(|d| d.flags & VRING_DESC_F_WRITE == 0)
+        {
+            let dlen = d.len as usize;
+            if skip >= dlen {
+                skip -= dlen;
+                continue;
+            }
+            let n = dlen - skip;
+            mem.read(d.addr.checked_add(skip as u64)?, buf.get_mut(len..len + n)?)?;
+            skip = 0;
+            len += n;
+        }
+
+        Some(len)
+    }
+
+    /// The size of the device-writable part of the chain.
+    pub fn capacity(&self) -> usize {
+        self.descs
+            .iter()
+            .filter(|d| d.flags & VRING_DESC_F_WRITE != 0)
+            .map(|d| d.len as usize)
+            .sum()
+    }
+
+    /// Fill the device-writable part of the chain, returning the written size.
+    pub fn write(&self, mem: &Memory, buf: &[u8]) -> Option<usize> {
+        let mut len = 0;
+
+        for d in self
+            .descs
+            .iter()
+            .filter(|d| d.flags & VRING_DESC_F_WRITE != 0)
+        {
+            if len == buf.len() {
+                break;
+            }
+            let n = (d.len as usize).min(buf.len() - len);
+            mem.write(d.addr, &buf[len..len + n])?;
+            len += n;
+        }
+
+        Some(len)
+    }
+}
+
+/// A split virtqueue.
+pub(super) struct Queue {
+    pub num: u16,
+    pub desc_addr: u64,
+    pub avail_addr: u64,
+    pub used_addr: u64,
+    desc: *mut u8,
+    avail: *mut u8,
+    used: *mut u8,
+    pub last_avail: u16,
+    used_idx: u16,
+    pub kick: Option<File>,
+    pub call: Option<File>,
+    pub started: bool,
+    pub enabled: bool,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            num: 0,
+            desc_addr: 0,
+            avail_addr: 0,
+            used_addr: 0,
+            desc: ptr::null_mut(),
+            avail: ptr::null_mut(),
+            used: ptr::null_mut(),
+            last_avail: 0,
+            used_idx: 0,
+            kick: None,
+            call: None,
+            started: false,
+            enabled: false,
+        }
+    }
+}
+
+impl Queue {
+    /// Translate the ring addresses, after they or the memory table changed.
+    pub fn map(&mut self, mem: &Memory) -> io::Result<()> {
+        // on failure, the queue is left unmapped and never ready
+        self.desc = ptr::null_mut();
+        self.avail = ptr::null_mut();
+        self.used = ptr::null_mut();
+        if self.desc_addr == 0 {
+            return Ok(());
+        }
+
+        let num = self.num as usize;
+        let map = |addr, len| {
+            mem.uaddr(addr, len).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("vring address {:#x} is not mapped", addr),
+                )
+            })
+        };
+        let desc = map(self.desc_addr, 16 * num)?;
+        let avail = map(self.avail_addr, 6 + 2 * num)?;
+        self.used = map(self.used_addr, 6 + 8 * num)?;
+        self.desc = desc;
+        self.avail = avail;
+        self.used_idx = unsafe { u16::from_le(ptr::read_volatile(self.used.add(2) as *const u16)) };
+
+        Ok(())
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.started && self.enabled && !self.desc.is_null() && self.num != 0
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Consume the pending kick notifications.
+    pub fn drain_kick(&mut self) -> io::Result<()> {
+        if let Some(kick) = &mut self.kick {
+            let mut buf = [0; 8];
+            match kick.read(&mut buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn avail_u16(&self, off: usize) -> u16 {
+        unsafe { u16::from_le(ptr::read_volatile(self.avail.add(off) as *const u16)) }
+    }
+
+    fn desc(&self, idx: u16) -> Desc {
+        unsafe {
+            let d = self.desc.add(16 * idx as usize);
+            Desc {
+                addr: u64::from_le(ptr::read_volatile(d as *const u64)),
+                len: u32::from_le(ptr::read_volatile(d.add(8) as *const u32)),
+                flags: u16::from_le(ptr::read_volatile(d.add(12) as *const u16)),
+                next: u16::from_le(ptr::read_volatile(d.add(14) as *const u16)),
+            }
+        }
+    }
+
+    /// Take the next available chain, if any.
+    pub fn pop(&mut self) -> io::Result<Option<Chain>> {
+        let avail_idx = self.avail_u16(2);
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+        if avail_idx.wrapping_sub(self.last_avail) > self.num {
+            return Err(invalid("avail index out of range"));
+        }
+        fence(Ordering::Acquire);
+
+        let head = self.avail_u16(4 + 2 * (self.last_avail % self.num) as usize);
+        let mut descs = Vec::new();
+        let mut idx = head;
+        loop {
+            if idx >= self.num || descs.len() >= self.num as usize {
+                return Err(invalid("invalid descriptor chain"));
+            }
+            let desc = self.desc(idx);
+            descs.push(desc);
+            if desc.flags & VRING_DESC_F_NEXT == 0 {
+                break;
+            }
+            idx = desc.next;
+        }
+
+        self.last_avail = self.last_avail.wrapping_add(1);
+        Ok(Some(Chain { head, descs }))
+    }
+
+    /// Give back the last `n` chains taken with `pop`, unused.
+    pub fn unpop(&mut self, n: u16) {
+        self.last_avail = self.last_avail.wrapping_sub(n);
+    }
+
+    /// Return a chain to the guest, with `len` bytes written to it.
+    pub fn push(&mut self, chain: &Chain, len: u32) {
+        unsafe {
+            let elem = self.used.add(4 + 8 * (self.used_idx % self.num) as usize);
+            ptr::write_volatile(elem as *mut u32, u32::from(chain.head).to_le());
+            ptr::write_volatile(elem.add(4) as *mut u32, len.to_le());
+            fence(Ordering::Release);
+            self.used_idx = self.used_idx.wrapping_add(1);
+            ptr::write_volatile(self.used.add(2) as *mut u16, self.used_idx.to_le());
+        }
+        fence(Ordering::SeqCst);
+    }
+
+    /// Notify the guest of used chains, unless it asked not to be.
+    pub fn signal(&mut self) -> io::Result<()> {
+        if self.avail_u16(0) & VRING_AVAIL_F_NO_INTERRUPT != 0 {
+            return Ok(());
+        }
+        if let Some(call) = &mut self.call {
+            call.write_all(&1u64.to_ne_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow() {
+        let mut mem = Memory::default();
+        let fd = File::open("/dev/null").unwrap();
+        let err = mem.add_region(0, u64::MAX, 0, 1, &fd).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let chain = Chain {
+            head: 0,
+            descs: vec![Desc {
+                addr: u64::MAX,
+                len: 2,
+                flags: 0,
+                next: 0,
+            }],
+        };
+        assert_eq!(chain.read(&mem, 1, &mut [0; 2]), None);
+    }
+}
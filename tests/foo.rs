@@ -56,7 +56,7 @@ fn ip() {
     let app = App {
         start: Instant::now(),
     };
-    let mut ctxt = libslirp::Context::new_with_opt(&opt, app);
+    let mut ctxt = libslirp::Context::new_with_opt(&opt, app).unwrap();
 
     {
         let builder = PacketBuilder::ethernet2(
@@ -0,0 +1,161 @@
+#![cfg(any(target_os = "linux", target_os = "android"))]
+
+use libslirp::packet::{udp_frame, Udp};
+use std::env;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::fs::symlink;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+const GUEST: [u8; 4] = [10, 0, 2, 15];
+const HOST: [u8; 4] = [10, 0, 2, 2];
+
+fn addr(ip: [u8; 4], port: u16) -> SocketAddr {
+    SocketAddr::from((ip, port))
+}
+
+fn udp(dst_mac: [u8; 6], src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    udp_frame(GUEST_MAC, dst_mac, src, dst, payload)
+}
+
+/// Who has the host, so that slirp learns the guest MAC.
+fn arp_request() -> Vec<u8> {
+    let mut frame = vec![0xff; 6];
+    frame.extend_from_slice(&GUEST_MAC);
+    frame.extend_from_slice(&[0x08, 0x06, 0, 1, 0x08, 0, 6, 4, 0, 1]);
+    frame.extend_from_slice(&GUEST_MAC);
+    frame.extend_from_slice(&GUEST);
+    frame.extend_from_slice(&[0; 6]);
+    frame.extend_from_slice(&HOST);
+    frame
+}
+
+fn dhcp_discover() -> Vec<u8> {
+    let mut bootp = vec![0; 236];
+    bootp[0] = 1; // BOOTREQUEST
+    bootp[1] = 1;
+    bootp[2] = 6;
+    bootp[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+    bootp[28..34].copy_from_slice(&GUEST_MAC);
+    bootp.extend_from_slice(&[99, 130, 83, 99, 53, 1, 1, 255]);
+    bootp.resize(300, 0);
+
+    udp([0xff; 6], addr([0; 4], 68), addr([255; 4], 67), &bootp)
+}
+
+fn tftp_rrq(port: u16, path: &str) -> Vec<u8> {
+    let mut rrq = vec![0, 1];
+    rrq.extend_from_slice(path.as_bytes());
+    rrq.extend_from_slice(b"\0octet\0");
+
+    udp([0xff; 6], addr(GUEST, port), addr(HOST, 69), &rrq)
+}
+
+const DNS_QUERY: &[u8] = b"\x12\x34\x01\x00\0\x01\0\0\0\0\0\0\x07sandbox\x04test\0\0\x01\0\x01";
+
+/// Wait for a datagram to the guest `port`.
+fn recv_udp(guest: &UnixDatagram, port: u16) -> (SocketAddr, Vec<u8>) {
+    let mut buf = [0; 4096];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Ok(len) = guest.recv(&mut buf) {
+            match Udp::parse(&buf[..len]) {
+                Some(udp) if udp.dst.port() == port => return (udp.src, udp.payload.to_vec()),
+                _ => {}
+            }
+        }
+    }
+    panic!("no reply to port {}", port);
+}
+
+fn inherit(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A DHCP, TFTP and DNS cycle with slirp-helper, once it is sandboxed.
+#[test]
+fn dhcp_tftp_dns() {
+    let (guest, slirp) = UnixDatagram::pair().unwrap();
+    guest
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+    let (mut status, ready) = UnixStream::pair().unwrap();
+
+    let dir = env::temp_dir().join(format!("slirp-sandbox-{}", process::id()));
+    let root = dir.join("tftp");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("boot"), b"boot").unwrap();
+    fs::write(dir.join("outside"), b"outside").unwrap();
+    symlink(dir.join("outside"), root.join("outside")).unwrap();
+
+    let fds = (slirp.as_raw_fd(), ready.as_raw_fd());
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_slirp-helper"));
+    cmd.arg("--sandbox")
+        .args(&["--fd", &fds.0.to_string()])
+        .args(&["--ready-fd", &fds.1.to_string()])
+        .arg("--tftp-server")
+        .arg("--tftp-root")
+        .arg(&root);
+    unsafe {
+        cmd.pre_exec(move || {
+            inherit(fds.0)?;
+            inherit(fds.1)
+        });
+    }
+    let mut child = cmd.spawn().unwrap();
+    drop((slirp, ready));
+
+    // written once the seccomp filter is installed
+    let mut buf = [0; 1];
+    let len = status.read(&mut buf).unwrap();
+    assert_eq!(len, 1, "slirp-helper exited: {:?}", child.wait());
+
+    guest.send(&arp_request()).unwrap();
+    guest.send(&dhcp_discover()).unwrap();
+    let (_, offer) = recv_udp(&guest, 68);
+    assert_eq!(offer[0], 2); // BOOTREPLY
+    assert_eq!(&offer[16..20], &GUEST);
+
+    guest.send(&tftp_rrq(2000, "boot")).unwrap();
+    let (_, data) = recv_udp(&guest, 2000);
+    assert_eq!(data, b"\0\x03\0\x01boot");
+
+    // Landlock denies the files outside of the TFTP root
+    guest.send(&tftp_rrq(2001, "outside")).unwrap();
+    let (_, error) = recv_udp(&guest, 2001);
+    assert_eq!(&error[..4], &[0, 5, 0, 2]);
+
+    // libslirp forwards the datagrams to the host address to the loopback
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let port = server.local_addr().unwrap().port();
+    let query = udp([0xff; 6], addr(GUEST, 5353), addr(HOST, port), DNS_QUERY);
+    guest.send(&query).unwrap();
+    let mut buf = [0; 512];
+    let (len, peer) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], DNS_QUERY);
+    let mut answer = DNS_QUERY.to_vec();
+    answer[2] |= 0x80;
+    answer[7] = 1;
+    answer.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 2, 100]);
+    server.send_to(&answer, peer).unwrap();
+    let (src, reply) = recv_udp(&guest, 5353);
+    assert_eq!(src, addr(HOST, port));
+    assert_eq!(reply, answer);
+
+    assert!(child.try_wait().unwrap().is_none());
+    child.kill().unwrap();
+    child.wait().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
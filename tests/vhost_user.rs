@@ -0,0 +1,266 @@
+#![cfg(target_os = "linux")]
+
+use libslirp::transport::{Transport, VhostUser};
+use std::fs::File;
+use std::io::prelude::*;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+const GET_FEATURES: u32 = 1;
+const SET_FEATURES: u32 = 2;
+const SET_OWNER: u32 = 3;
+const SET_MEM_TABLE: u32 = 5;
+const SET_VRING_NUM: u32 = 8;
+const SET_VRING_ADDR: u32 = 9;
+const SET_VRING_BASE: u32 = 10;
+const SET_VRING_KICK: u32 = 12;
+const SET_VRING_CALL: u32 = 13;
+const GET_PROTOCOL_FEATURES: u32 = 15;
+const SET_PROTOCOL_FEATURES: u32 = 16;
+const SET_VRING_ENABLE: u32 = 18;
+
+const NEED_REPLY: u32 = 0x8;
+const VRING_DESC_F_WRITE: u16 = 2;
+
+const MEM_SIZE: usize = 1 << 20;
+const GPA: u64 = 0x10_0000;
+const QUEUE_SIZE: u16 = 8;
+
+struct Vring {
+    desc: u64,
+    avail: u64,
+    used: u64,
+    kick: File,
+    call: File,
+}
+
+/// A minimal vhost-user frontend, standing for the VMM.
+struct Frontend {
+    sock: UnixStream,
+    mem: *mut u8,
+    memfd: File,
+    vrings: Vec<Vring>,
+}
+
+fn eventfd() -> File {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    assert!(fd >= 0);
+    unsafe { File::from_raw_fd(fd) }
+}
+
+impl Frontend {
+    fn new(sock: UnixStream) -> Self {
+        let fd = unsafe { libc::memfd_create(b"guest\0".as_ptr() as *const _, 0) };
+        assert!(fd >= 0);
+        let memfd = unsafe { File::from_raw_fd(fd) };
+        memfd.set_len(MEM_SIZE as u64).unwrap();
+        let mem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                MEM_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                memfd.as_raw_fd(),
+                0,
+            )
+        };
+        assert_ne!(mem, libc::MAP_FAILED);
+
+        let vrings = (0..2)
+            .map(|i| {
+                let base = GPA + i * 0x1000;
+                Vring {
+                    desc: base,
+                    avail: base + 0x200,
+                    used: base + 0x400,
+                    kick: eventfd(),
+                    call: eventfd(),
+                }
+            })
+            .collect();
+
+        Self {
+            sock,
+            mem: mem as *mut u8,
+            memfd,
+            vrings,
+        }
+    }
+
+    fn ptr(&self, gpa: u64) -> *mut u8 {
+        unsafe { self.mem.add((gpa - GPA) as usize) }
+    }
+
+    fn uaddr(&self, gpa: u64) -> u64 {
+        self.ptr(gpa) as u64
+    }
+
+    fn write(&self, gpa: u64, data: &[u8]) {
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.ptr(gpa), data.len()) }
+    }
+
+    fn read(&self, gpa: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        unsafe { ptr::copy_nonoverlapping(self.ptr(gpa), data.as_mut_ptr(), len) }
+        data
+    }
+
+    fn read_u16(&self, gpa: u64) -> u16 {
+        let data = self.read(gpa, 2);
+        u16::from_le_bytes([data[0], data[1]])
+    }
+
+    fn send(&mut self, request: u32, flags: u32, payload: &[u8], fds: &[RawFd]) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&request.to_le_bytes());
+        buf.extend_from_slice(&(0x1 | flags).to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(payload);
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut cmsg = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            let size = mem::size_of_val(fds) as u32;
+            msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = unsafe { libc::CMSG_SPACE(size) } as _;
+            unsafe {
+                let c = libc::CMSG_FIRSTHDR(&msg);
+                (*c).cmsg_level = libc::SOL_SOCKET;
+                (*c).cmsg_type = libc::SCM_RIGHTS;
+                (*c).cmsg_len = libc::CMSG_LEN(size) as _;
+                ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(c) as *mut RawFd, fds.len());
+            }
+        }
+        let len = unsafe { libc::sendmsg(self.sock.as_raw_fd(), &msg, 0) };
+        assert_eq!(len, buf.len() as isize);
+    }
+
+    fn recv_u64(&mut self, request: u32) -> u64 {
+        let mut buf = [0; 20];
+        self.sock.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[0..4], &request.to_le_bytes());
+        assert_eq!(&buf[8..12], &8u32.to_le_bytes());
+        let mut val = [0; 8];
+        val.copy_from_slice(&buf[12..]);
+        u64::from_le_bytes(val)
+    }
+
+    fn setup(&mut self, dev: &mut VhostUser) {
+        self.send(GET_FEATURES, 0, &[], &[]);
+        self.send(GET_PROTOCOL_FEATURES, 0, &[], &[]);
+        dev.recv(&mut [0; 64]).unwrap();
+        let features = self.recv_u64(GET_FEATURES);
+        let protocol_features = self.recv_u64(GET_PROTOCOL_FEATURES);
+        assert_ne!(features & (1 << 30), 0);
+        assert_ne!(protocol_features & (1 << 3), 0);
+
+        self.send(SET_FEATURES, 0, &features.to_le_bytes(), &[]);
+        self.send(SET_PROTOCOL_FEATURES, 0, &(1u64 << 3).to_le_bytes(), &[]);
+        self.send(SET_OWNER, 0, &[], &[]);
+
+        let mut table = Vec::new();
+        table.extend_from_slice(&1u32.to_le_bytes());
+        table.extend_from_slice(&0u32.to_le_bytes());
+        table.extend_from_slice(&GPA.to_le_bytes());
+        table.extend_from_slice(&(MEM_SIZE as u64).to_le_bytes());
+        table.extend_from_slice(&self.uaddr(GPA).to_le_bytes());
+        table.extend_from_slice(&0u64.to_le_bytes());
+        let memfd = self.memfd.as_raw_fd();
+        self.send(SET_MEM_TABLE, NEED_REPLY, &table, &[memfd]);
+
+        for i in 0..2u32 {
+            let state = |val: u32| [i.to_le_bytes(), val.to_le_bytes()].concat();
+            self.send(SET_VRING_NUM, 0, &state(QUEUE_SIZE.into()), &[]);
+            let vring = &self.vrings[i as usize];
+            let mut addr = state(0);
+            addr.extend_from_slice(&self.uaddr(vring.desc).to_le_bytes());
+            addr.extend_from_slice(&self.uaddr(vring.used).to_le_bytes());
+            addr.extend_from_slice(&self.uaddr(vring.avail).to_le_bytes());
+            addr.extend_from_slice(&0u64.to_le_bytes());
+            let (kick, call) = (vring.kick.as_raw_fd(), vring.call.as_raw_fd());
+            self.send(SET_VRING_ADDR, 0, &addr, &[]);
+            self.send(SET_VRING_BASE, 0, &state(0), &[]);
+            self.send(SET_VRING_KICK, 0, &u64::from(i).to_le_bytes(), &[kick]);
+            self.send(SET_VRING_CALL, 0, &u64::from(i).to_le_bytes(), &[call]);
+            self.send(SET_VRING_ENABLE, 0, &state(1), &[]);
+        }
+
+        assert_eq!(dev.recv(&mut [0; 64]).unwrap(), None);
+        assert_eq!(self.recv_u64(SET_MEM_TABLE), 0);
+    }
+
+    /// Make a buffer available on the queue `idx`.
+    fn add_buffer(&mut self, idx: usize, addr: u64, len: u32, flags: u16) {
+        let vring = &self.vrings[idx];
+        let avail_idx = self.read_u16(vring.avail + 2);
+        let head = avail_idx % QUEUE_SIZE;
+
+        let mut desc = Vec::new();
+        desc.extend_from_slice(&addr.to_le_bytes());
+        desc.extend_from_slice(&len.to_le_bytes());
+        desc.extend_from_slice(&flags.to_le_bytes());
+        desc.extend_from_slice(&0u16.to_le_bytes());
+        self.write(vring.desc + 16 * u64::from(head), &desc);
+        self.write(vring.avail + 4 + 2 * u64::from(head), &head.to_le_bytes());
+        self.write(vring.avail + 2, &avail_idx.wrapping_add(1).to_le_bytes());
+
+        (&vring.kick).write_all(&1u64.to_ne_bytes()).unwrap();
+    }
+
+    fn used(&self, idx: usize) -> (u16, u32) {
+        let used = self.read(self.vrings[idx].used, 12);
+        (
+            u16::from_le_bytes([used[2], used[3]]),
+            u32::from_le_bytes([used[8], used[9], used[10], used[11]]),
+        )
+    }
+
+    fn called(&self, idx: usize) -> bool {
+        let mut buf = [0; 8];
+        (&self.vrings[idx].call).read(&mut buf).is_ok()
+    }
+}
+
+#[test]
+fn tx_rx() {
+    let (a, b) = UnixStream::pair().unwrap();
+    let mut dev = VhostUser::new(a).unwrap();
+    let mut front = Frontend::new(b);
+    front.setup(&mut dev);
+
+    let frame: Vec<u8> = (0..60).collect();
+
+    // guest -> backend
+    let buf = GPA + 0x8000;
+    front.write(buf, &[0; 12]);
+    front.write(buf + 12, &frame);
+    front.add_buffer(1, buf, 12 + frame.len() as u32, 0);
+    let mut recv = [0; 2048];
+    assert_eq!(dev.recv(&mut recv).unwrap(), Some(frame.len()));
+    assert_eq!(&recv[..frame.len()], &frame[..]);
+    assert_eq!(dev.recv(&mut recv).unwrap(), None);
+    assert_eq!(front.used(1), (1, 0));
+    assert!(front.called(1));
+
+    // backend -> guest
+    let buf = GPA + 0x9000;
+    front.add_buffer(0, buf, 2048, VRING_DESC_F_WRITE);
+    assert_eq!(dev.recv(&mut recv).unwrap(), None);
+    assert_eq!(dev.send(&frame).unwrap(), frame.len());
+    assert_eq!(front.used(0), (1, 12 + frame.len() as u32));
+    let hdr = front.read(buf, 12);
+    assert_eq!(&hdr[10..12], &1u16.to_le_bytes());
+    assert_eq!(front.read(buf + 12, frame.len()), frame);
+    assert!(front.called(0));
+
+    // no more RX buffers, the frame is dropped
+    assert_eq!(dev.send(&frame).unwrap(), 0);
+}